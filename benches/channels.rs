@@ -0,0 +1,83 @@
+// Compares the channel types client events have been sent over, under the
+// load of a busy server: 64 clients sending 10 000 messages a second in
+// total. What's measured is the time each message spends between being sent
+// and being received, which is where lock contention shows up.
+
+use std::{
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+const SENDERS: u64 = 64;
+const MESSAGES_PER_SECOND: u64 = 10_000;
+// The size of the client event channel in main.rs
+const CAPACITY: usize = 16;
+
+// Sends `count` messages, paced across all the senders, and returns the total
+// time they took to arrive
+fn paced_latency<S: Clone + Send + 'static>(
+    count: u64,
+    tx: S,
+    send: fn(&S, Instant),
+    mut recv: impl FnMut() -> Option<Instant>,
+) -> Duration {
+    let interval = Duration::from_secs(1) / MESSAGES_PER_SECOND as u32;
+    let start = Instant::now();
+    for i in 0..SENDERS {
+        let tx = tx.clone();
+        thread::spawn(move || {
+            for n in (i..count).step_by(SENDERS as usize) {
+                let due = start + interval * n as u32;
+                if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                    thread::sleep(wait);
+                }
+                send(&tx, Instant::now());
+            }
+        });
+    }
+    drop(tx);
+    let mut total = Duration::ZERO;
+    while let Some(sent) = recv() {
+        total += sent.elapsed();
+    }
+    total
+}
+
+fn channels(c: &mut Criterion) {
+    let mut group = c.benchmark_group("channels");
+    // Every message takes 1/MESSAGES_PER_SECOND of wall time however quickly
+    // it's delivered, so keep the measured time short
+    group
+        .sample_size(10)
+        .warm_up_time(Duration::from_millis(100))
+        .measurement_time(Duration::from_millis(500));
+    group.bench_function("mpsc::sync_channel", |b| {
+        b.iter_custom(|count| {
+            let (tx, rx) = mpsc::sync_channel(CAPACITY);
+            paced_latency(
+                count,
+                tx,
+                |tx, sent| tx.send(sent).unwrap(),
+                || rx.recv().ok(),
+            )
+        })
+    });
+    group.bench_function("crossbeam_channel::bounded", |b| {
+        b.iter_custom(|count| {
+            let (tx, rx) = crossbeam_channel::bounded(CAPACITY);
+            paced_latency(
+                count,
+                tx,
+                |tx, sent| tx.send(sent).unwrap(),
+                || rx.recv().ok(),
+            )
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, channels);
+criterion_main!(benches);
@@ -0,0 +1,130 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::{
+    protocol::{Sphere, Vec3},
+    SceneElement,
+};
+
+#[derive(Deserialize)]
+struct SphereTrack {
+    // Row of the scene file, counting from 0 and not including the header
+    index: usize,
+    #[serde(default)]
+    orbit_radius: f32,
+    #[serde(default)]
+    orbit_speed: f32,
+    #[serde(default)]
+    bob_amplitude: f32,
+    #[serde(default)]
+    bob_frequency: f32,
+}
+
+#[derive(Deserialize)]
+struct AnimationFile {
+    #[serde(default)]
+    sphere: Vec<SphereTrack>,
+}
+
+pub struct AnimationScript {
+    tracks: HashMap<usize, SphereTrack>,
+}
+
+impl AnimationScript {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Reading animation script {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Parsing animation script {}", path.display()))
+    }
+    pub fn parse(text: &str) -> Result<Self, toml::de::Error> {
+        let file: AnimationFile = toml::from_str(text)?;
+        Ok(Self {
+            tracks: file
+                .sphere
+                .into_iter()
+                .map(|track| (track.index, track))
+                .collect(),
+        })
+    }
+    // Scripted spheres orbit their original position in the XZ plane and bob
+    // up and down along Y. Angles are in radians, speeds in radians per frame.
+    pub fn apply(&self, elements: &[SceneElement], frame: u64) -> Vec<Sphere> {
+        let t = frame as f32;
+        elements
+            .iter()
+            .enumerate()
+            .map(|(i, elem)| {
+                let mut center = Vec3 {
+                    x: elem.x,
+                    y: elem.y,
+                    z: elem.z,
+                };
+                if let Some(track) = self.tracks.get(&i) {
+                    let (sa, ca) = (track.orbit_speed * t).sin_cos();
+                    center.x += track.orbit_radius * ca;
+                    center.z += track.orbit_radius * sa;
+                    center.y += track.bob_amplitude * (track.bob_frequency * t).sin();
+                }
+                Sphere {
+                    center,
+                    radius: elem.r + 1.0,
+                    emissive: elem.emissive(),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn element(x: f32, y: f32, z: f32) -> SceneElement {
+        SceneElement {
+            x,
+            y,
+            z,
+            r: 4.0,
+            ex: 0.0,
+            ey: 0.0,
+            ez: 0.0,
+        }
+    }
+
+    #[test]
+    fn scripted_sphere_follows_its_orbit() {
+        let script = AnimationScript::parse(
+            r#"
+            [[sphere]]
+            index = 1
+            orbit_radius = 5.0
+            orbit_speed = 0.1
+            bob_amplitude = 2.0
+            bob_frequency = 0.05
+            "#,
+        )
+        .unwrap();
+        let elements = [element(-50.0, 0.0, 0.0), element(10.0, 20.0, 30.0)];
+        let spheres = script.apply(&elements, 10);
+
+        // Untracked spheres stay put
+        let still = spheres[0].center;
+        assert_eq!((still.x, still.y, still.z), (-50.0, 0.0, 0.0));
+        assert_eq!(spheres[0].radius, 5.0);
+        // One radian round the orbit, and half a radian into the bob
+        let moved = spheres[1].center;
+        assert!((moved.x - (10.0 + 5.0 * 1f32.cos())).abs() < 1e-4);
+        assert!((moved.y - (20.0 + 2.0 * 0.5f32.sin())).abs() < 1e-4);
+        assert!((moved.z - (30.0 + 5.0 * 1f32.sin())).abs() < 1e-4);
+    }
+
+    #[test]
+    fn tracks_default_to_standing_still() {
+        let script = AnimationScript::parse("[[sphere]]\nindex = 0\n").unwrap();
+        let spheres = script.apply(&[element(1.0, 2.0, 3.0)], 1234);
+        let center = spheres[0].center;
+        assert_eq!((center.x, center.y, center.z), (1.0, 2.0, 3.0));
+    }
+}
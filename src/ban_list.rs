@@ -0,0 +1,23 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// Peer IPs that an admin banned, each until its ban runs out. Filled in by the
+// server thread and checked by the accept loops before a handshake starts.
+#[derive(Debug, Clone, Default)]
+pub struct BanList(Arc<Mutex<HashMap<IpAddr, Instant>>>);
+
+impl BanList {
+    pub fn ban(&self, ip: IpAddr, duration: Duration) {
+        self.0.lock().unwrap().insert(ip, Instant::now() + duration);
+    }
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let mut bans = self.0.lock().unwrap();
+        let now = Instant::now();
+        bans.retain(|_, until| *until > now);
+        bans.contains_key(&ip)
+    }
+}
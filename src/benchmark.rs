@@ -0,0 +1,172 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+
+use crate::{
+    client_handler::{recv_response, ClientChannel},
+    output::OutputEvent,
+    protocol::{self, Request, Response},
+    ClientCommand, ClientEvent,
+};
+
+const PROGRESS_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const THROTTLED_BACKOFF: Duration = Duration::from_millis(10);
+
+// Stands in for the output thread, so no video is encoded, and counts the
+// frames the server finishes
+pub fn spawn_output_sink(rx: mpsc::Receiver<OutputEvent>) -> Arc<AtomicU64> {
+    let frames = Arc::new(AtomicU64::new(0));
+    let frames2 = frames.clone();
+    thread::spawn(move || {
+        for event in rx {
+            match event {
+                OutputEvent::BlitTile(blit) if blit.completes_frame => {
+                    frames2.fetch_add(1, Ordering::Relaxed);
+                }
+                OutputEvent::ForceCompleteFrame(_) => {
+                    frames2.fetch_add(1, Ordering::Relaxed);
+                }
+                _ => {}
+            }
+        }
+    });
+    frames
+}
+
+struct ClientReport {
+    tiles: u64,
+    latencies: Vec<Duration>,
+}
+
+// Unsolicited messages like keepalives use request ID 0, so skip past them
+fn wait_for(
+    rx: &crossbeam_channel::Receiver<ClientCommand>,
+    request_id: u32,
+) -> anyhow::Result<Response> {
+    loop {
+        let (id, response) = recv_response(rx)?;
+        if id == request_id {
+            return Ok(response);
+        }
+    }
+}
+
+fn run_client(
+    index: usize,
+    tx: crossbeam_channel::Sender<ClientEvent>,
+    stop: Arc<AtomicBool>,
+) -> anyhow::Result<ClientReport> {
+    let (channel, rx) = ClientChannel::new(tx, format!("benchmark client {index}"));
+    channel.send_request(1, Request::SetName(format!("benchmark-{index}")));
+    wait_for(&rx, 1)?;
+
+    let mut report = ClientReport {
+        tiles: 0,
+        latencies: Vec::new(),
+    };
+    let mut request_id = 1;
+    while !stop.load(Ordering::Relaxed) {
+        request_id += 1;
+        let started = Instant::now();
+        channel.send_request(request_id, Request::ReserveRays);
+        let rays = match wait_for(&rx, request_id)? {
+            Response::ReserveRays(rays, _) => rays,
+            Response::Throttled => {
+                thread::sleep(THROTTLED_BACKOFF);
+                continue;
+            }
+            other => bail!("Unexpected response to ReserveRays: {:?}", other),
+        };
+        report.latencies.push(started.elapsed());
+
+        // The server only checks the count, so any pattern will do
+        let results = (0..rays.len())
+            .map(|i| protocol::Result {
+                hit: i % 7 == 0,
+                color: None,
+                depth: None,
+                normal: None,
+                ray_depth: 0,
+            })
+            .collect();
+        request_id += 1;
+        let started = Instant::now();
+        channel.send_request(request_id, Request::SubmitResults(results));
+        match wait_for(&rx, request_id)? {
+            Response::SubmitResults => {}
+            other => bail!("Unexpected response to SubmitResults: {:?}", other),
+        }
+        report.latencies.push(started.elapsed());
+        report.tiles += 1;
+    }
+    request_id += 1;
+    channel.send_request(request_id, Request::Goodbye);
+    wait_for(&rx, request_id)?;
+    Ok(report)
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    sorted[((sorted.len() - 1) as f64 * p).round() as usize]
+}
+
+// Drives the server with synthetic clients over the same channels real
+// clients use, until enough frames are done, then prints a summary
+pub fn run(
+    n_clients: usize,
+    target_frames: u64,
+    frames: Arc<AtomicU64>,
+    tx: crossbeam_channel::Sender<ClientEvent>,
+) -> anyhow::Result<()> {
+    println!("Benchmarking with {n_clients} clients until {target_frames} frames are done...");
+    let start = Instant::now();
+    let stop = Arc::new(AtomicBool::new(false));
+    let clients: Vec<_> = (0..n_clients)
+        .map(|index| {
+            let tx = tx.clone();
+            let stop = stop.clone();
+            thread::spawn(move || run_client(index, tx, stop))
+        })
+        .collect();
+    while frames.load(Ordering::Relaxed) < target_frames {
+        if clients.iter().all(|client| client.is_finished()) {
+            bail!("Every benchmark client stopped early");
+        }
+        thread::sleep(PROGRESS_POLL_INTERVAL);
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    stop.store(true, Ordering::Relaxed);
+
+    let mut tiles = 0;
+    let mut latencies = Vec::new();
+    for client in clients {
+        match client.join().unwrap() {
+            Ok(report) => {
+                tiles += report.tiles;
+                latencies.extend(report.latencies);
+            }
+            Err(e) => log::warn!("Benchmark client failed: {:?}", e),
+        }
+    }
+    latencies.sort();
+
+    println!("Finished in {:.1}s", elapsed);
+    println!("  Frames completed: {}", frames.load(Ordering::Relaxed));
+    println!("  Tiles per second: {:.1}", tiles as f64 / elapsed);
+    println!(
+        "  Round-trip latency: p50 {:?}, p95 {:?}, p99 {:?}",
+        percentile(&latencies, 0.50),
+        percentile(&latencies, 0.95),
+        percentile(&latencies, 0.99)
+    );
+    Ok(())
+}
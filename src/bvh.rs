@@ -0,0 +1,190 @@
+use crate::protocol::{BvhNode, Sphere, Vec3, BVH_LEAF};
+
+// Smaller scenes get an empty `Scene::bvh_nodes`
+const MIN_BVH_SPHERES: usize = 4;
+
+fn sphere_bounds(sphere: &Sphere) -> (Vec3, Vec3) {
+    let r = sphere.radius;
+    let c = sphere.center;
+    (
+        Vec3 {
+            x: c.x - r,
+            y: c.y - r,
+            z: c.z - r,
+        },
+        Vec3 {
+            x: c.x + r,
+            y: c.y + r,
+            z: c.z + r,
+        },
+    )
+}
+
+fn union(a: (Vec3, Vec3), b: (Vec3, Vec3)) -> (Vec3, Vec3) {
+    (
+        Vec3 {
+            x: a.0.x.min(b.0.x),
+            y: a.0.y.min(b.0.y),
+            z: a.0.z.min(b.0.z),
+        },
+        Vec3 {
+            x: a.1.x.max(b.1.x),
+            y: a.1.y.max(b.1.y),
+            z: a.1.z.max(b.1.z),
+        },
+    )
+}
+
+fn axis_value(v: &Vec3, axis: usize) -> f32 {
+    match axis {
+        0 => v.x,
+        1 => v.y,
+        _ => v.z,
+    }
+}
+
+fn bounds(spheres: &[Sphere], indices: &[u32]) -> (Vec3, Vec3) {
+    indices
+        .iter()
+        .map(|&i| sphere_bounds(&spheres[i as usize]))
+        .reduce(union)
+        .unwrap()
+}
+
+// Partitions `indices` at the median along the longest axis of the bounds,
+// returning where the second half starts
+fn median_split(spheres: &[Sphere], indices: &mut [u32], (min, max): (Vec3, Vec3)) -> usize {
+    let extent = [max.x - min.x, max.y - min.y, max.z - min.z];
+    let axis = (0..3)
+        .max_by(|&a, &b| extent[a].total_cmp(&extent[b]))
+        .unwrap();
+    let mid = indices.len() / 2;
+    indices.select_nth_unstable_by(mid, |&a, &b| {
+        axis_value(&spheres[a as usize].center, axis)
+            .total_cmp(&axis_value(&spheres[b as usize].center, axis))
+    });
+    mid
+}
+
+struct Builder<'a> {
+    spheres: &'a [Sphere],
+    nodes: Vec<BvhNode>,
+}
+
+impl Builder<'_> {
+    // Splits `indices` all the way down to single spheres, returning the index
+    // of the new node
+    fn node(&mut self, indices: &mut [u32]) -> u32 {
+        let (min, max) = bounds(self.spheres, indices);
+        let index = self.nodes.len();
+        self.nodes.push(BvhNode {
+            left: indices[0],
+            right: BVH_LEAF,
+            aabb_min: min,
+            aabb_max: max,
+        });
+        if indices.len() > 1 {
+            let mid = median_split(self.spheres, indices, (min, max));
+            let (left, right) = indices.split_at_mut(mid);
+            let left_index = self.node(left);
+            let right_index = self.node(right);
+            self.nodes[index].left = left_index;
+            self.nodes[index].right = right_index;
+        }
+        index as u32
+    }
+}
+
+// Builds `Scene::bvh_nodes` by splitting the spheres at the median along the
+// longest axis
+pub fn build(spheres: &[Sphere]) -> Vec<BvhNode> {
+    if spheres.len() < MIN_BVH_SPHERES {
+        return Vec::new();
+    }
+    let mut indices: Vec<u32> = (0..spheres.len() as u32).collect();
+    let mut builder = Builder {
+        spheres,
+        nodes: Vec::new(),
+    };
+    builder.node(&mut indices);
+    builder.nodes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Spread out spheres from a fixed LCG, so the tree has several levels
+    fn spheres(count: usize) -> Vec<Sphere> {
+        let mut state = 12345u32;
+        let mut next = || {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 8) as f32 / (1 << 24) as f32 * 100.0
+        };
+        (0..count)
+            .map(|_| Sphere {
+                center: Vec3 {
+                    x: next(),
+                    y: next(),
+                    z: next(),
+                },
+                radius: next() / 10.0 + 0.1,
+                emissive: Vec3::default(),
+            })
+            .collect()
+    }
+
+    fn contains(outer: (Vec3, Vec3), inner: (Vec3, Vec3)) -> bool {
+        outer.0.x <= inner.0.x
+            && outer.0.y <= inner.0.y
+            && outer.0.z <= inner.0.z
+            && outer.1.x >= inner.1.x
+            && outer.1.y >= inner.1.y
+            && outer.1.z >= inner.1.z
+    }
+
+    #[test]
+    fn small_scenes_have_no_tree() {
+        assert!(build(&[]).is_empty());
+        assert!(build(&spheres(MIN_BVH_SPHERES - 1)).is_empty());
+        assert_eq!(build(&spheres(MIN_BVH_SPHERES)).len(), 7);
+    }
+
+    #[test]
+    fn tree_nodes_bound_their_children() {
+        let spheres = spheres(16);
+        let nodes = build(&spheres);
+        // A full binary tree over 16 leaves
+        assert_eq!(nodes.len(), 31);
+        for node in &nodes {
+            let bounds = (node.aabb_min, node.aabb_max);
+            if node.right == BVH_LEAF {
+                assert!(contains(
+                    bounds,
+                    sphere_bounds(&spheres[node.left as usize])
+                ));
+                continue;
+            }
+            for child in [node.left, node.right] {
+                let child = &nodes[child as usize];
+                assert!(contains(bounds, (child.aabb_min, child.aabb_max)));
+            }
+        }
+    }
+
+    #[test]
+    fn leaves_cover_every_sphere_once() {
+        let spheres = spheres(100);
+        let nodes = build(&spheres);
+        let mut seen = vec![0; spheres.len()];
+        for (index, node) in nodes.iter().enumerate() {
+            if node.right == BVH_LEAF {
+                seen[node.left as usize] += 1;
+            } else {
+                // Depth-first, so the left child comes straight after
+                assert_eq!(node.left as usize, index + 1);
+            }
+        }
+        assert!(seen.iter().all(|&count| count == 1));
+    }
+}
@@ -1,36 +1,170 @@
 use std::{
-    io::{self, Read, Write},
-    net::TcpStream,
-    sync::mpsc,
+    cell::Cell,
+    fmt,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Shutdown, TcpStream},
+    os::unix::net::UnixStream,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
 use crate::{
-    client_id::ClientId, protocol::Request, utils::SyncSenderExt, ClientCommand, ClientEvent,
-    ClientEventPayload,
+    ban_list::BanList,
+    client_id::ClientId,
+    protocol::{
+        request_name, response_name, JsonResponse, LegacyRequest, LegacyResponse, Request,
+        Response, FEATURE_EXTENDED_FIELDS, FEATURE_TAGGED, LEGACY_FEATURES, SUPPORTED_FEATURES,
+    },
+    utils::SyncSenderExt,
+    ClientCommand, ClientEvent, ClientEventPayload,
 };
 use anyhow::{anyhow, Context};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use snap::raw::{Decoder, Encoder};
 
-pub struct ClientHandler {
-    id: ClientId,
-    stream: TcpStream,
-    tx: mpsc::SyncSender<ClientEvent>,
-    rx: mpsc::Receiver<ClientCommand>,
+const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+const MAX_LINE_LENGTH: usize = 1024 * 1024;
+const MAX_RESPONSE_SIZE: usize = 64 * 1024 * 1024;
+// Set on a binary protocol version to prefix every frame with a request ID
+const TAGGED_FLAG: u32 = 0x100;
+// Set on a binary protocol version of 2 or later to follow it with a u64 of
+// requested features. The server answers with a u64 of those it agreed to.
+const FEATURES_FLAG: u32 = 0x200;
+// For writes, and on top of `ServerConfig::max_client_silence` for reads, as
+// the server expires leases and idle clients itself
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Why a connection ended. Callers get these wrapped in `anyhow::Error`, and
+// can tell a misbehaving client from a problem on our side by downcasting.
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    // The client sent something we couldn't decode or won't accept
+    Protocol(String),
+    // A response couldn't be encoded, which is a bug on our side
+    Serialize(String),
+    Timeout,
+    Disconnected,
+}
+
+impl ClientError {
+    fn protocol(e: impl fmt::Display) -> Self {
+        Self::Protocol(e.to_string())
+    }
+    fn serialize(e: impl fmt::Display) -> Self {
+        Self::Serialize(e.to_string())
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "I/O error: {e}"),
+            Self::Protocol(message) => write!(f, "Protocol error: {message}"),
+            Self::Serialize(message) => write!(f, "Failed to encode response: {message}"),
+            Self::Timeout => f.write_str("Timed out"),
+            Self::Disconnected => f.write_str("Disconnected"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            // Read timeouts show up as either, depending on the platform
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Self::Timeout,
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => Self::Disconnected,
+            // Includes failed TLS handshakes
+            io::ErrorKind::InvalidData => Self::Protocol(e.to_string()),
+            _ => Self::Io(e),
+        }
+    }
 }
 
-impl ClientHandler {
-    fn new(stream: TcpStream, tx: mpsc::SyncSender<ClientEvent>) -> Self {
-        let (tx2, rx) = mpsc::sync_channel(16);
+// Decoding errors, as that's where clients are involved. Encoding maps its
+// errors to `ClientError::Serialize` explicitly.
+impl From<serde_json::Error> for ClientError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::protocol(e)
+    }
+}
+
+impl From<postcard::Error> for ClientError {
+    fn from(e: postcard::Error) -> Self {
+        Self::protocol(e)
+    }
+}
+
+fn zstd_decompress(data: &[u8]) -> Result<Vec<u8>, ClientError> {
+    let mut res = Vec::new();
+    zstd::stream::read::Decoder::new(data)?
+        .take(MAX_DECOMPRESSED_SIZE as u64 + 1)
+        .read_to_end(&mut res)
+        .map_err(ClientError::protocol)?;
+    if res.len() > MAX_DECOMPRESSED_SIZE {
+        return Err(ClientError::Protocol(format!(
+            "Decompressed frame exceeds {MAX_DECOMPRESSED_SIZE} bytes"
+        )));
+    }
+    Ok(res)
+}
+
+fn zstd_compress(data: &[u8]) -> Result<Vec<u8>, ClientError> {
+    let res = zstd::bulk::compress(data, 0).map_err(ClientError::serialize)?;
+    log::debug!(
+        "Compressed response from {} to {} bytes (ratio {:.2})",
+        data.len(),
+        res.len(),
+        data.len() as f64 / res.len() as f64
+    );
+    Ok(res)
+}
+
+// The connection to the server thread shared by every transport. Creating
+// one registers the client, and dropping it unregisters it. Commands from
+// the server arrive on the receiver returned alongside it.
+pub struct ClientChannel {
+    pub id: ClientId,
+    tx: crossbeam_channel::Sender<ClientEvent>,
+    // Lets the handler answer some requests itself. Because this keeps the
+    // receiver open, the server must send an explicit Disconnect to end it.
+    commands_tx: crossbeam_channel::Sender<ClientCommand>,
+    // Goodbye unregisters the client, so there's no Disconnected to send
+    said_goodbye: Cell<bool>,
+    // The connection underneath, to read its round-trip time when pinged
+    tcp: Option<TcpStream>,
+}
+
+impl ClientChannel {
+    pub fn new(
+        tx: crossbeam_channel::Sender<ClientEvent>,
+        addr: String,
+    ) -> (Self, crossbeam_channel::Receiver<ClientCommand>) {
+        let (tx2, rx) = crossbeam_channel::bounded(16);
         let res = Self {
             id: ClientId::new(),
-            stream,
             tx,
-            rx,
+            commands_tx: tx2.clone(),
+            said_goodbye: Cell::new(false),
+            tcp: None,
         };
-        res.emit(ClientEventPayload::Connected(tx2));
-        res
+        res.emit(ClientEventPayload::Connected(tx2, addr));
+        (res, rx)
     }
     fn emit(&self, payload: ClientEventPayload) {
         let _ = self.tx.send_realtime(
@@ -41,69 +175,1248 @@ impl ClientHandler {
             "ClientHandler.tx",
         );
     }
-    pub fn run(&mut self) -> anyhow::Result<()> {
-        let timeout = Some(Duration::from_secs(5));
-        self.stream.set_read_timeout(timeout)?;
-        self.stream.set_write_timeout(timeout)?;
-        self.stream.set_nodelay(true)?;
+    fn respond(&self, request_id: u32, response: Response) {
+        let _ = self.commands_tx.send_realtime(
+            ClientCommand::Response(request_id, response),
+            "ClientChannel.commands_tx",
+        );
+    }
+    pub fn measure_round_trips(&mut self, tcp: TcpStream) {
+        self.tcp = Some(tcp);
+    }
+    fn set_features(&self, features: u64) {
+        self.emit(ClientEventPayload::SetFeatures(features));
+    }
+    pub fn send_request(&self, request_id: u32, request: Request) {
+        match request {
+            Request::Ping(timestamp) => {
+                self.respond(request_id, Response::Pong(timestamp));
+                let rtt = self.tcp.as_ref().and_then(round_trip_time);
+                self.emit(ClientEventPayload::Heartbeat(rtt));
+            }
+            Request::Goodbye => {
+                self.said_goodbye.set(true);
+                self.emit(ClientEventPayload::Request(request_id, Request::Goodbye));
+            }
+            request => self.emit(ClientEventPayload::Request(request_id, request)),
+        }
+    }
+    // Once true, the handler should stop reading and let the reply go out
+    pub fn said_goodbye(&self) -> bool {
+        self.said_goodbye.get()
+    }
+}
+
+// The kernel's smoothed estimate for the connection, so it reflects the
+// network rather than how busy the client is
+#[cfg(target_os = "linux")]
+fn round_trip_time(stream: &TcpStream) -> Option<Duration> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut libc::tcp_info as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    (res == 0).then(|| Duration::from_micros(info.tcpi_rtt.into()))
+}
 
-        let protocol_version = self.stream.read_u32::<BigEndian>()?;
-        if protocol_version > 2 {
-            return Err(anyhow!("Unknown protocol version: {protocol_version}"));
+#[cfg(not(target_os = "linux"))]
+fn round_trip_time(_stream: &TcpStream) -> Option<Duration> {
+    None
+}
+
+impl Drop for ClientChannel {
+    fn drop(&mut self) {
+        if !self.said_goodbye.get() {
+            self.emit(ClientEventPayload::Disconnected);
         }
+    }
+}
+
+pub fn recv_response(
+    rx: &crossbeam_channel::Receiver<ClientCommand>,
+) -> anyhow::Result<(u32, Response)> {
+    match rx.recv()? {
+        ClientCommand::Response(request_id, response) => Ok((request_id, response)),
+        ClientCommand::Disconnect(reason) => Err(anyhow!(reason)),
+    }
+}
+
+// Streams which can be read and written from different threads at once.
+pub trait DuplexStream {
+    type Reader: Read;
+    type Writer: ShutdownWrite + Send + 'static;
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)>;
+    // The TCP connection carrying the stream, if there is one
+    fn tcp(&self) -> Option<&TcpStream>;
+}
+
+pub trait ShutdownWrite: Write {
+    // Closes the connection, which also unblocks the reading half
+    fn shutdown(&mut self);
+}
+
+impl DuplexStream for TcpStream {
+    type Reader = TcpStream;
+    type Writer = TcpStream;
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)> {
+        let writer = self.try_clone()?;
+        Ok((self, writer))
+    }
+    fn tcp(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+impl ShutdownWrite for TcpStream {
+    fn shutdown(&mut self) {
+        let _ = TcpStream::shutdown(self, Shutdown::Both);
+    }
+}
+
+impl DuplexStream for UnixStream {
+    type Reader = UnixStream;
+    type Writer = UnixStream;
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)> {
+        let writer = self.try_clone()?;
+        Ok((self, writer))
+    }
+    fn tcp(&self) -> Option<&TcpStream> {
+        None
+    }
+}
+
+impl ShutdownWrite for UnixStream {
+    fn shutdown(&mut self) {
+        let _ = UnixStream::shutdown(self, Shutdown::Both);
+    }
+}
 
+#[derive(Copy, Clone)]
+enum Protocol {
+    JsonLines,
+    // `features` are those negotiated, or `LEGACY_FEATURES`
+    Binary {
+        version: u32,
+        tagged: bool,
+        features: u64,
+    },
+}
+
+impl Protocol {
+    fn encode_response(self, request_id: u32, response: &Response) -> Result<Vec<u8>, ClientError> {
+        let encode_json =
+            || serde_json::to_vec(&JsonResponse(response)).map_err(ClientError::serialize);
+        let encode_postcard = || postcard::to_allocvec(response).map_err(ClientError::serialize);
+        let encode_legacy_postcard =
+            || postcard::to_allocvec(&LegacyResponse(response)).map_err(ClientError::serialize);
+        let snappy = |data: Vec<u8>| {
+            Encoder::new()
+                .compress_vec(&data)
+                .map_err(ClientError::serialize)
+        };
+        let (protocol_version, tagged, features) = match self {
+            Protocol::JsonLines => {
+                let mut vec = encode_json()?;
+                vec.push(b'\n');
+                return Ok(vec);
+            }
+            Protocol::Binary {
+                version,
+                tagged,
+                features,
+            } => (version, tagged, features),
+        };
+        let vec = match protocol_version {
+            0 => encode_json()?,
+            1 => snappy(encode_json()?)?,
+            2 if features & FEATURE_EXTENDED_FIELDS == 0 => snappy(encode_legacy_postcard()?)?,
+            2 => snappy(encode_postcard()?)?,
+            3 => zstd_compress(&encode_postcard()?)?,
+            4 => {
+                let mut vec = Vec::new();
+                ciborium::ser::into_writer(response, &mut vec).map_err(ClientError::serialize)?;
+                vec
+            }
+            _ => unreachable!(),
+        };
+        if vec.len() > MAX_RESPONSE_SIZE {
+            return Err(ClientError::Serialize(format!(
+                "Response of {} bytes exceeds {MAX_RESPONSE_SIZE} bytes",
+                vec.len()
+            )));
+        }
+        let mut frame = Vec::with_capacity(vec.len() + 8);
+        frame.write_u32::<BigEndian>(vec.len() as u32)?;
+        if tagged {
+            frame.write_u32::<BigEndian>(request_id)?;
+        }
+        frame.extend_from_slice(&vec);
+        metrics::histogram!("workshop_response_frame_bytes", "response" => response_name(response))
+            .record(vec.len() as f64);
+        Ok(frame)
+    }
+}
+
+// Responses are written in the order the server sends them, which for a
+// single client is the order its requests were made.
+fn write_responses<W: Write>(
+    commands: crossbeam_channel::Receiver<ClientCommand>,
+    writer: &mut W,
+    protocol: Protocol,
+    negotiated_features: Option<u64>,
+) -> Result<(), ClientError> {
+    if let Some(features) = negotiated_features {
+        writer.write_u64::<BigEndian>(features)?;
+        writer.flush()?;
+    }
+    // The server drops its sender once it sees the client disconnect
+    while let Ok((request_id, response)) = recv_response(&commands) {
+        writer.write_all(&protocol.encode_response(request_id, &response)?)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+// Counts the connections being handled, so that --max-clients can turn new
+// ones away. Shared by every listener.
+#[derive(Clone)]
+pub struct ConnectionLimit {
+    active: Arc<AtomicUsize>,
+    max: Option<usize>,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: Option<usize>) -> Self {
+        Self {
+            active: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+    // Returns `None` if the server is already full
+    pub fn try_acquire(&self) -> Option<ConnectionSlot> {
+        let active = self.active.fetch_add(1, Ordering::Relaxed) + 1;
+        let slot = ConnectionSlot(self.active.clone());
+        // Over the limit, the slot is released straight away by dropping it
+        if self.max.is_some_and(|max| active > max) {
+            None
+        } else {
+            Some(slot)
+        }
+    }
+}
+
+pub struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Settings shared by the handlers of every TCP and Unix socket client
+#[derive(Clone)]
+pub struct HandlerConfig {
+    pub max_frame_size: usize,
+    // See `ServerConfig::max_client_silence`
+    pub max_silence: Duration,
+    // Each client's writer runs here for as long as it's connected, rather than
+    // on a thread of its own, so it needs a thread per connection just like
+    // the reader pool.
+    pub writers: Arc<rayon::ThreadPool>,
+}
+
+pub struct ClientHandler<R> {
+    channel: ClientChannel,
+    addr: String,
+    stream: BufReader<R>,
+    max_frame_size: usize,
+    writers: Arc<rayon::ThreadPool>,
+    features: u64,
+    // Counts against --max-clients until the handler is dropped
+    _slot: ConnectionSlot,
+}
+
+impl<R: Read> ClientHandler<R> {
+    fn new(
+        stream: R,
+        channel: ClientChannel,
+        addr: String,
+        config: &HandlerConfig,
+        slot: ConnectionSlot,
+    ) -> Self {
+        Self {
+            channel,
+            addr,
+            stream: BufReader::new(stream),
+            max_frame_size: config.max_frame_size,
+            writers: config.writers.clone(),
+            features: LEGACY_FEATURES,
+            _slot: slot,
+        }
+    }
+    fn spawn_writer<W: ShutdownWrite + Send + 'static>(
+        &self,
+        mut writer: W,
+        commands: crossbeam_channel::Receiver<ClientCommand>,
+        protocol: Protocol,
+        negotiated_features: Option<u64>,
+    ) {
+        let id = self.channel.id.0;
+        let addr = self.addr.clone();
+        self.writers.spawn(move || {
+            if let Err(e) = write_responses(commands, &mut writer, protocol, negotiated_features) {
+                log::error!("Client ({id} - {addr}) - {e}");
+            }
+            // The reader owns the channel, so it unregisters the client once it stops
+            writer.shutdown();
+        });
+    }
+    pub fn run<W: ShutdownWrite + Send + 'static>(
+        &mut self,
+        writer: W,
+        commands: crossbeam_channel::Receiver<ClientCommand>,
+    ) -> Result<(), ClientError> {
+        // Binary protocol versions start with a big-endian u32, so the first
+        // byte is always zero. A JSON value instead means newline-delimited JSON.
+        let first_byte = self.stream.fill_buf()?.first().copied();
+        if matches!(first_byte, Some(b'{' | b'"')) {
+            self.spawn_writer(writer, commands, Protocol::JsonLines, None);
+            self.run_json_lines()
+        } else {
+            let protocol_version = self.stream.read_u32::<BigEndian>()?;
+            let version = protocol_version & !(TAGGED_FLAG | FEATURES_FLAG);
+            if version > 4 {
+                return Err(ClientError::Protocol(format!(
+                    "Unknown protocol version: {protocol_version}"
+                )));
+            }
+            // Versions 0 and 1 keep the original handshake
+            let negotiated_features = if protocol_version & FEATURES_FLAG != 0 {
+                if version < 2 {
+                    return Err(ClientError::Protocol(format!(
+                        "Feature negotiation needs protocol version 2 or later, got {version}"
+                    )));
+                }
+                let features = self.stream.read_u64::<BigEndian>()? & SUPPORTED_FEATURES;
+                self.features = features;
+                self.channel.set_features(features);
+                Some(features)
+            } else {
+                None
+            };
+            let tagged = protocol_version & TAGGED_FLAG != 0 || self.features & FEATURE_TAGGED != 0;
+            self.spawn_writer(
+                writer,
+                commands,
+                Protocol::Binary {
+                    version,
+                    tagged,
+                    features: self.features,
+                },
+                negotiated_features,
+            );
+            self.run_binary(version, tagged)
+        }
+    }
+    fn run_json_lines(&mut self) -> Result<(), ClientError> {
+        log::info!(
+            "Client ({:?} - {}) - Connected (protocol: json-lines)",
+            self.channel.id,
+            self.addr
+        );
+
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            (&mut self.stream)
+                .take(MAX_LINE_LENGTH as u64 + 1)
+                .read_until(b'\n', &mut line)?;
+            if line.is_empty() {
+                return Ok(());
+            }
+            if line.len() > MAX_LINE_LENGTH {
+                return Err(ClientError::Protocol(format!(
+                    "Request line exceeds {MAX_LINE_LENGTH} bytes"
+                )));
+            }
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+
+            let request: Request = serde_json::from_slice(&line)?;
+            self.channel.send_request(0, request);
+            if self.channel.said_goodbye() {
+                return Ok(());
+            }
+        }
+    }
+    fn run_binary(&mut self, protocol_version: u32, tagged: bool) -> Result<(), ClientError> {
         log::info!(
-            "Client ({:?} - {}) - Connected (protocol: {})",
-            self.id,
-            self.stream.peer_addr()?,
-            protocol_version
+            "Client ({:?} - {}) - Connected (protocol: {}{}, features: {:#x})",
+            self.channel.id,
+            self.addr,
+            protocol_version,
+            if tagged { ", tagged" } else { "" },
+            self.features
         );
 
         let mut buffer = Vec::new();
         loop {
             let frame_size = self.stream.read_u32::<BigEndian>()? as usize;
+            // Check before allocating, as the length comes straight from the client
+            if frame_size > self.max_frame_size {
+                // The writer sends this before closing, once the channel is dropped
+                self.channel
+                    .respond(0, Response::Error("Frame too large".into()));
+                return Err(ClientError::Protocol(format!(
+                    "Frame of {frame_size} bytes exceeds the limit of {} bytes",
+                    self.max_frame_size
+                )));
+            }
+            let request_id = if tagged {
+                self.stream.read_u32::<BigEndian>()?
+            } else {
+                0
+            };
             buffer.resize(frame_size, 0);
             self.stream.read_exact(&mut buffer)?;
 
+            let snappy = |data: &[u8]| {
+                Decoder::new()
+                    .decompress_vec(data)
+                    .map_err(ClientError::protocol)
+            };
             let request: Request = match protocol_version {
                 0 => serde_json::from_slice(&buffer)?,
-                1 => serde_json::from_slice(&Decoder::new().decompress_vec(&buffer)?)?,
-                2 => postcard::from_bytes(&Decoder::new().decompress_vec(&buffer)?)?,
+                1 => serde_json::from_slice(&snappy(&buffer)?)?,
+                2 if self.features & FEATURE_EXTENDED_FIELDS == 0 => {
+                    postcard::from_bytes::<LegacyRequest>(&snappy(&buffer)?)?.into()
+                }
+                2 => postcard::from_bytes(&snappy(&buffer)?)?,
+                3 => postcard::from_bytes(&zstd_decompress(&buffer)?)?,
+                4 => ciborium::de::from_reader(buffer.as_slice()).map_err(ClientError::protocol)?,
                 _ => unreachable!(),
             };
-
-            self.emit(ClientEventPayload::Request(request));
-
-            match self.rx.recv()? {
-                ClientCommand::Response(response) => {
-                    let vec = match protocol_version {
-                        0 => serde_json::to_vec(&response)?,
-                        1 => Encoder::new().compress_vec(&serde_json::to_vec(&response)?)?,
-                        2 => Encoder::new().compress_vec(&postcard::to_allocvec(&response)?)?,
-                        _ => unreachable!(),
-                    };
-                    self.stream.write_u32::<BigEndian>(vec.len() as u32)?;
-                    self.stream.write_all(&vec)?;
-                }
+            metrics::histogram!("workshop_request_frame_bytes", "request" => request_name(&request))
+                .record(frame_size as f64);
+            self.channel.send_request(request_id, request);
+            if self.channel.said_goodbye() {
+                return Ok(());
             }
         }
     }
 }
 
-impl Drop for ClientHandler {
-    fn drop(&mut self) {
-        self.emit(ClientEventPayload::Disconnected);
+fn run_handler<S: DuplexStream>(
+    stream: S,
+    addr: String,
+    tx: crossbeam_channel::Sender<ClientEvent>,
+    config: &HandlerConfig,
+    slot: ConnectionSlot,
+) -> anyhow::Result<()> {
+    let tcp = stream.tcp().map(TcpStream::try_clone).transpose()?;
+    let (reader, writer) = stream.split()?;
+    let (mut channel, commands) = ClientChannel::new(tx, addr.clone());
+    if let Some(tcp) = tcp {
+        channel.measure_round_trips(tcp);
+    }
+    let mut client_handler = ClientHandler::new(reader, channel, addr.clone(), config, slot);
+    let id = client_handler.channel.id.0;
+    client_handler
+        .run(writer, commands)
+        .with_context(|| format!("Client ({id} - {addr})"))
+}
+
+// Runs on the accept thread, so the client's protocol isn't known and the
+// error goes out as a JSON line. TLS clients couldn't read it before a
+// handshake, which would need a worker, so they are just hung up on.
+pub fn refuse_connection(mut stream: impl Write, addr: &str, tls: bool) {
+    log::warn!("Refusing connection from {addr}, the server is at --max-clients");
+    if tls {
+        return;
+    }
+    let error = Response::Error("Server is full".into());
+    if let Ok(mut line) = serde_json::to_vec(&JsonResponse(&error)) {
+        line.push(b'\n');
+        let _ = stream.write_all(&line);
     }
 }
 
 pub fn client_connected(
-    stream: Result<TcpStream, io::Error>,
-    tx: mpsc::SyncSender<ClientEvent>,
+    stream: TcpStream,
+    slot: ConnectionSlot,
+    tx: crossbeam_channel::Sender<ClientEvent>,
+    tls_config: Option<Arc<rustls::ServerConfig>>,
+    config: &HandlerConfig,
+    ban_list: &BanList,
 ) -> anyhow::Result<()> {
-    let mut client_handler = ClientHandler::new(stream?, tx);
-    let addr = client_handler.stream.peer_addr()?;
-    let id = client_handler.id.0;
-    client_handler
-        .run()
-        .with_context(|| format!("Client ({id} - {addr})"))
+    let addr = stream.peer_addr()?;
+    // Dropping the stream closes it
+    if ban_list.is_banned(addr.ip()) {
+        log::info!("Refusing connection from banned address {addr}");
+        return Ok(());
+    }
+    stream.set_read_timeout(Some(config.max_silence + CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+
+    if let Some(tls_config) = tls_config {
+        let conn = rustls::ServerConnection::new(tls_config)?;
+        let mut tls_stream = rustls::StreamOwned::new(conn, stream);
+        while tls_stream.conn.is_handshaking() {
+            tls_stream
+                .conn
+                .complete_io(&mut tls_stream.sock)
+                .map_err(ClientError::from)
+                .with_context(|| format!("TLS handshake ({addr})"))?;
+        }
+        run_handler(tls_stream, addr.to_string(), tx, config, slot)
+    } else {
+        run_handler(stream, addr.to_string(), tx, config, slot)
+    }
+}
+
+pub fn unix_client_connected(
+    stream: UnixStream,
+    slot: ConnectionSlot,
+    tx: crossbeam_channel::Sender<ClientEvent>,
+    config: &HandlerConfig,
+) -> anyhow::Result<()> {
+    stream.set_read_timeout(Some(config.max_silence + CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    run_handler(stream, "unix socket".into(), tx, config, slot)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, time::Instant};
+
+    use super::*;
+    use crate::{
+        protocol::{self, Ray, Scene, Sphere, Vec3, FEATURE_PACKED_RESULTS},
+        stats,
+    };
+
+    fn test_config() -> HandlerConfig {
+        HandlerConfig {
+            max_frame_size: 1024 * 1024,
+            max_silence: Duration::from_secs(5),
+            writers: Arc::new(
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(1)
+                    .build()
+                    .unwrap(),
+            ),
+        }
+    }
+
+    fn cbor_frame(request: &Request) -> Vec<u8> {
+        let mut body = Vec::new();
+        ciborium::ser::into_writer(request, &mut body).unwrap();
+        let mut frame = Vec::new();
+        frame.write_u32::<BigEndian>(body.len() as u32).unwrap();
+        frame.extend_from_slice(&body);
+        frame
+    }
+
+    // Runs a handler over `input` as if a client had sent it and hung up,
+    // returning how it ended, the events it passed on and what it wrote back
+    fn run_input(input: Vec<u8>) -> (Result<(), ClientError>, Vec<ClientEvent>, Vec<u8>) {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let (channel, commands) = ClientChannel::new(tx, "test".into());
+        let slot = ConnectionLimit::new(None).try_acquire().unwrap();
+        let mut handler = ClientHandler::new(
+            Cursor::new(input),
+            channel,
+            "test".into(),
+            &test_config(),
+            slot,
+        );
+        let (writer, mut client) = UnixStream::pair().unwrap();
+        let result = handler.run(writer, commands);
+        drop(handler);
+        // Dropping the sender handed to the server lets the writer finish
+        let events = rx
+            .try_iter()
+            .filter(|event| !matches!(event.payload, ClientEventPayload::Connected(..)))
+            .collect();
+        let mut output = Vec::new();
+        client.read_to_end(&mut output).unwrap();
+        (result, events, output)
+    }
+
+    // A request body as a client speaking `version` would send it
+    fn encode_request(version: u32, request: &Request) -> Vec<u8> {
+        let json = || serde_json::to_vec(request).unwrap();
+        let postcard = || postcard::to_allocvec(request).unwrap();
+        match version {
+            0 => json(),
+            1 => Encoder::new().compress_vec(&json()).unwrap(),
+            2 => Encoder::new().compress_vec(&postcard()).unwrap(),
+            3 => zstd::bulk::compress(&postcard(), 0).unwrap(),
+            4 => {
+                let mut body = Vec::new();
+                ciborium::ser::into_writer(request, &mut body).unwrap();
+                body
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn decode_response(version: u32, body: &[u8]) -> Response {
+        let snappy = |data: &[u8]| Decoder::new().decompress_vec(data).unwrap();
+        match version {
+            0 => serde_json::from_slice(body).unwrap(),
+            1 => serde_json::from_slice(&snappy(body)).unwrap(),
+            2 => postcard::from_bytes(&snappy(body)).unwrap(),
+            3 => postcard::from_bytes(&zstd_decompress(body).unwrap()).unwrap(),
+            4 => ciborium::de::from_reader(body).unwrap(),
+            _ => unreachable!(),
+        }
+    }
+
+    fn write_frame(stream: &mut impl Write, version: u32, request: &Request) {
+        let body = encode_request(version, request);
+        stream.write_u32::<BigEndian>(body.len() as u32).unwrap();
+        stream.write_all(&body).unwrap();
+    }
+
+    fn read_frame(stream: &mut impl Read, version: u32) -> Response {
+        let len = stream.read_u32::<BigEndian>().unwrap();
+        let mut body = vec![0; len as usize];
+        stream.read_exact(&mut body).unwrap();
+        decode_response(version, &body)
+    }
+
+    #[test]
+    fn every_version_round_trips_over_loopback() {
+        for version in 0..=4 {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let (tx, rx) = crossbeam_channel::unbounded();
+            let server = std::thread::spawn(move || {
+                let (stream, addr) = listener.accept().unwrap();
+                let slot = ConnectionLimit::new(None).try_acquire().unwrap();
+                run_handler(stream, addr.to_string(), tx, &test_config(), slot)
+            });
+
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.write_u32::<BigEndian>(version).unwrap();
+            // Answered by the handler itself, so there's no server thread to run
+            write_frame(&mut client, version, &Request::Ping(1000 + version as u64));
+            write_frame(&mut client, version, &Request::SetName("Alice".into()));
+            let response = read_frame(&mut client, version);
+            assert!(
+                matches!(response, Response::Pong(n) if n == 1000 + version as u64),
+                "version {version}: {response:?}"
+            );
+            drop(client);
+            // The client hanging up ends the handler
+            assert!(server.join().unwrap().is_err());
+
+            let requests: Vec<_> = rx
+                .try_iter()
+                .filter_map(|event| match event.payload {
+                    ClientEventPayload::Request(_, request) => Some(request),
+                    _ => None,
+                })
+                .collect();
+            assert!(
+                matches!(&requests[..], [Request::SetName(name)] if name == "Alice"),
+                "version {version}: {requests:?}"
+            );
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn pings_over_tcp_report_the_round_trip_time() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let server = std::thread::spawn(move || {
+            let (stream, addr) = listener.accept().unwrap();
+            let slot = ConnectionLimit::new(None).try_acquire().unwrap();
+            run_handler(stream, addr.to_string(), tx, &test_config(), slot)
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_u32::<BigEndian>(4).unwrap();
+        write_frame(&mut client, 4, &Request::Ping(1));
+        assert!(matches!(read_frame(&mut client, 4), Response::Pong(1)));
+        drop(client);
+        assert!(server.join().unwrap().is_err());
+
+        let heartbeats: Vec<_> = rx
+            .try_iter()
+            .filter_map(|event| match event.payload {
+                ClientEventPayload::Heartbeat(rtt) => Some(rtt),
+                _ => None,
+            })
+            .collect();
+        assert!(matches!(heartbeats[..], [Some(_)]), "{heartbeats:?}");
+    }
+
+    #[test]
+    fn json_lines_work_over_loopback_without_a_handshake() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let server = std::thread::spawn(move || {
+            let (stream, addr) = listener.accept().unwrap();
+            let slot = ConnectionLimit::new(None).try_acquire().unwrap();
+            run_handler(stream, addr.to_string(), tx, &test_config(), slot)
+        });
+
+        // printf '{"SetName":"bob"}\n' | nc 127.0.0.1 $port
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"{\"SetName\":\"bob\"}\n").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+
+        // Stand in for the server thread
+        let commands_tx = match rx.recv_timeout(Duration::from_secs(1)).unwrap().payload {
+            ClientEventPayload::Connected(commands_tx, _) => commands_tx,
+            _ => panic!("Expected the client to connect first"),
+        };
+        let payload = rx.recv_timeout(Duration::from_secs(1)).unwrap().payload;
+        assert!(matches!(
+            &payload,
+            ClientEventPayload::Request(0, Request::SetName(name)) if name == "bob"
+        ));
+        commands_tx
+            .send(ClientCommand::Response(0, Response::SetName("bob".into())))
+            .unwrap();
+        drop(commands_tx);
+
+        client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut output = Vec::new();
+        client.read_to_end(&mut output).unwrap();
+        assert_eq!(output, b"\"SetName\"\n");
+        // Hanging up is how a JSON-lines client says it's done
+        assert!(server.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn over_long_json_lines_are_refused() {
+        let mut input = b"{\"SetName\":\"".to_vec();
+        input.resize(MAX_LINE_LENGTH + 1, b'a');
+        let (result, events, _) = run_input(input);
+        let expected = format!("Request line exceeds {MAX_LINE_LENGTH} bytes");
+        assert!(matches!(result, Err(ClientError::Protocol(m)) if m == expected));
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event.payload, ClientEventPayload::Request(..))));
+    }
+
+    #[test]
+    fn a_disconnect_command_closes_the_connection() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let server = std::thread::spawn(move || {
+            let (stream, addr) = listener.accept().unwrap();
+            let slot = ConnectionLimit::new(None).try_acquire().unwrap();
+            run_handler(stream, addr.to_string(), tx, &test_config(), slot)
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_u32::<BigEndian>(4).unwrap();
+        let commands_tx = match rx.recv_timeout(Duration::from_secs(1)).unwrap().payload {
+            ClientEventPayload::Connected(commands_tx, _) => commands_tx,
+            _ => panic!("Expected the client to connect first"),
+        };
+        commands_tx
+            .send(ClientCommand::Disconnect("Kicked by admin".into()))
+            .unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        assert!(server.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn connections_past_the_limit_are_refused_until_one_ends() {
+        let limit = ConnectionLimit::new(Some(2));
+        let first = limit.try_acquire().unwrap();
+        let _second = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+        // Being refused doesn't use up a slot
+        assert!(limit.try_acquire().is_none());
+        drop(first);
+        assert!(limit.try_acquire().is_some());
+
+        let unlimited = ConnectionLimit::new(None);
+        let slots: Vec<_> = (0..100).map(|_| unlimited.try_acquire()).collect();
+        assert!(slots.iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn connecting_more_clients_than_worker_threads_eventually_succeeds() {
+        // Accepting the way main does, with workers for two clients
+        let pool = |threads| {
+            Arc::new(
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build()
+                    .unwrap(),
+            )
+        };
+        let workers = pool(2);
+        let config = HandlerConfig {
+            writers: pool(2),
+            ..test_config()
+        };
+        let limit = ConnectionLimit::new(Some(2));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // No server thread: the handlers answer pings themselves
+        let (tx, _) = crossbeam_channel::unbounded();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let slot = match limit.try_acquire() {
+                    Some(slot) => slot,
+                    None => {
+                        refuse_connection(&stream, "test", false);
+                        continue;
+                    }
+                };
+                let tx = tx.clone();
+                let config = config.clone();
+                workers.spawn(move || {
+                    let _ = run_handler(stream, "test".into(), tx, &config, slot);
+                });
+            }
+        });
+
+        // Returns the connection if a worker answered its ping
+        let connect = || -> io::Result<TcpStream> {
+            let mut client = TcpStream::connect(addr)?;
+            client.set_read_timeout(Some(Duration::from_secs(5)))?;
+            client.write_u32::<BigEndian>(4)?;
+            let body = encode_request(4, &Request::Ping(7));
+            client.write_u32::<BigEndian>(body.len() as u32)?;
+            client.write_all(&body)?;
+            let len = client.read_u32::<BigEndian>()?;
+            // The start of a JSON line, if the server is full
+            if len >> 24 == b'{' as u32 {
+                return Err(io::ErrorKind::ConnectionRefused.into());
+            }
+            let mut body = vec![0; len as usize];
+            client.read_exact(&mut body)?;
+            match ciborium::de::from_reader(body.as_slice()) {
+                Ok(Response::Pong(7)) => Ok(client),
+                _ => Err(io::ErrorKind::InvalidData.into()),
+            }
+        };
+        let first = connect().unwrap();
+        let _second = connect().unwrap();
+        // Turned away straight away, rather than waiting for a worker
+        let started = Instant::now();
+        assert!(connect().is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        drop(first);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while connect().is_err() {
+            assert!(Instant::now() < deadline, "No worker was freed up");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn refused_connections_are_told_why() {
+        let mut written = Vec::new();
+        refuse_connection(&mut written, "127.0.0.1:4000", false);
+        let line = std::str::from_utf8(&written).unwrap();
+        assert_eq!(line, "{\"Error\":\"Server is full\"}\n");
+    }
+
+    #[test]
+    fn refused_tls_connections_are_sent_nothing() {
+        let mut written = Vec::new();
+        refuse_connection(&mut written, "127.0.0.1:4000", true);
+        assert!(written.is_empty());
+    }
+
+    #[test]
+    fn legacy_versions_keep_the_unit_acknowledgement() {
+        let encode = |version| {
+            Protocol::Binary {
+                version,
+                tagged: false,
+                features: LEGACY_FEATURES,
+            }
+            .encode_response(0, &Response::SubmitResults)
+            .unwrap()
+        };
+        let mut v0 = vec![0, 0, 0, 15];
+        v0.extend_from_slice(b"\"SubmitResults\"");
+        assert_eq!(encode(0), v0);
+        // Snappy: the uncompressed length, then a single literal
+        let mut v1 = vec![0, 0, 0, 17, 0x0f, 0x38];
+        v1.extend_from_slice(b"\"SubmitResults\"");
+        assert_eq!(encode(1), v1);
+    }
+
+    fn handshake(protocol_version: u32, features: Option<u64>) -> Vec<u8> {
+        let mut input = Vec::new();
+        input.write_u32::<BigEndian>(protocol_version).unwrap();
+        if let Some(features) = features {
+            input.write_u64::<BigEndian>(features).unwrap();
+        }
+        input
+    }
+
+    fn negotiated_features(events: &[ClientEvent]) -> Vec<u64> {
+        events
+            .iter()
+            .filter_map(|event| match event.payload {
+                ClientEventPayload::SetFeatures(features) => Some(features),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unknown_features_are_masked_out() {
+        let wanted = FEATURE_PACKED_RESULTS | 1 << 40 | 1 << 63;
+        let (_, events, output) = run_input(handshake(2 | FEATURES_FLAG, Some(wanted)));
+        assert_eq!(negotiated_features(&events), [FEATURE_PACKED_RESULTS]);
+        assert_eq!(output, FEATURE_PACKED_RESULTS.to_be_bytes());
+    }
+
+    #[test]
+    fn no_features_in_common_are_still_answered() {
+        let (_, events, output) = run_input(handshake(3 | FEATURES_FLAG, Some(1 << 50)));
+        assert_eq!(negotiated_features(&events), [0]);
+        assert_eq!(output, 0u64.to_be_bytes());
+    }
+
+    #[test]
+    fn the_legacy_handshake_gets_no_features_back() {
+        let (_, events, output) = run_input(handshake(2, None));
+        assert!(negotiated_features(&events).is_empty());
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn legacy_versions_cannot_negotiate_features() {
+        for version in [0, 1] {
+            let input = handshake(version | FEATURES_FLAG, Some(SUPPORTED_FEATURES));
+            let (result, events, output) = run_input(input);
+            assert!(
+                matches!(&result, Err(ClientError::Protocol(m)) if m.contains("version 2 or later")),
+                "version {version}: {result:?}"
+            );
+            assert!(negotiated_features(&events).is_empty());
+            assert!(output.is_empty());
+        }
+    }
+
+    fn submitted_results(events: Vec<ClientEvent>) -> Vec<protocol::Result> {
+        let mut requests: Vec<_> = events
+            .into_iter()
+            .filter_map(|event| match event.payload {
+                ClientEventPayload::Request(_, request) => Some(request),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(requests.len(), 1, "{requests:?}");
+        match requests.pop() {
+            Some(Request::SubmitResults(results)) => results,
+            other => panic!("Expected SubmitResults, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn version_2_results_keep_their_original_layout() {
+        let body = include_bytes!("../test-data/postcard/submit_results_request.postcard");
+        let compressed = Encoder::new().compress_vec(body).unwrap();
+        let mut input = handshake(2, None);
+        input
+            .write_u32::<BigEndian>(compressed.len() as u32)
+            .unwrap();
+        input.extend_from_slice(&compressed);
+        let (_, events, _) = run_input(input);
+        let results = submitted_results(events);
+        assert_eq!(results.len(), 2);
+        assert!(results[0].hit && !results[1].hit);
+        let color = results[0].color.unwrap();
+        assert_eq!((color.x, color.y, color.z), (1.0, 0.5, 0.0));
+        assert!(results[1].color.is_none());
+        for result in &results {
+            assert!(result.depth.is_none() && result.normal.is_none());
+            assert_eq!(result.ray_depth, 0);
+        }
+    }
+
+    #[test]
+    fn version_2_results_carry_depths_once_negotiated() {
+        let result = protocol::Result {
+            hit: true,
+            color: None,
+            depth: Some(12.5),
+            normal: Some(Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            }),
+            ray_depth: 2,
+        };
+        let mut input = handshake(2 | FEATURES_FLAG, Some(FEATURE_EXTENDED_FIELDS));
+        write_frame(&mut input, 2, &Request::SubmitResults(vec![result]));
+        let (_, events, _) = run_input(input);
+        let results = submitted_results(events);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].depth, Some(12.5));
+        assert_eq!(results[0].normal.map(|normal| normal.z), Some(-1.0));
+        assert_eq!(results[0].ray_depth, 2);
+    }
+
+    #[test]
+    fn negotiated_version_2_results_end_with_the_ray_depth() {
+        let result = protocol::Result {
+            hit: true,
+            color: None,
+            depth: None,
+            normal: None,
+            ray_depth: 3,
+        };
+        let request = Request::SubmitResults(vec![result]);
+        // Variant, length, hit, no color, depth or normal, then the ray depth
+        assert_eq!(
+            postcard::to_allocvec(&request).unwrap(),
+            [1, 1, 1, 0, 0, 0, 3]
+        );
+        let mut input = handshake(2 | FEATURES_FLAG, Some(FEATURE_EXTENDED_FIELDS));
+        write_frame(&mut input, 2, &request);
+        let (_, events, _) = run_input(input);
+        assert_eq!(submitted_results(events)[0].ray_depth, 3);
+    }
+
+    // `Response` as clients written before the scene's lighting and BVH
+    // fields decode it
+    #[derive(serde::Deserialize)]
+    enum OriginalResponse {
+        ReserveRays(Vec<Ray>, OriginalScene),
+    }
+
+    #[derive(serde::Deserialize)]
+    struct OriginalScene {
+        frame: u64,
+        spheres: Vec<OriginalSphere>,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct OriginalSphere {
+        center: Vec3,
+        radius: f32,
+    }
+
+    #[test]
+    fn version_2_scenes_keep_their_original_layout() {
+        let vec3 = |x, y, z| Vec3 { x, y, z };
+        let response = Response::ReserveRays(
+            Arc::new(vec![Ray {
+                origin: vec3(0.0, 0.0, -350.0),
+                direction: vec3(0.0, 0.6, 0.8),
+            }]),
+            Arc::new(Scene {
+                frame: 7,
+                spheres: vec![Sphere {
+                    center: vec3(1.0, 2.0, 3.0),
+                    radius: 4.0,
+                    emissive: vec3(1.0, 0.5, 0.0),
+                }],
+                ambient: vec3(0.25, 0.25, 0.25),
+                ..Scene::default()
+            }),
+        );
+        let body = |features| {
+            let frame = Protocol::Binary {
+                version: 2,
+                tagged: false,
+                features,
+            }
+            .encode_response(0, &response)
+            .unwrap();
+            Decoder::new().decompress_vec(&frame[4..]).unwrap()
+        };
+
+        let legacy = body(LEGACY_FEATURES);
+        let fixture = include_bytes!("../test-data/postcard/reserve_rays_response.postcard");
+        assert_eq!(legacy, fixture);
+        let (OriginalResponse::ReserveRays(rays, scene), rest) =
+            postcard::take_from_bytes::<OriginalResponse>(&legacy).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!((rays[0].origin.z, rays[0].direction.y), (-350.0, 0.6));
+        assert_eq!(scene.frame, 7);
+        assert_eq!(
+            (scene.spheres[0].center.x, scene.spheres[0].radius),
+            (1.0, 4.0)
+        );
+
+        // Clients that negotiate get the lighting as well
+        match postcard::from_bytes::<Response>(&body(FEATURE_EXTENDED_FIELDS)).unwrap() {
+            Response::ReserveRays(_, scene) => {
+                assert_eq!(scene.ambient.x, 0.25);
+                assert_eq!(scene.spheres[0].emissive.y, 0.5);
+            }
+            other => panic!("Unexpected response {other:?}"),
+        }
+    }
+
+    #[test]
+    fn oversized_frames_are_refused_before_reading_them() {
+        let mut input = handshake(4, None);
+        input.write_u32::<BigEndian>(u32::MAX).unwrap();
+        let (result, events, output) = run_input(input);
+        assert!(
+            matches!(&result, Err(ClientError::Protocol(m)) if m.contains("exceeds the limit")),
+            "{result:?}"
+        );
+        assert!(events
+            .iter()
+            .all(|event| !matches!(event.payload, ClientEventPayload::Request(..))));
+        let response = read_frame(&mut output.as_slice(), 4);
+        assert!(
+            matches!(&response, Response::Error(m) if m == "Frame too large"),
+            "{response:?}"
+        );
+    }
+
+    #[test]
+    fn io_errors_are_sorted_by_kind() {
+        let from_kind = |kind| ClientError::from(io::Error::new(kind, "test"));
+        for kind in [io::ErrorKind::TimedOut, io::ErrorKind::WouldBlock] {
+            assert!(matches!(from_kind(kind), ClientError::Timeout), "{kind:?}");
+        }
+        for kind in [
+            io::ErrorKind::UnexpectedEof,
+            io::ErrorKind::ConnectionReset,
+            io::ErrorKind::ConnectionAborted,
+            io::ErrorKind::BrokenPipe,
+        ] {
+            assert!(
+                matches!(from_kind(kind), ClientError::Disconnected),
+                "{kind:?}"
+            );
+        }
+        assert!(matches!(
+            from_kind(io::ErrorKind::InvalidData),
+            ClientError::Protocol(m) if m == "test"
+        ));
+        let error = from_kind(io::ErrorKind::PermissionDenied);
+        assert!(
+            matches!(&error, ClientError::Io(e) if e.kind() == io::ErrorKind::PermissionDenied)
+        );
+        assert_eq!(error.to_string(), "I/O error: test");
+        assert!(std::error::Error::source(&error).is_some());
+    }
+
+    #[test]
+    fn decoding_errors_are_protocol_errors() {
+        let json = serde_json::from_slice::<Request>(b"{").unwrap_err();
+        let message = json.to_string();
+        let error = ClientError::from(json);
+        assert!(matches!(&error, ClientError::Protocol(m) if *m == message));
+        assert_eq!(error.to_string(), format!("Protocol error: {message}"));
+
+        let postcard = postcard::from_bytes::<Request>(&[0xff]).unwrap_err();
+        let message = postcard.to_string();
+        assert!(matches!(ClientError::from(postcard), ClientError::Protocol(m) if m == message));
+    }
+
+    #[test]
+    fn client_errors_survive_anyhow() {
+        let error = anyhow::Error::from(ClientError::Timeout);
+        assert!(matches!(error.downcast_ref(), Some(ClientError::Timeout)));
+        assert_eq!(error.to_string(), "Timed out");
+        assert_eq!(ClientError::Disconnected.to_string(), "Disconnected");
+        assert_eq!(
+            ClientError::Serialize("too big".into()).to_string(),
+            "Failed to encode response: too big"
+        );
+    }
+
+    #[test]
+    fn request_frame_sizes_are_recorded_by_type() {
+        let set_name = cbor_frame(&Request::SetName("Alice".into()));
+        let debug_info = cbor_frame(&Request::SubmitDebugInfo("x".repeat(5000)));
+        let reserve_rays = cbor_frame(&Request::ReserveRays);
+        let mut input = 4u32.to_be_bytes().to_vec();
+        for frame in [&set_name, &debug_info, &reserve_rays, &reserve_rays] {
+            input.extend_from_slice(frame);
+        }
+
+        let recorder = stats::prometheus_recorder();
+        let metrics = recorder.handle();
+        let (_, events, _) = metrics::with_local_recorder(&recorder, || run_input(input));
+        let requests = events
+            .iter()
+            .filter(|event| matches!(event.payload, ClientEventPayload::Request(..)))
+            .count();
+        assert_eq!(requests, 4);
+
+        let rendered = metrics.render();
+        let has = |line: &str| rendered.lines().any(|l| l == line);
+        // Sizes don't count the 4 byte length prefix
+        let set_name_size = set_name.len() - 4;
+        assert!(has(
+            "workshop_request_frame_bytes_bucket{request=\"SetName\",le=\"256\"} 1"
+        ));
+        assert!(has(&format!(
+            "workshop_request_frame_bytes_sum{{request=\"SetName\"}} {set_name_size}"
+        )));
+        assert!(has(
+            "workshop_request_frame_bytes_bucket{request=\"SubmitDebugInfo\",le=\"4096\"} 0"
+        ));
+        assert!(has(
+            "workshop_request_frame_bytes_bucket{request=\"SubmitDebugInfo\",le=\"65536\"} 1"
+        ));
+        assert!(has(
+            "workshop_request_frame_bytes_count{request=\"ReserveRays\"} 2"
+        ));
+    }
+
+    #[test]
+    fn response_frame_sizes_are_recorded_by_type() {
+        let protocol = Protocol::Binary {
+            version: 4,
+            tagged: true,
+            features: LEGACY_FEATURES,
+        };
+        let recorder = stats::prometheus_recorder();
+        let metrics = recorder.handle();
+        let frame = metrics::with_local_recorder(&recorder, || {
+            protocol
+                .encode_response(7, &Response::SubmitDebugInfoAck)
+                .unwrap()
+        });
+        // Without the length and request ID
+        let size = frame.len() - 8;
+        let rendered = metrics.render();
+        assert!(rendered.lines().any(|line| line
+            == format!(
+                "workshop_response_frame_bytes_sum{{response=\"SubmitDebugInfoAck\"}} {size}"
+            )));
+        assert!(rendered.lines().any(|line| {
+            line
+            == "workshop_response_frame_bytes_bucket{response=\"SubmitDebugInfoAck\",le=\"256\"} 1"
+        }));
+    }
 }
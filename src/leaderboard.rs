@@ -0,0 +1,167 @@
+use std::{
+    cmp::Reverse,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils::FileWriter;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LeaderboardEntry {
+    pub name: String,
+    pub total_tiles: u64,
+    pub best_avg_time: f64,
+}
+
+pub fn load(path: &Path) -> Vec<LeaderboardEntry> {
+    let mut entries: Vec<LeaderboardEntry> = match fs::read(path) {
+        Ok(data) => match serde_json::from_slice(&data) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::warn!(
+                    "Ignoring corrupt leaderboard file {}: {}",
+                    path.display(),
+                    e
+                );
+                Vec::new()
+            }
+        },
+        Err(e) => {
+            log::warn!("Could not read leaderboard file {}: {}", path.display(), e);
+            Vec::new()
+        }
+    };
+    entries.sort_by_key(|entry| Reverse(entry.total_tiles));
+    entries
+}
+
+pub struct Leaderboard {
+    path: PathBuf,
+    entries: Arc<RwLock<Vec<LeaderboardEntry>>>,
+}
+
+impl Leaderboard {
+    pub fn new(path: PathBuf, entries: Arc<RwLock<Vec<LeaderboardEntry>>>) -> Self {
+        Self { path, entries }
+    }
+    pub fn entries(&self) -> Vec<LeaderboardEntry> {
+        self.entries.read().unwrap().clone()
+    }
+    pub fn restore(&self, entries: Vec<LeaderboardEntry>) {
+        *self.entries.write().unwrap() = entries;
+    }
+    /// Returns the client's 1-based rank after recording the tile.
+    pub fn record_tile(&self, name: &str, avg_time: f64) -> u32 {
+        let mut entries = self.entries.write().unwrap();
+        let mut idx = if let Some(idx) = entries.iter().position(|entry| entry.name == name) {
+            let entry = &mut entries[idx];
+            entry.total_tiles += 1;
+            entry.best_avg_time = entry.best_avg_time.min(avg_time);
+            idx
+        } else {
+            entries.push(LeaderboardEntry {
+                name: name.into(),
+                total_tiles: 1,
+                best_avg_time: avg_time,
+            });
+            entries.len() - 1
+        };
+        // Only one entry changed, so bubble it up to keep the list sorted
+        while idx > 0 && entries[idx - 1].total_tiles < entries[idx].total_tiles {
+            entries.swap(idx - 1, idx);
+            idx -= 1;
+        }
+        idx as u32 + 1
+    }
+    pub fn rank(&self, name: &str) -> Option<u32> {
+        self.find(name).map(|(rank, _)| rank)
+    }
+    pub fn find(&self, name: &str) -> Option<(u32, LeaderboardEntry)> {
+        let entries = self.entries.read().unwrap();
+        let idx = entries.iter().position(|entry| entry.name == name)?;
+        Some((idx as u32 + 1, entries[idx].clone()))
+    }
+    pub fn top(&self, limit: usize) -> Vec<LeaderboardEntry> {
+        self.entries
+            .read()
+            .unwrap()
+            .iter()
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+    pub fn save(&self, writer: &FileWriter) {
+        let data = serde_json::to_vec(&*self.entries.read().unwrap()).unwrap();
+        writer.write(self.path.clone(), data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{name}-{}", std::process::id()))
+    }
+
+    fn names(entries: &[LeaderboardEntry]) -> Vec<(&str, u64)> {
+        entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.total_tiles))
+            .collect()
+    }
+
+    #[test]
+    fn entries_persist_across_restarts() {
+        let path = temp_path("leaderboard-persist.json");
+        fs::write(
+            &path,
+            r#"[
+                {"name": "Bob", "total_tiles": 2, "best_avg_time": 1.5},
+                {"name": "Alice", "total_tiles": 5, "best_avg_time": 0.5}
+            ]"#,
+        )
+        .unwrap();
+        let entries = load(&path);
+        assert_eq!(names(&entries), [("Alice", 5), ("Bob", 2)]);
+
+        let leaderboard = Leaderboard::new(path.clone(), Arc::new(RwLock::new(entries)));
+        assert_eq!(leaderboard.record_tile("Carol", 2.0), 3);
+        assert_eq!(leaderboard.record_tile("Bob", 1.0), 2);
+        let writer = FileWriter::spawn();
+        leaderboard.save(&writer);
+        drop(writer);
+
+        let reloaded = load(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(names(&reloaded), [("Alice", 5), ("Bob", 3), ("Carol", 1)]);
+        assert_eq!(reloaded[1].best_avg_time, 1.0);
+    }
+
+    #[test]
+    fn missing_or_corrupt_files_start_empty() {
+        assert!(load(&temp_path("leaderboard-missing.json")).is_empty());
+        let path = temp_path("leaderboard-corrupt.json");
+        fs::write(&path, "not json").unwrap();
+        assert!(load(&path).is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn ranks_move_up_past_fewer_tiles() {
+        let leaderboard = Leaderboard::new(temp_path("leaderboard-unsaved.json"), Arc::default());
+        assert_eq!(leaderboard.record_tile("Alice", 1.0), 1);
+        assert_eq!(leaderboard.record_tile("Bob", 1.0), 2);
+        assert_eq!(leaderboard.record_tile("Bob", 1.0), 1);
+        assert_eq!(leaderboard.rank("Alice"), Some(2));
+        // A renamed client starts a new entry, and the old one keeps its tiles
+        assert_eq!(leaderboard.record_tile("Robert", 1.0), 3);
+        assert_eq!(
+            names(&leaderboard.top(10)),
+            [("Bob", 2), ("Alice", 1), ("Robert", 1)]
+        );
+    }
+}
@@ -1,28 +1,60 @@
 use std::{
-    fs,
-    net::{SocketAddr, TcpListener},
-    path::PathBuf,
-    sync::{atomic::AtomicBool, mpsc, Arc},
+    collections::VecDeque,
+    env, fs, io,
+    net::{SocketAddr, TcpListener, TcpStream},
+    os::unix::net::UnixListener,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, RwLock,
+    },
     thread,
+    time::Duration,
 };
 
+use anyhow::Context;
 use client_id::ClientId;
-use log::error;
-use ordered_float::NotNan;
-use protocol::{Request, Response};
-use serde::Deserialize;
+use log::{error, info, warn};
+use protocol::{Request, Response, Vec3};
+use serde::{Deserialize, Serialize};
 use signal_hook::{consts::TERM_SIGNALS, flag};
 use structopt::StructOpt;
 
-use crate::{client_handler::client_connected, output::output_thread, server_state::server_thread};
+use crate::{
+    animation::AnimationScript,
+    ban_list::BanList,
+    client_handler::{
+        client_connected, refuse_connection, unix_client_connected, ClientError, ConnectionLimit,
+        HandlerConfig,
+    },
+    leaderboard::Leaderboard,
+    name_filter::NameFilter,
+    output::{output_thread, output_thread_stub, EncoderKind, OutputConfig, RecordFormat},
+    server_state::{
+        server_thread, CameraOrbit, OutputChannel, Projection, ProjectionKind, PublicServerState,
+        ServerConfig,
+    },
+    ws_handler::{refuse_ws_connection, ws_connected},
+};
 
+mod animation;
+mod ban_list;
+mod benchmark;
+mod bvh;
 mod client_handler;
 mod client_id;
 mod http;
+mod leaderboard;
+mod name_filter;
 mod output;
 mod protocol;
+mod scene_watcher;
 mod server_state;
+mod stats;
+mod tls;
 mod utils;
+mod watchdog;
+mod ws_handler;
 
 const TILE_SIZE: usize = 128;
 const TILES_X: usize = 8;
@@ -36,39 +68,379 @@ pub struct ClientEvent {
 
 #[derive(Debug)]
 pub enum ClientEventPayload {
-    Connected(mpsc::SyncSender<ClientCommand>),
+    Connected(crossbeam_channel::Sender<ClientCommand>, String),
     Disconnected,
-    Request(Request),
+    // The request ID is chosen by the client and echoed back in the response
+    Request(u32, Request),
+    // Sent by the admin API rather than the client itself; replies with
+    // whether a client with this ID was connected. With a duration, the
+    // client's IP is also refused for that long.
+    Kick(Option<Duration>, mpsc::SyncSender<bool>),
+    // The client showed signs of life without needing a response, with the
+    // connection's round-trip time where the transport reports one
+    Heartbeat(Option<Duration>),
+    // The protocol features agreed in the handshake, see `protocol::SUPPORTED_FEATURES`
+    SetFeatures(u64),
 }
 
 pub enum ClientCommand {
-    Response(Response),
+    Response(u32, Response),
+    Disconnect(String),
+}
+
+pub(crate) enum AdminCommand {
+    ReloadScene(PathBuf, mpsc::SyncSender<anyhow::Result<()>>),
+    // Swapped in at the next frame boundary
+    UpdateScene(Vec<SceneElement>),
+    Broadcast(String),
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 struct SceneElement {
     x: f32,
     y: f32,
     z: f32,
     r: f32,
+    // Optional emissive color columns
+    #[serde(default)]
+    ex: f32,
+    #[serde(default)]
+    ey: f32,
+    #[serde(default)]
+    ez: f32,
+}
+
+impl SceneElement {
+    fn validate(&self) -> anyhow::Result<()> {
+        let values = [self.x, self.y, self.z, self.r, self.ex, self.ey, self.ez];
+        if !values.iter().all(|value| value.is_finite()) {
+            return Err(anyhow::anyhow!("Every value must be a finite number"));
+        }
+        if self.r <= 0.0 {
+            return Err(anyhow::anyhow!(
+                "Radius must be greater than zero, got {}",
+                self.r
+            ));
+        }
+        Ok(())
+    }
+    fn emissive(&self) -> Vec3 {
+        Vec3 {
+            x: self.ex,
+            y: self.ey,
+            z: self.ez,
+        }
+    }
+}
+
+// Parses an "x,y,z" triple, or "r,g,b" for colors
+fn parse_vec3(s: &str) -> anyhow::Result<Vec3> {
+    let parts = s
+        .split(',')
+        .map(|part| part.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid vector: {s}"))?;
+    match parts[..] {
+        [x, y, z] => Ok(Vec3 { x, y, z }),
+        _ => Err(anyhow::anyhow!("Expected three components: {s}")),
+    }
+}
+
+// Clients misbehaving or going away is routine, so only problems on our
+// side are logged as errors
+fn log_client_error(e: &anyhow::Error) {
+    match e.downcast_ref::<ClientError>() {
+        Some(ClientError::Protocol(_)) => warn!("{:#}", e),
+        Some(ClientError::Timeout | ClientError::Disconnected) => info!("{:#}", e),
+        Some(ClientError::Io(_) | ClientError::Serialize(_)) | None => error!("{:?}", e),
+    }
+}
+
+fn peer_name(stream: &TcpStream) -> String {
+    stream
+        .peer_addr()
+        .map_or_else(|_| "unknown address".into(), |addr| addr.to_string())
+}
+
+fn parse_orbit_radius(s: &str) -> anyhow::Result<f32> {
+    let radius = s
+        .parse::<f32>()
+        .with_context(|| format!("Invalid radius: {s}"))?;
+    if radius > 0.0 {
+        Ok(radius)
+    } else {
+        Err(anyhow::anyhow!(
+            "Orbit radius must be greater than zero: {s}"
+        ))
+    }
+}
+
+fn parse_fov(s: &str) -> anyhow::Result<f32> {
+    let fov = s
+        .parse::<f32>()
+        .with_context(|| format!("Invalid angle: {s}"))?;
+    if fov > 0.0 && fov < 180.0 {
+        Ok(fov)
+    } else {
+        Err(anyhow::anyhow!(
+            "Field of view must be between 0 and 180 degrees: {s}"
+        ))
+    }
+}
+
+// Scenes are CSV unless the file ends in .json
+fn load_scene(path: &Path) -> anyhow::Result<Vec<SceneElement>> {
+    let is_json = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("json"));
+    let scene_elements = if is_json {
+        load_json_scene(path)?
+    } else {
+        load_csv_scene(path)?
+    };
+    // Nothing breaks, every ray just misses, but it's rarely intended
+    if scene_elements.is_empty() {
+        warn!(
+            "Scene {} has no spheres, so every frame will be blank",
+            path.display()
+        );
+    }
+    Ok(scene_elements)
+}
+
+// An array of objects with the same fields as the CSV columns. Unknown
+// fields are ignored, so files can carry extra data ahead of the server.
+fn load_json_scene(path: &Path) -> anyhow::Result<Vec<SceneElement>> {
+    let file =
+        fs::File::open(path).with_context(|| format!("Failed to open scene {}", path.display()))?;
+    let scene_elements: Vec<SceneElement> = serde_json::from_reader(io::BufReader::new(file))
+        .with_context(|| format!("Failed to parse scene {}", path.display()))?;
+    for (index, elem) in scene_elements.iter().enumerate() {
+        elem.validate()
+            .with_context(|| format!("Invalid element {index} in {}", path.display()))?;
+    }
+    Ok(scene_elements)
+}
+
+fn load_csv_scene(path: &Path) -> anyhow::Result<Vec<SceneElement>> {
+    let mut scene_reader = csv::Reader::from_path(path)
+        .with_context(|| format!("Failed to open scene {}", path.display()))?;
+    let headers = scene_reader
+        .headers()
+        .with_context(|| format!("Failed to read the header of {}", path.display()))?
+        .clone();
+    let mut scene_elements = Vec::new();
+    // Rows count from 1, not including the header
+    for (row, record) in (1..).zip(scene_reader.records()) {
+        let record =
+            record.with_context(|| format!("Malformed row {row} in {}", path.display()))?;
+        let elem = record
+            .deserialize::<SceneElement>(Some(&headers))
+            .map_err(anyhow::Error::from)
+            .and_then(|elem| elem.validate().map(|()| elem))
+            .with_context(|| {
+                format!(
+                    "Invalid row {row} (line {}) in {}: {}",
+                    record.position().map_or(0, |position| position.line()),
+                    path.display(),
+                    record.iter().collect::<Vec<_>>().join(",")
+                )
+            })?;
+        scene_elements.push(elem);
+    }
+    Ok(scene_elements)
 }
 
 #[derive(StructOpt)]
 struct Opt {
     scene_filename: PathBuf,
-    #[structopt(short, long, default_value = "0.0.0.0:1234")]
-    addr: SocketAddr,
+    /// Address to accept clients on; may be repeated, e.g. to also listen on [::]:1234
+    #[structopt(short, long, default_value = "0.0.0.0:1234", number_of_values = 1)]
+    addr: Vec<SocketAddr>,
+    /// Also accept clients over WebSocket on this address
+    #[structopt(long)]
+    ws_addr: Option<SocketAddr>,
+    /// Also accept clients on a Unix domain socket at this path
+    #[structopt(long)]
+    unix_socket: Option<PathBuf>,
+    /// PEM certificate chain used to serve clients over TLS
+    #[structopt(long, requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key matching --tls-cert
+    #[structopt(long, requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+    /// Directory served over HTTP, containing the live video and recordings
+    #[structopt(long, default_value = "static")]
+    static_dir: PathBuf,
+    /// Only produce the live stream, without recording to disk
+    #[structopt(long)]
+    no_record: bool,
+    /// Container for the recording: ts or mp4
+    #[structopt(long, default_value = "ts", possible_values = &["ts", "mp4"])]
+    record_format: RecordFormat,
+    /// Encode video on the GPU: vaapi (the default when no value is given) or nvenc.
+    /// Falls back to x264 if the encoder can't be created.
+    #[structopt(long, possible_values = &["vaapi", "nvenc"])]
+    hw_encode: Option<Option<EncoderKind>>,
+    /// Also record a VP9 WebM file next to the H.264 recording
+    #[structopt(long)]
+    webm_output: bool,
+    /// Only put every Nth completed frame in the WebM recording, since VP9 is slow to encode
+    #[structopt(long, default_value = "1")]
+    webm_frame_interval: u64,
+    /// Also stream a half resolution copy of the live video to livevideo-low
+    #[structopt(long)]
+    preview_stream: bool,
+    /// Don't copy the frame into the live stream again until it has changed
+    #[structopt(long)]
+    skip_unchanged: bool,
+    /// Treat client colors as linear and convert them to sRGB for output
+    #[structopt(long)]
+    srgb: bool,
+    /// Turn away new clients while this many are connected. Each connection has
+    /// a worker thread of its own, and this many are started.
+    #[structopt(long, default_value = "256")]
+    max_clients: usize,
+    /// Largest request frame in bytes accepted from binary protocol clients
+    #[structopt(long, default_value = "4194304")]
+    max_frame_size: usize,
+    /// Build BVHs over the spheres each frame and send them with the scene
+    #[structopt(long)]
+    send_bvh: bool,
+    /// Abort if the server loop makes no progress for this many seconds (0 to disable).
+    /// The loop wakes every 100ms even when idle, so this only catches it stuck
+    /// handling something.
+    #[structopt(long, default_value = "10")]
+    watchdog_timeout: f64,
+    /// Ambient light added to every scene, as r,g,b
+    #[structopt(long, default_value = "0,0,0", parse(try_from_str = parse_vec3))]
+    ambient_light: Vec3,
+    /// Where the server's rays start, as x,y,z
+    #[structopt(long, default_value = "0,0,-350", parse(try_from_str = parse_vec3))]
+    camera_origin: Vec3,
+    /// Horizontal field of view of the server's rays, in degrees
+    #[structopt(long, default_value = "14.250033", parse(try_from_str = parse_fov))]
+    fov_degrees: f32,
+    /// Stop handing out tiles for new frames while this many frames are unfinished
+    #[structopt(long)]
+    max_frames_ahead: Option<u64>,
+    /// How the server's rays are cast: perspective or orthographic
+    #[structopt(long, default_value = "perspective", possible_values = &["perspective", "orthographic"])]
+    projection: ProjectionKind,
+    /// World units between neighbouring pixels with --projection orthographic
+    #[structopt(long, default_value = "0.085")]
+    ortho_scale: f32,
+    /// Circle the camera around the scene's center, looking at it with --fov-degrees
+    #[structopt(long)]
+    camera_orbit: bool,
+    /// Distance of the orbiting camera from the scene's center
+    #[structopt(long, default_value = "350", parse(try_from_str = parse_orbit_radius))]
+    camera_orbit_radius: f32,
+    /// Radians the orbiting camera turns each frame
+    #[structopt(long, default_value = "0.01")]
+    camera_orbit_speed: f32,
+    /// Height of the orbiting camera above the scene's center
+    #[structopt(long, default_value = "0")]
+    camera_orbit_height: f32,
+    /// Render every tile this many times per frame with jittered rays, and average them
+    #[structopt(long, default_value = "1")]
+    samples_per_tile: u32,
+    /// Offset every ray by a random amount within its pixel, differently each frame
+    #[structopt(long)]
+    jitter: bool,
+    /// Seed for --jitter, so that runs can be reproduced (random by default)
+    #[structopt(long, requires = "jitter")]
+    seed: Option<u64>,
+    /// Seconds a client has to return a tile before it is handed to someone else
+    #[structopt(long, default_value = "5")]
+    tile_timeout_secs: f64,
+    /// Give up on tiles still missing this many seconds after a frame starts
+    #[structopt(long)]
+    frame_deadline_secs: Option<f64>,
+    /// Probe clients that have been idle this many seconds, and drop them if
+    /// they stay silent for as long again
+    #[structopt(long)]
+    idle_timeout_secs: Option<f64>,
+    /// Which part of the client results to show: color, depth or normal
+    #[structopt(long, default_value = "color", possible_values = &["color", "depth", "normal"])]
+    channel: OutputChannel,
+    /// Skip tiles whose colors all changed by at most this much (0 always blits)
+    #[structopt(long, default_value = "0.0")]
+    diff_threshold: f32,
+    /// Number of client debug messages kept for /api/debug-log
+    #[structopt(long, default_value = "100")]
+    debug_log_size: usize,
+    /// Don't start GStreamer: frames are only kept in memory (for snapshots), and
+    /// just the dashboard metadata is written. For running without GStreamer installed.
+    #[structopt(long)]
+    no_video: bool,
+    /// Instead of accepting clients, drive the server with this many synthetic
+    /// clients and report throughput. No video is produced.
+    #[structopt(long)]
+    benchmark: Option<usize>,
+    /// Frames to complete before a --benchmark run ends
+    #[structopt(long, default_value = "300")]
+    benchmark_frames: u64,
+    /// Persist the leaderboard to this JSON file and restore it on startup
+    #[structopt(long, default_value = "leaderboard.json")]
+    leaderboard_file: PathBuf,
+    /// Write a PNG of every completed frame into this directory
+    #[structopt(long)]
+    snapshots_dir: Option<PathBuf>,
+    /// Number of PNG snapshots to keep before deleting the oldest
+    #[structopt(long, default_value = "10")]
+    snapshot_keep: u64,
+    /// JPEG quality of the image served at /api/snapshot.jpg
+    #[structopt(long, default_value = "85")]
+    snapshot_quality: i32,
+    /// Write every completed frame as raw BGRx into this directory
+    #[structopt(long)]
+    record_raw_frames: Option<PathBuf>,
+    /// Number of raw frames to keep before deleting the oldest (0 keeps them all)
+    #[structopt(long, default_value = "0")]
+    raw_keep_frames: u64,
+    /// Newline-separated words that mustn't appear in client names. Matching
+    /// names are shown as "Client #<id>" instead.
+    #[structopt(long)]
+    name_blocklist: Option<PathBuf>,
+    /// TOML file describing per-sphere animation tracks
+    #[structopt(long)]
+    animation_script: Option<PathBuf>,
+    /// Restore the frame, scene and leaderboard from this file if it exists,
+    /// and keep it updated at every frame so a crashed server can pick up again
+    #[structopt(long)]
+    resume_from: Option<PathBuf>,
+    /// Give every client the same 128x128 tiles, instead of sizing tiles to each client's speed
+    #[structopt(long)]
+    uniform_tiles: bool,
+    /// Reload the scene file whenever it changes on disk
+    #[structopt(long)]
+    watch_scene: bool,
+    /// Shared secret for the admin HTTP endpoints, sent in an X-Admin-Secret header
+    /// (DELETE /api/clients also takes it as a Basic auth password with any username);
+    /// they are disabled when unset
+    #[structopt(long)]
+    secret: Option<String>,
 }
 
 fn main() -> anyhow::Result<()> {
     let _ = dotenvy::dotenv();
     let _ = pretty_env_logger::try_init();
     let opt = Opt::from_args();
+    let recorder = stats::prometheus_recorder();
+    let metrics = recorder.handle();
+    metrics::set_global_recorder(recorder)?;
 
     // Wipe the live video directory before starting
-    let _ = fs::remove_dir_all("static/livevideo");
-    fs::create_dir_all("static/livevideo")?;
-    fs::create_dir_all("static/recording")?;
+    let livevideo_dir = opt.static_dir.join("livevideo");
+    let _ = fs::remove_dir_all(&livevideo_dir);
+    fs::create_dir_all(&livevideo_dir)?;
+    if opt.preview_stream {
+        let preview_dir = opt.static_dir.join("livevideo-low");
+        let _ = fs::remove_dir_all(&preview_dir);
+        fs::create_dir_all(&preview_dir)?;
+    }
+    fs::create_dir_all(opt.static_dir.join("recording"))?;
 
     // Make sure double CTRL+C and similar kills
     let term_now = Arc::new(AtomicBool::new(false));
@@ -82,27 +454,317 @@ fn main() -> anyhow::Result<()> {
         flag::register(*sig, Arc::clone(&term_now))?;
     }
 
-    let scene_reader = csv::Reader::from_path(opt.scene_filename)?;
-    let mut scene_elements = scene_reader
-        .into_deserialize()
-        .collect::<Result<Vec<SceneElement>, _>>()?;
-    scene_elements.sort_by_key(|elem| NotNan::new(elem.x).unwrap());
+    let scene_elements = load_scene(&opt.scene_filename)?;
+    let scene_path = Arc::new(RwLock::new(opt.scene_filename));
 
-    let listener = TcpListener::bind(opt.addr)?;
-    let (client_tx, client_rx) = mpsc::sync_channel(16);
+    let tls_config = match (&opt.tls_cert, &opt.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::load_config(cert, key)?),
+        _ => None,
+    };
+
+    let listeners = opt
+        .addr
+        .iter()
+        .filter(|_| opt.benchmark.is_none())
+        .map(|addr| {
+            let listener =
+                TcpListener::bind(addr).with_context(|| format!("Failed to listen on {addr}"))?;
+            info!("Listening for clients on {addr}");
+            Ok(listener)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let (client_tx, client_rx) = crossbeam_channel::bounded(16);
     let (output_tx, output_rx) = mpsc::sync_channel(16);
+    let (admin_tx, admin_rx) = mpsc::sync_channel(4);
+
+    // Keep synthetic clients off the real leaderboard
+    let leaderboard_file = if opt.benchmark.is_some() {
+        env::temp_dir().join("benchmark-leaderboard.json")
+    } else {
+        opt.leaderboard_file
+    };
+    let name_filter = opt
+        .name_blocklist
+        .as_deref()
+        .map(NameFilter::load)
+        .transpose()?;
+    let mut leaderboard_entries = leaderboard::load(&leaderboard_file);
+    if let Some(name_filter) = &name_filter {
+        name_filter.retain_allowed(&mut leaderboard_entries);
+    }
+    let shared_leaderboard = Arc::new(RwLock::new(leaderboard_entries.clone()));
+    let leaderboard = Leaderboard::new(leaderboard_file, shared_leaderboard.clone());
+
+    let output_config = OutputConfig {
+        static_dir: opt.static_dir.clone(),
+        record: !opt.no_record,
+        record_format: opt.record_format,
+        encoder: match opt.hw_encode {
+            None => EncoderKind::Software,
+            Some(None) => EncoderKind::Vaapi,
+            Some(Some(kind)) => kind,
+        },
+        preview_stream: opt.preview_stream,
+        skip_unchanged: opt.skip_unchanged,
+        samples_per_tile: opt.samples_per_tile.max(1),
+        webm_output: opt.webm_output,
+        webm_frame_interval: opt.webm_frame_interval.max(1),
+        srgb: opt.srgb,
+        leaderboard: leaderboard_entries,
+        snapshots_dir: opt.snapshots_dir,
+        snapshot_keep: opt.snapshot_keep,
+        snapshot_quality: opt.snapshot_quality,
+        raw_frames_dir: opt.record_raw_frames,
+        raw_keep_frames: opt.raw_keep_frames,
+    };
+    let latest_jpeg = Arc::new(RwLock::new(Vec::new()));
+    let public_state = Arc::new(RwLock::new(PublicServerState::default()));
+    let debug_log = Arc::new(RwLock::new(VecDeque::new()));
+    let http_state = http::HttpState {
+        server_state: public_state.clone(),
+        leaderboard: shared_leaderboard,
+        debug_log: debug_log.clone(),
+        static_dir: opt.static_dir,
+        latest_jpeg: latest_jpeg.clone(),
+        secret: opt.secret,
+        client_tx: client_tx.clone(),
+        admin_tx: admin_tx.clone(),
+        scene_path: scene_path.clone(),
+        metrics,
+    };
+    if opt.watch_scene {
+        scene_watcher::spawn(scene_path, admin_tx)?;
+    }
+    let frames_done = if opt.benchmark.is_some() {
+        Some(benchmark::spawn_output_sink(output_rx))
+    } else {
+        let output_term_now = term_now.clone();
+        let output = if opt.no_video {
+            output_thread_stub
+        } else {
+            output_thread
+        };
+        thread::spawn(move || {
+            output(output_rx, output_term_now, latest_jpeg, output_config).unwrap()
+        });
+        None
+    };
+    let heartbeat = Arc::new(AtomicU64::new(0));
+    if opt.watchdog_timeout > 0.0 {
+        watchdog::spawn(
+            heartbeat.clone(),
+            Duration::from_secs_f64(opt.watchdog_timeout),
+        );
+    }
+    let ban_list = BanList::default();
+    let server_config = ServerConfig {
+        heartbeat,
+        ban_list: ban_list.clone(),
+        send_bvh: opt.send_bvh,
+        tile_timeout: Duration::from_secs_f64(opt.tile_timeout_secs),
+        frame_deadline: opt.frame_deadline_secs.map(Duration::from_secs_f64),
+        idle_timeout: opt.idle_timeout_secs.map(Duration::from_secs_f64),
+        resume_from: opt.resume_from,
+        uniform_tiles: opt.uniform_tiles,
+        ambient_light: opt.ambient_light,
+        camera_origin: opt.camera_origin,
+        fov_degrees: opt.fov_degrees,
+        max_frames_ahead: opt.max_frames_ahead.map(|frames| frames.max(1)),
+        projection: match opt.projection {
+            ProjectionKind::Perspective => Projection::Perspective,
+            ProjectionKind::Orthographic => Projection::Orthographic(opt.ortho_scale),
+        },
+        camera_orbit: opt.camera_orbit.then_some(CameraOrbit {
+            radius: opt.camera_orbit_radius,
+            height: opt.camera_orbit_height,
+            radians_per_frame: opt.camera_orbit_speed,
+        }),
+        samples_per_tile: opt.samples_per_tile.max(1),
+        jitter_seed: opt.jitter.then(|| opt.seed.unwrap_or_else(rand::random)),
+        channel: opt.channel,
+        diff_threshold: opt.diff_threshold,
+        debug_log_size: opt.debug_log_size,
+        name_filter,
+        animation_script: opt
+            .animation_script
+            .as_deref()
+            .map(AnimationScript::load)
+            .transpose()?,
+    };
+    if let Some(seed) = server_config.jitter_seed {
+        info!("Jittering rays with seed {seed}");
+    }
+    // Client sockets only time out once the server would have given up anyway
+    let max_silence = server_config.max_client_silence();
+    thread::spawn(move || {
+        server_thread(
+            client_rx,
+            admin_rx,
+            output_tx,
+            scene_elements,
+            public_state,
+            leaderboard,
+            debug_log,
+            server_config,
+        )
+    });
+    if let (Some(n_clients), Some(frames)) = (opt.benchmark, frames_done) {
+        return benchmark::run(n_clients, opt.benchmark_frames, frames, client_tx);
+    }
+    thread::spawn(move || http::run_server(http_state));
+
+    // Each connection occupies a worker for its whole lifetime, so there's one
+    // for every connection --max-clients lets in. Past that, connections are
+    // refused rather than queued behind clients that may never leave.
+    let pool = Arc::new(
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(opt.max_clients)
+            .thread_name(|i| format!("client-worker-{i}"))
+            .build()?,
+    );
+    let handler_config = HandlerConfig {
+        max_frame_size: opt.max_frame_size,
+        max_silence,
+        writers: Arc::new(
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(opt.max_clients)
+                .thread_name(|i| format!("client-writer-{i}"))
+                .build()?,
+        ),
+    };
 
-    thread::spawn(move || output_thread(output_rx, term_now).unwrap());
-    thread::spawn(move || server_thread(client_rx, output_tx, scene_elements));
-    thread::spawn(move || http::run_server());
+    let limit = ConnectionLimit::new(Some(opt.max_clients));
+    if let Some(ws_addr) = opt.ws_addr {
+        let ws_listener =
+            TcpListener::bind(ws_addr).with_context(|| format!("Failed to listen on {ws_addr}"))?;
+        let client_tx = client_tx.clone();
+        let pool = pool.clone();
+        let ban_list = ban_list.clone();
+        let limit = limit.clone();
+        thread::spawn(move || {
+            for stream in ws_listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        error!("Failed to accept a WebSocket client: {e}");
+                        continue;
+                    }
+                };
+                let slot = match limit.try_acquire() {
+                    Some(slot) => slot,
+                    None => {
+                        let _ = stream.set_nonblocking(true);
+                        refuse_ws_connection(&stream, &peer_name(&stream));
+                        continue;
+                    }
+                };
+                let client_tx = client_tx.clone();
+                let ban_list = ban_list.clone();
+                pool.spawn(move || {
+                    if let Err(e) = ws_connected(stream, slot, client_tx, &ban_list) {
+                        log_client_error(&e);
+                    }
+                });
+            }
+        });
+    }
 
-    for stream in listener.incoming() {
+    if let Some(unix_socket) = opt.unix_socket {
+        // Remove a stale socket left behind by a previous run
+        let _ = fs::remove_file(&unix_socket);
+        let unix_listener = UnixListener::bind(&unix_socket)
+            .with_context(|| format!("Failed to listen on {}", unix_socket.display()))?;
         let client_tx = client_tx.clone();
+        let pool = pool.clone();
+        let limit = limit.clone();
+        let handler_config = handler_config.clone();
         thread::spawn(move || {
-            if let Err(e) = client_connected(stream, client_tx) {
-                error!("{:?}", e);
+            for stream in unix_listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        error!("Failed to accept a client: {e}");
+                        continue;
+                    }
+                };
+                let slot = match limit.try_acquire() {
+                    Some(slot) => slot,
+                    None => {
+                        let _ = stream.set_nonblocking(true);
+                        refuse_connection(&stream, "unix socket", false);
+                        continue;
+                    }
+                };
+                let client_tx = client_tx.clone();
+                let handler_config = handler_config.clone();
+                pool.spawn(move || {
+                    if let Err(e) = unix_client_connected(stream, slot, client_tx, &handler_config)
+                    {
+                        log_client_error(&e);
+                    }
+                });
             }
         });
+
+        // The process exits from the output thread, so clean up once shutdown starts
+        thread::spawn(move || {
+            while !term_now.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(100));
+            }
+            let _ = fs::remove_file(&unix_socket);
+        });
+    }
+
+    let accept_threads: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| {
+            let client_tx = client_tx.clone();
+            let tls_config = tls_config.clone();
+            let pool = pool.clone();
+            let ban_list = ban_list.clone();
+            let limit = limit.clone();
+            let handler_config = handler_config.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            error!("Failed to accept a client: {e}");
+                            continue;
+                        }
+                    };
+                    // Checked here rather than on the pool, so that extra
+                    // connections are turned away instead of queueing for a worker
+                    let slot = match limit.try_acquire() {
+                        Some(slot) => slot,
+                        None => {
+                            let _ = stream.set_nonblocking(true);
+                            refuse_connection(&stream, &peer_name(&stream), tls_config.is_some());
+                            continue;
+                        }
+                    };
+                    let client_tx = client_tx.clone();
+                    let tls_config = tls_config.clone();
+                    let ban_list = ban_list.clone();
+                    let handler_config = handler_config.clone();
+                    pool.spawn(move || {
+                        if let Err(e) = client_connected(
+                            stream,
+                            slot,
+                            client_tx,
+                            tls_config,
+                            &handler_config,
+                            &ban_list,
+                        ) {
+                            log_client_error(&e);
+                        }
+                    });
+                }
+            })
+        })
+        .collect();
+    for accept_thread in accept_threads {
+        accept_thread.join().unwrap();
     }
 
     Ok(())
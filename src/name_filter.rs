@@ -0,0 +1,121 @@
+use std::{fs, path::Path};
+
+use anyhow::Context;
+
+use crate::leaderboard::LeaderboardEntry;
+
+// Digits and symbols commonly standing in for letters, undone before matching
+const LEET_TABLE: [(char, char); 8] = [
+    ('0', 'o'),
+    ('1', 'i'),
+    ('3', 'e'),
+    ('4', 'a'),
+    ('5', 's'),
+    ('7', 't'),
+    ('@', 'a'),
+    ('$', 's'),
+];
+
+// Lowercases the text and replaces look-alike characters from `LEET_TABLE`
+pub fn normalize(text: &str) -> String {
+    text.chars()
+        .flat_map(char::to_lowercase)
+        .map(|c| {
+            LEET_TABLE
+                .iter()
+                .find(|&&(from, _)| from == c)
+                .map_or(c, |&(_, to)| to)
+        })
+        .collect()
+}
+
+// Words that mustn't appear in names shown on the projector
+pub struct NameFilter {
+    words: Vec<String>,
+}
+
+impl NameFilter {
+    // One word per line. Blank lines are ignored.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Reading name blocklist {}", path.display()))?;
+        Ok(Self {
+            words: text
+                .lines()
+                .map(str::trim)
+                .filter(|word| !word.is_empty())
+                .map(normalize)
+                .collect(),
+        })
+    }
+    pub fn is_blocked(&self, name: &str) -> bool {
+        let name = normalize(name);
+        self.words.iter().any(|word| name.contains(word.as_str()))
+    }
+    // Names restored from disk never went through SetName, so they're checked here
+    pub fn retain_allowed(&self, entries: &mut Vec<LeaderboardEntry>) {
+        entries.retain(|entry| {
+            let blocked = self.is_blocked(&entry.name);
+            if blocked {
+                log::info!(
+                    "Dropping blocked name {:?} from the leaderboard",
+                    entry.name
+                );
+            }
+            !blocked
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(words: &[&str]) -> NameFilter {
+        NameFilter {
+            words: words.iter().map(|word| normalize(word)).collect(),
+        }
+    }
+
+    #[test]
+    fn normalize_undoes_leetspeak() {
+        assert_eq!(normalize("H3LL0"), "hello");
+        assert_eq!(normalize("1337"), "ieet");
+        assert_eq!(normalize("$p@4m"), "spaam");
+        assert_eq!(normalize("7ru5t"), "trust");
+    }
+
+    #[test]
+    fn normalize_keeps_other_characters() {
+        assert_eq!(normalize("Ünïcödé 2 9!"), "ünïcödé 2 9!");
+        assert_eq!(normalize(""), "");
+    }
+
+    #[test]
+    fn blocks_words_inside_names() {
+        let filter = filter(&["badword"]);
+        assert!(filter.is_blocked("badword"));
+        assert!(filter.is_blocked("xxBADWORDxx"));
+        assert!(filter.is_blocked("B@dw0rd"));
+        assert!(!filter.is_blocked("bad word"));
+        assert!(!filter.is_blocked("Alice"));
+    }
+
+    #[test]
+    fn blocklist_words_are_normalized_too() {
+        assert!(filter(&["B4DW0RD"]).is_blocked("badword"));
+    }
+
+    #[test]
+    fn retain_allowed_drops_blocked_entries() {
+        let entry = |name: &str| LeaderboardEntry {
+            name: name.into(),
+            total_tiles: 1,
+            best_avg_time: 1.0,
+        };
+        let mut entries = vec![entry("Alice"), entry("b4dw0rd"), entry("Bob")];
+        filter(&["badword"]).retain_allowed(&mut entries);
+        let names: Vec<_> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["Alice", "Bob"]);
+    }
+}
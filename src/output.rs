@@ -1,34 +1,51 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     fs,
-    io::{Cursor, Write},
-    mem, process,
+    io::{self, BufWriter, Cursor, Write},
+    mem,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        mpsc, Arc, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Condvar, Mutex, RwLock,
     },
     thread,
     time::{Duration, Instant},
 };
 
-use chrono::{DateTime, NaiveDateTime, Utc};
+use byteorder::{LittleEndian, WriteBytesExt};
+use chrono::{DateTime, Utc};
 use gio::{
     traits::FileExt, Cancellable, File, FileCreateFlags, FileOutputStream, WriteOutputStream,
 };
 use gst::{
-    prelude::{Cast, GstBinExtManual, ObjectExt},
+    prelude::{Cast, GObjectExtManualGst, GstBinExtManual, GstObjectExt, ObjectExt},
     traits::ElementExt,
     Element, MessageView,
 };
+use image::{Rgb, RgbImage};
+use rayon::prelude::*;
 use serde::Serialize;
 
 use crate::{
-    client_id::ClientId, protocol::Vec3, server_state::TileAddr, TILES_X, TILES_Y, TILE_SIZE,
+    client_id::ClientId, leaderboard::LeaderboardEntry, protocol::Vec3, server_state::TileAddr,
+    utils::SyncSenderExt, TILES_X, TILES_Y, TILE_SIZE,
 };
 
 #[derive(Debug)]
 pub enum OutputEvent {
     BlitTile(BlitTileEvent),
+    // The frame missed its deadline and will not receive any more tiles
+    ForceCompleteFrame(u64),
+    // The client's tiles no longer belong to anyone on the dashboard
+    ClientDisconnected(ClientId),
+    // Base sized tiles of the frame returned so far, counting every sample
+    FrameProgress {
+        frame: u64,
+        completed: usize,
+        total: usize,
+    },
 }
 
 #[derive(Debug)]
@@ -38,14 +55,29 @@ pub struct BlitTileEvent {
     pub name: String,
     pub pixels: Vec<Vec3>,
     pub time: f64,
+    // Set on the tile that fills in the last of its frame
+    pub completes_frame: bool,
+    // The client's samples per pixel. Above 1 the tile is blended into the
+    // frame with a weight of 1 / samples instead of replacing it.
+    pub samples: u8,
 }
 
+// Upper bounds in seconds of each render time bucket, with a final bucket
+// for anything slower
+const TIME_BUCKET_BOUNDS: [f64; 5] = [0.1, 0.5, 1.0, 2.0, 5.0];
+
 #[derive(Serialize, Clone)]
 struct ClientState {
     current_count: u32,
     total_count: u32,
     average_time: f64,
     name: String,
+    time_buckets: [u32; TIME_BUCKET_BOUNDS.len() + 1],
+}
+
+struct NameTotals {
+    total_count: u32,
+    average_time: f64,
 }
 
 #[derive(Serialize, Clone)]
@@ -54,6 +86,28 @@ struct MetaState {
     clients: HashMap<ClientId, ClientState>,
     tiles_x: usize,
     tiles_y: usize,
+    forced_frames: u64,
+    #[serde(flatten)]
+    progress: MetaFrameProgress,
+    session: MetaSession,
+}
+
+// Totals since the server started, kept when clients disconnect.
+// `uptime_secs` and `tiles_per_second` are refreshed with each snapshot.
+#[derive(Serialize, Clone)]
+struct MetaSession {
+    started_at: String,
+    uptime_secs: f64,
+    total_tiles: u64,
+    tiles_per_second: f64,
+}
+
+// In base sized tiles, so the numbers don't depend on how tiles were split
+#[derive(Serialize, Clone, Default)]
+struct MetaFrameProgress {
+    current_frame: u64,
+    completed_tiles: usize,
+    outstanding_tiles: usize,
 }
 
 #[derive(Serialize, Clone)]
@@ -69,6 +123,8 @@ struct MetaBlitTile {
 enum MetaActionPayload {
     Snapshot(MetaState),
     BlitTile(MetaBlitTile),
+    ClientDisconnected(ClientId),
+    FrameProgress(MetaFrameProgress),
 }
 
 #[derive(Serialize, Clone)]
@@ -78,11 +134,190 @@ struct MetaAction {
 }
 
 struct Accumulator {
-    data: Vec<u8>,
+    // Tiles are blitted into the back buffer, which is copied to the front
+    // once a frame is complete, so the video never shows a half drawn frame
+    back: Vec<u8>,
+    front: Vec<u8>,
     frame_done: bool,
     meta_state: MetaState,
     meta_actions: Vec<MetaAction>,
     meta_filename: String,
+    name_totals: HashMap<String, NameTotals>,
+    frames_written: u64,
+    last_done_frame: u64,
+}
+
+impl Accumulator {
+    fn new(layout: FrameLayout, leaderboard: Vec<LeaderboardEntry>) -> Self {
+        Self {
+            back: vec![0x40; layout.size],
+            front: vec![0x40; layout.size],
+            frame_done: false,
+            meta_state: MetaState {
+                tiles: vec![None; TILES_X * TILES_Y],
+                clients: HashMap::new(),
+                tiles_x: TILES_X,
+                tiles_y: TILES_Y,
+                forced_frames: 0,
+                progress: MetaFrameProgress::default(),
+                session: MetaSession {
+                    started_at: Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                    uptime_secs: 0.0,
+                    total_tiles: 0,
+                    tiles_per_second: 0.0,
+                },
+            },
+            meta_actions: Vec::new(),
+            meta_filename: String::new(),
+            name_totals: leaderboard
+                .into_iter()
+                .map(|entry| {
+                    (
+                        entry.name,
+                        NameTotals {
+                            total_count: entry.total_tiles as u32,
+                            average_time: entry.best_avg_time,
+                        },
+                    )
+                })
+                .collect(),
+            frames_written: 0,
+            last_done_frame: 0,
+        }
+    }
+    // Starts a new metadata sidecar, opening with a snapshot of the current state,
+    // and returns the actions and filename of the previous one
+    fn rotate_meta(&mut self, begin: Instant, new_filename: String) -> (Vec<MetaAction>, String) {
+        let session = &mut self.meta_state.session;
+        session.uptime_secs = begin.elapsed().as_secs_f64();
+        if session.uptime_secs > 0.0 {
+            session.tiles_per_second = session.total_tiles as f64 / session.uptime_secs;
+        }
+        let new_actions = vec![MetaAction {
+            ts: begin.elapsed().as_millis() as u64,
+            payload: MetaActionPayload::Snapshot(self.meta_state.clone()),
+        }];
+        (
+            mem::replace(&mut self.meta_actions, new_actions),
+            mem::replace(&mut self.meta_filename, new_filename),
+        )
+    }
+}
+
+// Where the pixels of a BGRx frame live in its buffer
+#[derive(Debug, Clone, Copy)]
+struct FrameLayout {
+    size: usize,
+    offset: usize,
+    stride: usize,
+}
+
+impl FrameLayout {
+    // Without GStreamer there's no video info to ask, so rows are tightly packed
+    fn packed() -> Self {
+        Self {
+            size: WIDTH * HEIGHT * 4,
+            offset: 0,
+            stride: WIDTH * 4,
+        }
+    }
+}
+
+// Running averages per pixel, for when every tile is rendered several times
+struct SampleBuffer {
+    frames: Vec<u64>,
+    counts: Vec<u32>,
+    sums: Vec<Vec3>,
+}
+
+impl SampleBuffer {
+    fn new() -> Self {
+        Self {
+            frames: vec![0; WIDTH * HEIGHT],
+            counts: vec![0; WIDTH * HEIGHT],
+            sums: vec![Vec3::default(); WIDTH * HEIGHT],
+        }
+    }
+    // Adds one sample of a tile and returns the average so far of each of its
+    // pixels. A newer frame starts a pixel over, and a sample from an older
+    // frame than the pixel already has is left out.
+    fn add(&mut self, addr: TileAddr, pixels: &[Vec3]) -> Vec<Vec3> {
+        let (px, py, size) = addr.rect();
+        let mut averages = Vec::with_capacity(size * size);
+        for (i, &pixel) in pixels.iter().take(size * size).enumerate() {
+            let idx = (py + i / size) * WIDTH + px + i % size;
+            if addr.frame > self.frames[idx] {
+                self.frames[idx] = addr.frame;
+                self.counts[idx] = 0;
+                self.sums[idx] = Vec3::default();
+            }
+            if addr.frame == self.frames[idx] {
+                self.sums[idx] = self.sums[idx] + pixel;
+                self.counts[idx] += 1;
+            }
+            averages.push(self.sums[idx] * (1.0 / self.counts[idx] as f32));
+        }
+        averages
+    }
+}
+
+// Running colors of pixels drawn by multi-sampled clients, kept in linear
+// space so blending happens before the sRGB conversion
+struct BlendBuffer {
+    frames: Vec<Option<u64>>,
+    colors: Vec<Vec3>,
+}
+
+impl BlendBuffer {
+    fn new() -> Self {
+        Self {
+            frames: vec![None; WIDTH * HEIGHT],
+            colors: vec![Vec3::default(); WIDTH * HEIGHT],
+        }
+    }
+    // Moves each pixel of the tile towards its new color by `1 / samples` and
+    // returns the results. The first tile of a newer frame replaces the color,
+    // so nothing ghosts across frames, and a tile from an older frame than the
+    // pixel already has is left out.
+    fn blend(&mut self, addr: TileAddr, pixels: &[Vec3], samples: u8) -> Vec<Vec3> {
+        let (px, py, size) = addr.rect();
+        let weight = 1.0 / samples.max(1) as f32;
+        let mut colors = Vec::with_capacity(size * size);
+        for (i, &pixel) in pixels.iter().take(size * size).enumerate() {
+            let idx = (py + i / size) * WIDTH + px + i % size;
+            match self.frames[idx] {
+                Some(frame) if frame == addr.frame => {
+                    self.colors[idx] = self.colors[idx] + (pixel - self.colors[idx]) * weight;
+                }
+                Some(frame) if frame > addr.frame => {}
+                _ => {
+                    self.frames[idx] = Some(addr.frame);
+                    self.colors[idx] = pixel;
+                }
+            }
+            colors.push(self.colors[idx]);
+        }
+        colors
+    }
+}
+
+pub struct OutputConfig {
+    pub static_dir: PathBuf,
+    pub record: bool,
+    pub record_format: RecordFormat,
+    pub encoder: EncoderKind,
+    pub preview_stream: bool,
+    pub skip_unchanged: bool,
+    pub samples_per_tile: u32,
+    pub webm_output: bool,
+    pub webm_frame_interval: u64,
+    pub srgb: bool,
+    pub leaderboard: Vec<LeaderboardEntry>,
+    pub snapshots_dir: Option<PathBuf>,
+    pub snapshot_keep: u64,
+    pub snapshot_quality: i32,
+    pub raw_frames_dir: Option<PathBuf>,
+    pub raw_keep_frames: u64,
 }
 
 struct PlaylistWriter {
@@ -91,8 +326,9 @@ struct PlaylistWriter {
     playlist_state: Arc<Mutex<PlaylistState>>,
 }
 
-#[derive(Default)]
 struct PlaylistState {
+    // Wall clock time the stream started, which program dates count from
+    started_at: DateTime<Utc>,
     last_sequence_no: u32,
     total_elapsed: f64,
     next_duration: f64,
@@ -110,40 +346,41 @@ impl Write for PlaylistWriter {
 
 impl Drop for PlaylistWriter {
     fn drop(&mut self) {
-        let inner = mem::replace(self.inner.get_mut(), Vec::new());
+        let inner = mem::take(self.inner.get_mut());
         self.inner.set_position(0);
         let inner = String::from_utf8(inner).unwrap();
-        let mut written_program_date = false;
+        let started_at = self.playlist_state.lock().unwrap().started_at;
         let mut sequence_no = 0;
+        // Start time of the next segment in this playlist
+        let mut elapsed = None;
         for line in inner.lines() {
-            if !written_program_date {
-                if let Some(rest) = line.strip_prefix("#EXT-X-MEDIA-SEQUENCE:") {
-                    sequence_no = rest.parse().unwrap();
-                }
-                if let Some(rest) = line.strip_prefix("#EXTINF:") {
-                    written_program_date = true;
-                    let segment_duration: f64 = rest.strip_suffix(",").unwrap().parse().unwrap();
-                    let elapsed = {
-                        let mut guard = self.playlist_state.lock().unwrap();
-                        if sequence_no > guard.last_sequence_no {
-                            guard.last_sequence_no = sequence_no;
-                            guard.total_elapsed += guard.next_duration;
-                        }
-                        guard.next_duration = segment_duration;
-                        guard.total_elapsed
-                    };
-                    let base_datetime =
-                        DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(0, 0), Utc);
-                    let datetime = base_datetime
-                        + chrono::Duration::from_std(Duration::from_secs_f64(elapsed)).unwrap();
-
-                    writeln!(
-                        self.inner,
-                        "#EXT-X-PROGRAM-DATE-TIME:{}\n",
-                        datetime.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
-                    )
-                    .unwrap();
-                }
+            if let Some(rest) = line.strip_prefix("#EXT-X-MEDIA-SEQUENCE:") {
+                sequence_no = rest.parse().unwrap();
+            }
+            if let Some(rest) = line.strip_prefix("#EXTINF:") {
+                let segment_duration: f64 = rest.strip_suffix(",").unwrap().parse().unwrap();
+                // Only the first segment's start is tracked across playlists,
+                // the rest follow on from it
+                let start = *elapsed.get_or_insert_with(|| {
+                    let mut guard = self.playlist_state.lock().unwrap();
+                    if sequence_no > guard.last_sequence_no {
+                        guard.last_sequence_no = sequence_no;
+                        guard.total_elapsed += guard.next_duration;
+                    }
+                    guard.next_duration = segment_duration;
+                    guard.total_elapsed
+                });
+                elapsed = Some(start + segment_duration);
+
+                let datetime = started_at
+                    + chrono::Duration::from_std(Duration::from_secs_f64(start)).unwrap();
+
+                writeln!(
+                    self.inner,
+                    "#EXT-X-PROGRAM-DATE-TIME:{}",
+                    datetime.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+                )
+                .unwrap();
             }
             writeln!(self.inner, "{}", line).unwrap();
         }
@@ -153,41 +390,279 @@ impl Drop for PlaylistWriter {
 
 const WIDTH: usize = TILES_X * TILE_SIZE;
 const HEIGHT: usize = TILES_Y * TILE_SIZE;
+// In frames, so two seconds at 30fps
+const HW_KEYFRAME_INTERVAL: u32 = 60;
 
-pub fn output_thread(
-    rx: mpsc::Receiver<OutputEvent>,
-    term_now: Arc<AtomicBool>,
-) -> anyhow::Result<()> {
-    gst::init()?;
+fn to_u8(c: f32) -> u8 {
+    if c.is_nan() {
+        0
+    } else {
+        (c.clamp(0.0, 1.0) * 255.0) as u8
+    }
+}
 
-    let pipeline = gst::Pipeline::new(None);
-    let src = gst::ElementFactory::make("appsrc", None)?;
-    let videoconvert = gst::ElementFactory::make("videoconvert", None)?;
-    let encode = gst::ElementFactory::make("x264enc", None)?;
-    let caps = gst::ElementFactory::make("capsfilter", None)?;
-    let parse = gst::ElementFactory::make("h264parse", None)?;
-    let sink = gst::ElementFactory::make("hlssink2", None)?;
+fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn bgrx_to_rgb_image(
+    data: &[u8],
+    offset: usize,
+    stride: usize,
+    width: u32,
+    height: u32,
+) -> RgbImage {
+    RgbImage::from_fn(width, height, |x, y| {
+        let i = offset + y as usize * stride + x as usize * 4;
+        Rgb([data[i + 2], data[i + 1], data[i]])
+    })
+}
+
+fn encode_jpeg(data: &[u8], offset: usize, stride: usize, quality: i32) -> anyhow::Result<Vec<u8>> {
+    let image = turbojpeg::Image {
+        pixels: &data[offset..],
+        width: WIDTH,
+        pitch: stride,
+        height: HEIGHT,
+        format: turbojpeg::PixelFormat::BGRX,
+    };
+    Ok(turbojpeg::compress(image, quality, turbojpeg::Subsamp::Sub2x2)?.to_vec())
+}
+
+fn snapshot_path(dir: &Path, frame: u64) -> PathBuf {
+    dir.join(format!("frame_{frame:08}.png"))
+}
+
+fn write_snapshot(dir: &Path, frame: u64, image: RgbImage) {
+    let filename = snapshot_path(dir, frame);
+    if let Err(e) = image.save(&filename) {
+        log::warn!("Failed to write snapshot {}: {}", filename.display(), e);
+    }
+}
+
+// Encodes finished frames to JPEG and PNG snapshots on one long-lived thread.
+// Only the newest frame waits for it: a frame that's still waiting when the
+// next one finishes is replaced, so a slow encoder skips frames rather than
+// piling them up.
+struct FrameEncoder {
+    pending: Arc<PendingFrame>,
+}
+
+// The index and pixels of the frame waiting to be encoded, if any
+type PendingFrame = (Mutex<Option<(u64, Vec<u8>)>>, Condvar);
+
+impl FrameEncoder {
+    fn new(
+        layout: FrameLayout,
+        latest_jpeg: Arc<RwLock<Vec<u8>>>,
+        snapshots_dir: Option<PathBuf>,
+        snapshot_keep: u64,
+        snapshot_quality: i32,
+    ) -> Self {
+        let FrameLayout { offset, stride, .. } = layout;
+        let pending: Arc<PendingFrame> = Arc::new((Mutex::new(None), Condvar::new()));
+        let shared = pending.clone();
+        thread::spawn(move || {
+            let (slot, ready) = &*shared;
+            let mut last_stored = None;
+            // Skipped frames have no file, so track the ones that were written
+            let mut written = VecDeque::new();
+            loop {
+                let (index, data) = {
+                    let mut pending = ready
+                        .wait_while(slot.lock().unwrap(), |pending| pending.is_none())
+                        .unwrap();
+                    pending.take().unwrap()
+                };
+                if last_stored.is_some_and(|last| index <= last) {
+                    continue;
+                }
+                last_stored = Some(index);
+                match encode_jpeg(&data, offset, stride, snapshot_quality) {
+                    Ok(jpeg) => *latest_jpeg.write().unwrap() = jpeg,
+                    Err(e) => log::warn!("Failed to encode JPEG snapshot: {}", e),
+                }
+                if let Some(snapshots_dir) = &snapshots_dir {
+                    let image =
+                        bgrx_to_rgb_image(&data, offset, stride, WIDTH as u32, HEIGHT as u32);
+                    write_snapshot(snapshots_dir, index, image);
+                    written.push_back(index);
+                    while written.len() as u64 > snapshot_keep {
+                        let oldest = written.pop_front().unwrap();
+                        let _ = fs::remove_file(snapshot_path(snapshots_dir, oldest));
+                    }
+                }
+            }
+        });
+        Self { pending }
+    }
+    fn encode(&self, index: u64, data: Vec<u8>) {
+        let (slot, ready) = &*self.pending;
+        if let Some((skipped, _)) = slot.lock().unwrap().replace((index, data)) {
+            log::warn!("Frame encoder is behind, skipping frame {}", skipped);
+        }
+        ready.notify_one();
+    }
+}
+
+// How long a completed frame may wait for the raw frame writer before it's dropped
+const RAW_FRAME_SEND_TIMEOUT: Duration = Duration::from_millis(10);
+
+// Writes completed frames to disk on its own thread, so slow disks don't
+// hold up the live stream.
+struct RawFrameWriter {
+    tx: mpsc::SyncSender<(u64, Vec<u8>)>,
+}
+
+impl RawFrameWriter {
+    fn new(dir: PathBuf, keep: u64, offset: usize, stride: usize) -> anyhow::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let (tx, rx) = mpsc::sync_channel::<(u64, Vec<u8>)>(4);
+        thread::spawn(move || {
+            // Dropped and skipped frames have no file, so track the ones that were written
+            let mut written = VecDeque::new();
+            for (frame, data) in rx {
+                let filename = raw_frame_path(&dir, frame);
+                if let Err(e) = write_raw_frame(&filename, &data, offset, stride) {
+                    log::warn!("Failed to write raw frame {}: {}", filename.display(), e);
+                    continue;
+                }
+                written.push_back(frame);
+                while keep > 0 && written.len() as u64 > keep {
+                    let oldest = written.pop_front().unwrap();
+                    let _ = fs::remove_file(raw_frame_path(&dir, oldest));
+                }
+            }
+        });
+        Ok(Self { tx })
+    }
+    fn write(&self, frame: u64, data: Vec<u8>) {
+        if let Err(mpsc::TrySendError::Full(_)) =
+            self.tx
+                .send_with_timeout((frame, data), "RawFrameWriter.tx", RAW_FRAME_SEND_TIMEOUT)
+        {
+            log::warn!("Raw frame writer is behind, dropping frame {}", frame);
+        }
+    }
+}
+
+fn raw_frame_path(dir: &Path, frame: u64) -> PathBuf {
+    dir.join(format!("frame_{frame:08}.raw"))
+}
+
+fn write_raw_frame(filename: &Path, data: &[u8], offset: usize, stride: usize) -> io::Result<()> {
+    let mut file = BufWriter::new(fs::File::create(filename)?);
+    file.write_u32::<LittleEndian>(WIDTH as u32)?;
+    file.write_u32::<LittleEndian>(HEIGHT as u32)?;
+    for y in 0..HEIGHT {
+        let row = offset + y * stride;
+        file.write_all(&data[row..row + WIDTH * 4])?;
+    }
+    file.flush()
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RecordFormat {
+    Ts,
+    Mp4,
+}
+
+impl FromStr for RecordFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ts" => Ok(Self::Ts),
+            "mp4" => Ok(Self::Mp4),
+            _ => Err(anyhow::anyhow!("Unknown record format: {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EncoderKind {
+    Software,
+    Vaapi,
+    Nvenc,
+}
+
+impl FromStr for EncoderKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "vaapi" => Ok(Self::Vaapi),
+            "nvenc" => Ok(Self::Nvenc),
+            _ => Err(anyhow::anyhow!("Unknown hardware encoder: {s}")),
+        }
+    }
+}
+
+// Hardware encoders are looked up by name at runtime, so a missing plugin or
+// device only shows up here and we drop back to x264.
+fn make_encoder(kind: EncoderKind) -> anyhow::Result<gst::Element> {
+    let name = match kind {
+        EncoderKind::Software => return Ok(gst::ElementFactory::make("x264enc", None)?),
+        EncoderKind::Vaapi => "vaapih264enc",
+        EncoderKind::Nvenc => "nvh264enc",
+    };
+    let encode = match gst::ElementFactory::make(name, None) {
+        Ok(encode) => encode,
+        Err(e) => {
+            log::warn!("Could not create {}, falling back to x264: {}", name, e);
+            return make_encoder(EncoderKind::Software);
+        }
+    };
+    // Both default to constant quality, so match x264's default bitrate
+    // instead, and keep keyframes frequent enough for the HLS segments
+    if kind == EncoderKind::Vaapi {
+        encode.set_property_from_str("rate-control", "cbr");
+        encode.set_property("keyframe-period", HW_KEYFRAME_INTERVAL);
+    } else {
+        encode.set_property("gop-size", HW_KEYFRAME_INTERVAL as i32);
+    }
+    encode.set_property("bitrate", 2048u32);
+    Ok(encode)
+}
+
+fn make_recording_appsrc(src: gst::Element, video_info: &gst_video::VideoInfo) -> gst_app::AppSrc {
+    let appsrc = src
+        .dynamic_cast::<gst_app::AppSrc>()
+        .expect("Source element is expected to be an appsrc!");
+
+    appsrc.set_caps(Some(&video_info.to_caps().unwrap()));
+    appsrc.set_format(gst::Format::Time);
+    appsrc
+}
 
+fn make_file_pipeline(
+    static_dir: &Path,
+    ts: &str,
+    video_info: &gst_video::VideoInfo,
+    format: RecordFormat,
+    encoder: EncoderKind,
+) -> anyhow::Result<(gst::Pipeline, gst_app::AppSrc)> {
     let file_pipeline = gst::Pipeline::new(None);
     let file_src = gst::ElementFactory::make("appsrc", None)?;
     let file_videoconvert = gst::ElementFactory::make("videoconvert", None)?;
-    let file_encode = gst::ElementFactory::make("x264enc", None)?;
+    let file_encode = make_encoder(encoder)?;
     let file_caps = gst::ElementFactory::make("capsfilter", None)?;
     let file_parse = gst::ElementFactory::make("h264parse", None)?;
-    // let file_mux = gst::ElementFactory::make("mp4mux", None)?;
-    let file_mux = gst::ElementFactory::make("mpegtsmux", None)?;
+    let (file_mux, extension) = match format {
+        RecordFormat::Ts => (gst::ElementFactory::make("mpegtsmux", None)?, "ts"),
+        RecordFormat::Mp4 => {
+            let mux = gst::ElementFactory::make("mp4mux", None)?;
+            // Fragmented, so everything up to the last fragment survives a crash
+            mux.set_property("fragment-duration", 1000u32);
+            (mux, "mp4")
+        }
+    };
     let file_sink = gst::ElementFactory::make("filesink", None)?;
 
-    caps.set_property(
-        "caps",
-        gst::Caps::builder("video/x-h264")
-            .field("profile", "baseline")
-            .build(),
-    );
-    sink.set_property("location", "static/livevideo/segment%05d.ts");
-    sink.set_property("playlist-location", "static/livevideo/playlist.m3u8");
-    sink.set_property("target-duration", 3u32);
-
     file_encode.set_property("bitrate", 8092u32);
     file_caps.set_property(
         "caps",
@@ -195,12 +670,14 @@ pub fn output_thread(
             .field("profile", "high")
             .build(),
     );
-    let ts = Utc::now()
-        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
-        .replace(":", "-");
-    file_sink.set_property("location", format!("static/recording/{ts}.ts"));
+    file_sink.set_property(
+        "location",
+        static_dir
+            .join(format!("recording/{ts}.{extension}"))
+            .to_string_lossy()
+            .into_owned(),
+    );
 
-    pipeline.add_many(&[&src, &videoconvert, &encode, &caps, &parse, &sink])?;
     file_pipeline.add_many(&[
         &file_src,
         &file_videoconvert,
@@ -210,7 +687,6 @@ pub fn output_thread(
         &file_mux,
         &file_sink,
     ])?;
-    gst::Element::link_many(&[&src, &videoconvert, &encode, &caps, &parse, &sink])?;
     gst::Element::link_many(&[
         &file_src,
         &file_videoconvert,
@@ -221,13 +697,262 @@ pub fn output_thread(
         &file_sink,
     ])?;
 
+    Ok((file_pipeline, make_recording_appsrc(file_src, video_info)))
+}
+
+fn make_webm_pipeline(
+    static_dir: &Path,
+    ts: &str,
+    video_info: &gst_video::VideoInfo,
+) -> anyhow::Result<(gst::Pipeline, gst_app::AppSrc)> {
+    let webm_pipeline = gst::Pipeline::new(None);
+    let webm_src = gst::ElementFactory::make("appsrc", None)?;
+    let webm_videoconvert = gst::ElementFactory::make("videoconvert", None)?;
+    let webm_encode = gst::ElementFactory::make("vp9enc", None)?;
+    let webm_mux = gst::ElementFactory::make("webmmux", None)?;
+    let webm_sink = gst::ElementFactory::make("filesink", None)?;
+
+    webm_encode.set_property("target-bitrate", 8_000_000i32);
+    webm_sink.set_property(
+        "location",
+        static_dir
+            .join(format!("recording/{ts}.webm"))
+            .to_string_lossy()
+            .into_owned(),
+    );
+
+    webm_pipeline.add_many(&[
+        &webm_src,
+        &webm_videoconvert,
+        &webm_encode,
+        &webm_mux,
+        &webm_sink,
+    ])?;
+    gst::Element::link_many(&[
+        &webm_src,
+        &webm_videoconvert,
+        &webm_encode,
+        &webm_mux,
+        &webm_sink,
+    ])?;
+
+    Ok((webm_pipeline, make_recording_appsrc(webm_src, video_info)))
+}
+
+// Runs a recording pipeline until it has written out everything after EOS.
+// Each recording finishes on its own, and the process exits once the last
+// one is saved.
+fn start_file_pipeline(
+    file_pipeline: &gst::Pipeline,
+    description: &'static str,
+    remaining: Arc<AtomicUsize>,
+) -> anyhow::Result<()> {
+    file_pipeline.set_state(gst::State::Playing)?;
+
+    let file_bus = file_pipeline.bus().unwrap();
+    let pipeline_name = file_pipeline.name();
+    thread::spawn(move || {
+        let mut playing = false;
+        for msg in file_bus.iter_timed(gst::ClockTime::NONE) {
+            match msg.view() {
+                MessageView::StateChanged(state_changed)
+                    if state_changed.src().map(|src| src.name()) == Some(pipeline_name.clone())
+                        && state_changed.current() == gst::State::Playing
+                        && !playing =>
+                {
+                    playing = true;
+                    log::info!("{} recording started", description);
+                }
+                MessageView::Eos(..) => {
+                    if !playing {
+                        log::warn!("{} recording ended without ever playing", description);
+                    }
+                    println!("{} recording saved.", description);
+                    if remaining.fetch_sub(1, Ordering::SeqCst) == 1 {
+                        println!("All recordings saved. Exiting.");
+                        process::exit(0);
+                    }
+                    break;
+                }
+                MessageView::Error(err) => eprintln!("{:?}", err),
+                _ => {}
+            }
+        }
+    });
+    Ok(())
+}
+
+// Downscaled copy of the live stream for bandwidth-limited embeds. Unlike the
+// main stream there's no JSON sidecar, so hlssink2 manages its own files.
+fn add_preview_branch(
+    pipeline: &gst::Pipeline,
+    tee: &Element,
+    static_dir: &Path,
+    encoder: EncoderKind,
+) -> anyhow::Result<()> {
+    let queue = gst::ElementFactory::make("queue", None)?;
+    let videoscale = gst::ElementFactory::make("videoscale", None)?;
+    let scale_caps = gst::ElementFactory::make("capsfilter", None)?;
+    let encode = make_encoder(encoder)?;
+    let caps = gst::ElementFactory::make("capsfilter", None)?;
+    let parse = gst::ElementFactory::make("h264parse", None)?;
+    let sink = gst::ElementFactory::make("hlssink2", None)?;
+
+    scale_caps.set_property(
+        "caps",
+        gst::Caps::builder("video/x-raw")
+            .field("width", (WIDTH / 2) as i32)
+            .field("height", (HEIGHT / 2) as i32)
+            .build(),
+    );
+    encode.set_property("bitrate", 1024u32);
+    caps.set_property(
+        "caps",
+        gst::Caps::builder("video/x-h264")
+            .field("profile", "baseline")
+            .build(),
+    );
+    let preview_dir = static_dir.join("livevideo-low");
+    sink.set_property(
+        "location",
+        preview_dir
+            .join("segment%05d.ts")
+            .to_string_lossy()
+            .into_owned(),
+    );
+    sink.set_property(
+        "playlist-location",
+        preview_dir
+            .join("playlist.m3u8")
+            .to_string_lossy()
+            .into_owned(),
+    );
+    sink.set_property("target-duration", 3u32);
+
+    pipeline.add_many(&[
+        &queue,
+        &videoscale,
+        &scale_caps,
+        &encode,
+        &caps,
+        &parse,
+        &sink,
+    ])?;
+    gst::Element::link_many(&[
+        tee,
+        &queue,
+        &videoscale,
+        &scale_caps,
+        &encode,
+        &caps,
+        &parse,
+        &sink,
+    ])?;
+    Ok(())
+}
+
+pub fn output_thread(
+    rx: mpsc::Receiver<OutputEvent>,
+    term_now: Arc<AtomicBool>,
+    latest_jpeg: Arc<RwLock<Vec<u8>>>,
+    config: OutputConfig,
+) -> anyhow::Result<()> {
+    gst::init()?;
+    let OutputConfig {
+        static_dir,
+        record,
+        record_format,
+        encoder,
+        preview_stream,
+        skip_unchanged,
+        samples_per_tile,
+        webm_output,
+        webm_frame_interval,
+        srgb,
+        leaderboard,
+        snapshots_dir,
+        snapshot_keep,
+        snapshot_quality,
+        raw_frames_dir,
+        raw_keep_frames,
+    } = config;
+    if let Some(snapshots_dir) = &snapshots_dir {
+        fs::create_dir_all(snapshots_dir)?;
+    }
+
+    let pipeline = gst::Pipeline::new(None);
+    let src = gst::ElementFactory::make("appsrc", None)?;
+    let videoconvert = gst::ElementFactory::make("videoconvert", None)?;
+    let encode = make_encoder(encoder)?;
+    let caps = gst::ElementFactory::make("capsfilter", None)?;
+    let parse = gst::ElementFactory::make("h264parse", None)?;
+    let sink = gst::ElementFactory::make("hlssink2", None)?;
+
+    caps.set_property(
+        "caps",
+        gst::Caps::builder("video/x-h264")
+            .field("profile", "baseline")
+            .build(),
+    );
+    let livevideo_dir = static_dir.join("livevideo");
+    sink.set_property(
+        "location",
+        livevideo_dir
+            .join("segment%05d.ts")
+            .to_string_lossy()
+            .into_owned(),
+    );
+    sink.set_property(
+        "playlist-location",
+        livevideo_dir
+            .join("playlist.m3u8")
+            .to_string_lossy()
+            .into_owned(),
+    );
+    sink.set_property("target-duration", 3u32);
+
+    if preview_stream {
+        let tee = gst::ElementFactory::make("tee", None)?;
+        let queue = gst::ElementFactory::make("queue", None)?;
+        pipeline.add_many(&[
+            &src,
+            &videoconvert,
+            &tee,
+            &queue,
+            &encode,
+            &caps,
+            &parse,
+            &sink,
+        ])?;
+        gst::Element::link_many(&[
+            &src,
+            &videoconvert,
+            &tee,
+            &queue,
+            &encode,
+            &caps,
+            &parse,
+            &sink,
+        ])?;
+        add_preview_branch(&pipeline, &tee, &static_dir, encoder)?;
+    } else {
+        pipeline.add_many(&[&src, &videoconvert, &encode, &caps, &parse, &sink])?;
+        gst::Element::link_many(&[&src, &videoconvert, &encode, &caps, &parse, &sink])?;
+    }
+
     let video_info =
         gst_video::VideoInfo::builder(gst_video::VideoFormat::Bgrx, WIDTH as u32, HEIGHT as u32)
             .fps(gst::Fraction::new(30, 1))
             .build()
             .expect("Failed to create video info");
-    let stride = video_info.stride()[0] as usize;
-    let offset = video_info.offset()[0] as usize;
+    let layout = FrameLayout {
+        size: video_info.size(),
+        offset: video_info.offset()[0],
+        stride: video_info.stride()[0] as usize,
+    };
+    let raw_frame_writer = raw_frames_dir
+        .map(|dir| RawFrameWriter::new(dir, raw_keep_frames, layout.offset, layout.stride))
+        .transpose()?;
 
     let appsrc = src
         .dynamic_cast::<gst_app::AppSrc>()
@@ -237,30 +962,38 @@ pub fn output_thread(
     appsrc.set_format(gst::Format::Time);
     appsrc.set_is_live(true);
 
-    let file_appsrc = file_src
-        .dynamic_cast::<gst_app::AppSrc>()
-        .expect("Source element is expected to be an appsrc!");
+    let recording_ts = Utc::now()
+        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+        .replace(":", "-");
+    let recording = if record {
+        Some(make_file_pipeline(
+            &static_dir,
+            &recording_ts,
+            &video_info,
+            record_format,
+            encoder,
+        )?)
+    } else {
+        None
+    };
+    let file_appsrc = recording.as_ref().map(|(_, appsrc)| appsrc.clone());
+    let webm_recording = if webm_output {
+        Some(make_webm_pipeline(&static_dir, &recording_ts, &video_info)?)
+    } else {
+        None
+    };
+    let webm_appsrc = webm_recording.as_ref().map(|(_, appsrc)| appsrc.clone());
 
-    file_appsrc.set_caps(Some(&video_info.to_caps().unwrap()));
-    file_appsrc.set_format(gst::Format::Time);
-
-    let acc = Arc::new(Mutex::new(Accumulator {
-        data: vec![0x40; video_info.size()],
-        frame_done: false,
-        meta_state: MetaState {
-            tiles: vec![None; TILES_X * TILES_Y],
-            clients: HashMap::new(),
-            tiles_x: TILES_X,
-            tiles_y: TILES_Y,
-        },
-        meta_actions: Vec::new(),
-        meta_filename: String::new(),
-    }));
+    let acc = Arc::new(Mutex::new(Accumulator::new(layout, leaderboard)));
     let acc2 = acc.clone();
     let acc3 = acc.clone();
-    let playlist_state = Arc::new(Mutex::new(PlaylistState::default()));
-
     let begin = Instant::now();
+    let playlist_state = Arc::new(Mutex::new(PlaylistState {
+        started_at: Utc::now(),
+        last_sequence_no: 0,
+        total_elapsed: 0.0,
+        next_duration: 0.0,
+    }));
     sink.connect_closure(
         "get-playlist-stream",
         false,
@@ -279,18 +1012,7 @@ pub fn output_thread(
         false,
         glib::closure!(move |_elem: &Element, filename: &str| -> FileOutputStream {
             let new_filename = format!("{}.json", filename);
-            let (old_actions, old_filename) = {
-                let mut acc_guard = acc3.lock().unwrap();
-                let mut new_actions = Vec::new();
-                new_actions.push(MetaAction {
-                    ts: begin.elapsed().as_millis() as u64,
-                    payload: MetaActionPayload::Snapshot(acc_guard.meta_state.clone()),
-                });
-                (
-                    mem::replace(&mut acc_guard.meta_actions, new_actions),
-                    mem::replace(&mut acc_guard.meta_filename, new_filename),
-                )
-            };
+            let (old_actions, old_filename) = acc3.lock().unwrap().rotate_meta(begin, new_filename);
 
             if !old_filename.is_empty() {
                 fs::write(old_filename, serde_json::to_string(&old_actions).unwrap()).unwrap();
@@ -312,41 +1034,83 @@ pub fn output_thread(
     );
 
     let mut i = 0;
+    let mut webm_frames_seen = 0;
+    let mut webm_i = 0;
+    let mut finalizing = false;
+    let mut last_buffer: Option<gst::Buffer> = None;
     appsrc.set_callbacks(
         gst_app::AppSrcCallbacks::builder()
             .need_data(move |appsrc, _| {
-                // Create the buffer that can hold exactly one BGRx frame.
-                let mut buffer = gst::Buffer::with_size(video_info.size()).unwrap();
-                let buffer_ref = buffer.get_mut().unwrap();
-                let frame_done = {
+                let (mut buffer, frame_done) = {
                     let mut acc_guard = acc.lock().unwrap();
-                    buffer_ref.copy_from_slice(0, &acc_guard.data).unwrap();
-                    mem::replace(&mut acc_guard.frame_done, false)
+                    let frame_done = mem::replace(&mut acc_guard.frame_done, false);
+                    match &last_buffer {
+                        // The live stream still needs a buffer on every call to keep
+                        // its timing, but an unchanged one can share the last one's memory
+                        Some(last_buffer) if skip_unchanged && !frame_done => {
+                            (last_buffer.copy(), false)
+                        }
+                        _ => {
+                            // Create the buffer that can hold exactly one BGRx frame.
+                            let mut buffer = gst::Buffer::with_size(video_info.size()).unwrap();
+                            let buffer_ref = buffer.get_mut().unwrap();
+                            buffer_ref.copy_from_slice(0, &acc_guard.front).unwrap();
+                            (buffer, frame_done)
+                        }
+                    }
                 };
                 let ts = begin.elapsed().as_millis() as u64;
-                buffer_ref.set_pts(ts * gst::ClockTime::MSECOND);
-
-                if frame_done {
-                    let mut buffer = buffer.copy();
-                    let buffer_ref = buffer.get_mut().unwrap();
-                    buffer_ref.set_pts(Some(i * 33 * gst::ClockTime::MSECOND));
-                    i += 1;
-                    let _ = file_appsrc.push_buffer(buffer);
+                buffer
+                    .get_mut()
+                    .unwrap()
+                    .set_pts(ts * gst::ClockTime::MSECOND);
+                if skip_unchanged {
+                    last_buffer = Some(buffer.clone());
+                }
+
+                if let Some(file_appsrc) = &file_appsrc {
+                    if frame_done {
+                        let mut buffer = buffer.copy();
+                        let buffer_ref = buffer.get_mut().unwrap();
+                        buffer_ref.set_pts(Some(i * 33 * gst::ClockTime::MSECOND));
+                        i += 1;
+                        let _ = file_appsrc.push_buffer(buffer);
+                    }
+                }
+                if let Some(webm_appsrc) = &webm_appsrc {
+                    // VP9 is slow to encode, so only every Nth frame is kept
+                    if frame_done {
+                        if webm_frames_seen % webm_frame_interval == 0 {
+                            let mut buffer = buffer.copy();
+                            let buffer_ref = buffer.get_mut().unwrap();
+                            buffer_ref.set_pts(Some(
+                                webm_i * webm_frame_interval * 33 * gst::ClockTime::MSECOND,
+                            ));
+                            webm_i += 1;
+                            let _ = webm_appsrc.push_buffer(buffer);
+                        }
+                        webm_frames_seen += 1;
+                    }
                 }
                 // appsrc already handles the error here
                 let _ = appsrc.push_buffer(buffer);
 
-                // If Ctrl+C is pressed, end the recording
-                if term_now.load(Ordering::Relaxed) {
+                // If Ctrl+C is pressed, end the recordings
+                if term_now.load(Ordering::Relaxed) && !finalizing {
+                    finalizing = true;
+                    if file_appsrc.is_none() && webm_appsrc.is_none() {
+                        process::exit(0);
+                    }
                     println!("Finalizing video recording...");
-                    file_appsrc.end_of_stream().unwrap();
+                    for recording_appsrc in file_appsrc.iter().chain(&webm_appsrc) {
+                        let _ = recording_appsrc.end_of_stream();
+                    }
                 }
             })
             .build(),
     );
 
     pipeline.set_state(gst::State::Playing)?;
-    file_pipeline.set_state(gst::State::Playing)?;
 
     let bus = pipeline.bus().unwrap();
     thread::spawn(move || {
@@ -359,89 +1123,562 @@ pub fn output_thread(
         }
     });
 
-    let file_bus = file_pipeline.bus().unwrap();
+    let remaining_recordings = Arc::new(AtomicUsize::new(
+        recording.iter().count() + webm_recording.iter().count(),
+    ));
+    if let Some((file_pipeline, _)) = &recording {
+        start_file_pipeline(file_pipeline, "Video", remaining_recordings.clone())?;
+    }
+    if let Some((webm_pipeline, _)) = &webm_recording {
+        start_file_pipeline(webm_pipeline, "WebM", remaining_recordings)?;
+    }
+
+    let finish_frame = frame_finisher(
+        layout,
+        latest_jpeg,
+        snapshots_dir,
+        snapshot_keep,
+        snapshot_quality,
+        raw_frame_writer,
+    );
+    process_events(
+        rx,
+        &acc2,
+        begin,
+        layout,
+        srgb,
+        samples_per_tile,
+        finish_frame,
+    );
+    Ok(())
+}
+
+// How often the dry run output starts a new metadata sidecar, like the live
+// stream's segment duration
+const STUB_SIDECAR_INTERVAL: Duration = Duration::from_secs(3);
+// Number of sidecars the dry run output keeps, like hlssink2's max-files
+const STUB_SIDECAR_KEEP: u64 = 10;
+const TERM_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Stands in for `output_thread` when there's no GStreamer to produce video. Frames
+// are only kept in memory, for snapshots, and the dashboard metadata is written to
+// `livevideo/dryrun*.json` on the same schedule the stream would write its sidecars.
+pub fn output_thread_stub(
+    rx: mpsc::Receiver<OutputEvent>,
+    term_now: Arc<AtomicBool>,
+    latest_jpeg: Arc<RwLock<Vec<u8>>>,
+    config: OutputConfig,
+) -> anyhow::Result<()> {
+    let OutputConfig {
+        static_dir,
+        samples_per_tile,
+        srgb,
+        leaderboard,
+        snapshots_dir,
+        snapshot_keep,
+        snapshot_quality,
+        raw_frames_dir,
+        raw_keep_frames,
+        ..
+    } = config;
+    if let Some(snapshots_dir) = &snapshots_dir {
+        fs::create_dir_all(snapshots_dir)?;
+    }
+    let livevideo_dir = static_dir.join("livevideo");
+    fs::create_dir_all(&livevideo_dir)?;
+
+    let layout = FrameLayout::packed();
+    let raw_frame_writer = raw_frames_dir
+        .map(|dir| RawFrameWriter::new(dir, raw_keep_frames, layout.offset, layout.stride))
+        .transpose()?;
+    let acc = Arc::new(Mutex::new(Accumulator::new(layout, leaderboard)));
+    let acc2 = acc.clone();
+    let begin = Instant::now();
+
+    // Nothing pulls frames, so this thread also has to notice Ctrl+C
     thread::spawn(move || {
-        for msg in file_bus.iter_timed(gst::ClockTime::NONE) {
-            match msg.view() {
-                MessageView::Eos(..) => {
-                    println!("Video recording saved. Exiting.");
-                    process::exit(0);
+        let sidecar_path = |index: u64| {
+            livevideo_dir
+                .join(format!("dryrun{:05}.json", index))
+                .to_string_lossy()
+                .into_owned()
+        };
+        for index in 0u64.. {
+            let (old_actions, old_filename) =
+                acc2.lock().unwrap().rotate_meta(begin, sidecar_path(index));
+            if !old_filename.is_empty() {
+                if let Err(e) =
+                    fs::write(&old_filename, serde_json::to_string(&old_actions).unwrap())
+                {
+                    log::warn!("Failed to write {}: {}", old_filename, e);
                 }
-                MessageView::Error(err) => eprintln!("{:?}", err),
-                _ => {}
+            }
+            if let Some(stale) = index.checked_sub(STUB_SIDECAR_KEEP + 1) {
+                let _ = fs::remove_file(sidecar_path(stale));
+            }
+            if term_now.load(Ordering::Relaxed) {
+                process::exit(0);
+            }
+            let deadline = Instant::now() + STUB_SIDECAR_INTERVAL;
+            while Instant::now() < deadline && !term_now.load(Ordering::Relaxed) {
+                thread::sleep(TERM_POLL_INTERVAL);
             }
         }
     });
 
+    let finish_frame = frame_finisher(
+        layout,
+        latest_jpeg,
+        snapshots_dir,
+        snapshot_keep,
+        snapshot_quality,
+        raw_frame_writer,
+    );
+    process_events(
+        rx,
+        &acc,
+        begin,
+        layout,
+        srgb,
+        samples_per_tile,
+        finish_frame,
+    );
+    Ok(())
+}
+
+fn frame_finisher(
+    layout: FrameLayout,
+    latest_jpeg: Arc<RwLock<Vec<u8>>>,
+    snapshots_dir: Option<PathBuf>,
+    snapshot_keep: u64,
+    snapshot_quality: i32,
+    raw_frame_writer: Option<RawFrameWriter>,
+) -> impl Fn(&mut Accumulator, u64) {
+    let encoder = FrameEncoder::new(
+        layout,
+        latest_jpeg,
+        snapshots_dir,
+        snapshot_keep,
+        snapshot_quality,
+    );
+    move |acc, frame| {
+        acc.frame_done = true;
+        acc.last_done_frame = frame;
+        // Copied rather than swapped, since tiles that didn't change aren't blitted again
+        acc.front.copy_from_slice(&acc.back);
+        let index = acc.frames_written;
+        let data = acc.front.clone();
+        if let Some(raw_frame_writer) = &raw_frame_writer {
+            raw_frame_writer.write(index, data.clone());
+        }
+        encoder.encode(index, data);
+        acc.frames_written += 1;
+    }
+}
+
+fn process_events(
+    rx: mpsc::Receiver<OutputEvent>,
+    acc: &Mutex<Accumulator>,
+    begin: Instant,
+    layout: FrameLayout,
+    srgb: bool,
+    samples_per_tile: u32,
+    finish_frame: impl Fn(&mut Accumulator, u64),
+) {
+    let mut samples = (samples_per_tile > 1).then(SampleBuffer::new);
+    // Only allocated once a multi-sampled client shows up, then fed every tile
+    // so blends start from what's on screen
+    let mut blended: Option<BlendBuffer> = None;
     while let Ok(event) = rx.recv() {
         match event {
+            OutputEvent::ForceCompleteFrame(frame) => {
+                let mut acc_guard = acc.lock().unwrap();
+                acc_guard.meta_state.forced_frames += 1;
+                // The last tile may have arrived before the deadline, in which
+                // case the frame was already finished
+                if frame != acc_guard.last_done_frame {
+                    finish_frame(&mut acc_guard, frame);
+                }
+            }
             OutputEvent::BlitTile(payload) => {
-                let mut acc_guard = acc2.lock().unwrap();
-                if payload.addr.x == TILES_X - 1 && payload.addr.y == TILES_Y - 1 {
-                    acc_guard.frame_done = true;
+                // Convert colors before taking the lock, so it's only held for the copy.
+                // The server validates the pixel count, but never index past the tile.
+                let (px, py, size) = payload.addr.rect();
+                let encode = |c: f32| to_u8(if srgb { linear_to_srgb(c) } else { c });
+                let averaged = samples
+                    .as_mut()
+                    .map(|samples| samples.add(payload.addr, &payload.pixels));
+                let pixels = averaged.as_ref().unwrap_or(&payload.pixels);
+                if payload.samples > 1 && blended.is_none() {
+                    blended = Some(BlendBuffer::new());
                 }
+                let colors = blended
+                    .as_mut()
+                    .map(|blended| blended.blend(payload.addr, pixels, payload.samples));
+                let bgr: Vec<[u8; 3]> = colors
+                    .as_ref()
+                    .unwrap_or(pixels)
+                    .par_iter()
+                    .take(size * size)
+                    .map(|pixel| [encode(pixel.z), encode(pixel.y), encode(pixel.x)])
+                    .collect();
+
+                let mut acc_guard = acc.lock().unwrap();
 
-                let buffer = &mut *acc_guard.data;
-                for y in 0..TILE_SIZE {
-                    for x in 0..TILE_SIZE {
-                        let i = offset
-                            + (payload.addr.y * TILE_SIZE + y) * stride
-                            + (payload.addr.x * TILE_SIZE + x) * 4;
-                        let j = y * TILE_SIZE + x;
-                        buffer[i] = (payload.pixels[j].z * 255.0) as u8;
-                        buffer[i + 1] = (payload.pixels[j].y * 255.0) as u8;
-                        buffer[i + 2] = (payload.pixels[j].x * 255.0) as u8;
+                let buffer = &mut *acc_guard.back;
+                for (y, row) in bgr.chunks(size).enumerate() {
+                    let start = layout.offset + (py + y) * layout.stride + px * 4;
+                    for (dst, src) in buffer[start..start + row.len() * 4]
+                        .chunks_exact_mut(4)
+                        .zip(row)
+                    {
+                        dst[..3].copy_from_slice(src);
                     }
                 }
-                let tile = payload.addr.y * TILES_X + payload.addr.x;
+                if payload.completes_frame {
+                    finish_frame(&mut acc_guard, payload.addr.frame);
+                }
+                // The dashboard works in base sized tiles, so larger tiles
+                // claim several and smaller ones claim the one they're in
+                let tiles: Vec<usize> = payload.addr.base_cells().collect();
 
-                if let Some(old_client_id) = acc_guard.meta_state.tiles[tile] {
-                    let mut old_client = acc_guard
-                        .meta_state
-                        .clients
-                        .get_mut(&old_client_id)
-                        .unwrap();
-                    old_client.current_count -= 1;
-                    if old_client.current_count == 0 {
-                        acc_guard.meta_state.clients.remove(&old_client_id);
+                for &tile in &tiles {
+                    if let Some(old_client_id) = acc_guard.meta_state.tiles[tile] {
+                        let old_client = acc_guard
+                            .meta_state
+                            .clients
+                            .get_mut(&old_client_id)
+                            .unwrap();
+                        old_client.current_count -= 1;
+                        if old_client.current_count == 0 {
+                            acc_guard.meta_state.clients.remove(&old_client_id);
+                        }
                     }
+                    acc_guard.meta_state.tiles[tile] = Some(payload.client_id);
                 }
-                acc_guard.meta_state.tiles[tile] = Some(payload.client_id);
 
-                let client = acc_guard
+                let acc_ref = &mut *acc_guard;
+                let name_totals = acc_ref
+                    .name_totals
+                    .entry(payload.name.clone())
+                    .or_insert_with(|| NameTotals {
+                        total_count: 0,
+                        average_time: payload.time,
+                    });
+
+                let client = acc_ref
                     .meta_state
                     .clients
                     .entry(payload.client_id)
                     .or_insert_with(|| ClientState {
                         current_count: 0,
-                        total_count: 0,
-                        average_time: payload.time,
+                        total_count: name_totals.total_count,
+                        average_time: name_totals.average_time,
                         name: String::new(),
+                        time_buckets: Default::default(),
                     });
 
+                name_totals.average_time = name_totals.average_time * 0.999 + payload.time * 0.001;
+                name_totals.total_count += 1;
+
                 let name_changed = client.name != payload.name;
                 if name_changed {
                     client.name = payload.name.clone();
                 }
                 client.average_time = client.average_time * 0.999 + payload.time * 0.001;
-                client.current_count += 1;
+                client.current_count += tiles.len() as u32;
                 client.total_count += 1;
+                acc_ref.meta_state.session.total_tiles += 1;
+                let bucket = TIME_BUCKET_BOUNDS.partition_point(|&b| b <= payload.time);
+                client.time_buckets[bucket] += 1;
 
+                let ts = begin.elapsed().as_millis() as u64;
+                for (i, &tile) in tiles.iter().enumerate() {
+                    acc_ref.meta_actions.push(MetaAction {
+                        ts,
+                        payload: MetaActionPayload::BlitTile(MetaBlitTile {
+                            client_id: payload.client_id,
+                            tile,
+                            time: payload.time,
+                            name: (name_changed && i == 0).then(|| payload.name.clone()),
+                        }),
+                    });
+                }
+            }
+            OutputEvent::ClientDisconnected(client_id) => {
+                let mut acc_guard = acc.lock().unwrap();
+                let acc_ref = &mut *acc_guard;
+                for tile in &mut acc_ref.meta_state.tiles {
+                    if *tile == Some(client_id) {
+                        *tile = None;
+                    }
+                }
+                acc_ref.meta_state.clients.remove(&client_id);
+                acc_ref.meta_actions.push(MetaAction {
+                    ts: begin.elapsed().as_millis() as u64,
+                    payload: MetaActionPayload::ClientDisconnected(client_id),
+                });
+            }
+            OutputEvent::FrameProgress {
+                frame,
+                completed,
+                total,
+            } => {
+                let mut acc_guard = acc.lock().unwrap();
+                // Stragglers from older frames shouldn't move the bar backwards
+                if frame < acc_guard.meta_state.progress.current_frame {
+                    continue;
+                }
+                let progress = MetaFrameProgress {
+                    current_frame: frame,
+                    completed_tiles: completed,
+                    outstanding_tiles: total.saturating_sub(completed),
+                };
+                acc_guard.meta_state.progress = progress.clone();
                 acc_guard.meta_actions.push(MetaAction {
                     ts: begin.elapsed().as_millis() as u64,
-                    payload: MetaActionPayload::BlitTile(MetaBlitTile {
-                        client_id: payload.client_id,
-                        tile,
-                        time: payload.time,
-                        name: if name_changed {
-                            Some(payload.name)
-                        } else {
-                            None
-                        },
-                    }),
+                    payload: MetaActionPayload::FrameProgress(progress),
                 });
             }
         }
     }
-    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::{protocol::srgb_to_linear, server_state::TileLevel};
+
+    #[test]
+    fn to_u8_clamps_out_of_range_channels() {
+        assert_eq!(to_u8(-0.5), 0);
+        assert_eq!(to_u8(1.5), 255);
+        assert_eq!(to_u8(f32::NAN), 0);
+        assert_eq!(to_u8(0.999), 254);
+        assert_eq!(to_u8(0.0), 0);
+        assert_eq!(to_u8(1.0), 255);
+    }
+
+    #[test]
+    fn srgb_matches_known_values() {
+        assert_eq!(linear_to_srgb(0.0), 0.0);
+        assert!((linear_to_srgb(1.0) - 1.0).abs() < 1e-6);
+        assert!((linear_to_srgb(0.5) - 0.7354).abs() < 1e-3);
+        assert!((linear_to_srgb(0.0031308) - 0.04045).abs() < 1e-4);
+    }
+
+    fn solid(size: usize, x: f32, y: f32, z: f32) -> Vec<Vec3> {
+        vec![Vec3 { x, y, z }; size * size]
+    }
+
+    fn tile(frame: u64) -> TileAddr {
+        TileAddr {
+            frame,
+            level: TileLevel::Base,
+            x: 1,
+            y: 1,
+        }
+    }
+
+    fn rgb(pixel: Vec3) -> (f32, f32, f32) {
+        (pixel.x, pixel.y, pixel.z)
+    }
+
+    #[test]
+    fn multi_sampled_clients_blend_their_tiles() {
+        let mut blended = BlendBuffer::new();
+        let red = blended.blend(tile(1), &solid(TILE_SIZE, 1.0, 0.0, 0.0), 2);
+        assert!(red.iter().all(|&pixel| rgb(pixel) == (1.0, 0.0, 0.0)));
+        // A second client at two samples per pixel moves it half way
+        let averaged = blended.blend(tile(1), &solid(TILE_SIZE, 0.0, 0.0, 1.0), 2);
+        assert_eq!(averaged.len(), TILE_SIZE * TILE_SIZE);
+        assert!(averaged.iter().all(|&pixel| rgb(pixel) == (0.5, 0.0, 0.5)));
+        // Older frames are left out, and a newer one starts over
+        let stale = blended.blend(tile(0), &solid(TILE_SIZE, 0.0, 1.0, 0.0), 2);
+        assert!(stale.iter().all(|&pixel| rgb(pixel) == (0.5, 0.0, 0.5)));
+        let next = blended.blend(tile(2), &solid(TILE_SIZE, 0.0, 1.0, 0.0), 2);
+        assert!(next.iter().all(|&pixel| rgb(pixel) == (0.0, 1.0, 0.0)));
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{name}-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn jpeg_snapshots_keep_a_solid_color() {
+        let layout = FrameLayout::packed();
+        let data: Vec<u8> = [40, 120, 200, 0].repeat(WIDTH * HEIGHT);
+        assert_eq!(data.len(), layout.size);
+        let jpeg = encode_jpeg(&data, layout.offset, layout.stride, 85).unwrap();
+
+        let decoded = turbojpeg::decompress(&jpeg, turbojpeg::PixelFormat::RGB).unwrap();
+        assert_eq!((decoded.width, decoded.height), (WIDTH, HEIGHT));
+        for rgb in decoded.pixels.chunks_exact(3) {
+            for (&channel, expected) in rgb.iter().zip([200, 120, 40]) {
+                assert!(channel.abs_diff(expected) <= 2, "{rgb:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn snapshots_store_bgrx_pixels_as_rgb() {
+        let dir = temp_dir("png-snapshot");
+        fs::create_dir_all(&dir).unwrap();
+        // One pixel after a byte of padding, with its X byte set to show it's skipped
+        let data = [0xff, 10, 20, 30, 0xff];
+        let image = bgrx_to_rgb_image(&data, 1, 4, 1, 1);
+        write_snapshot(&dir, 3, image);
+
+        let path = snapshot_path(&dir, 3);
+        assert_eq!(path.file_name().unwrap(), "frame_00000003.png");
+        let decoded = image::open(&path).unwrap().to_rgb8();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(decoded.dimensions(), (1, 1));
+        assert_eq!(decoded.get_pixel(0, 0), &Rgb([30, 20, 10]));
+    }
+
+    #[test]
+    fn raw_frames_read_back() {
+        let dir = temp_dir("raw-frame-read-back");
+        fs::create_dir_all(&dir).unwrap();
+        // Padded like a GStreamer buffer, which the file leaves out
+        let (offset, stride) = (16, WIDTH * 4 + 32);
+        let mut data = vec![0; offset + HEIGHT * stride];
+        let (x, y) = (3, 5);
+        let pixel = offset + y * stride + x * 4;
+        data[pixel..pixel + 4].copy_from_slice(&[10, 20, 30, 0]);
+        let filename = raw_frame_path(&dir, 7);
+        write_raw_frame(&filename, &data, offset, stride).unwrap();
+
+        let file = fs::read(&filename).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(file.len(), 8 + WIDTH * HEIGHT * 4);
+        assert_eq!(
+            u32::from_le_bytes(file[0..4].try_into().unwrap()),
+            WIDTH as u32
+        );
+        assert_eq!(
+            u32::from_le_bytes(file[4..8].try_into().unwrap()),
+            HEIGHT as u32
+        );
+        let pixel = 8 + (y * WIDTH + x) * 4;
+        assert_eq!(file[pixel..pixel + 4], [10, 20, 30, 0]);
+        assert_eq!(file[pixel + 4..pixel + 8], [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn raw_frame_writer_keeps_the_latest_written_frames() {
+        let dir = temp_dir("raw-frame-keep");
+        let layout = FrameLayout::packed();
+        let writer = RawFrameWriter::new(dir.clone(), 2, layout.offset, layout.stride).unwrap();
+        // Gaps, as when frames are dropped or skipped
+        for frame in [0, 5, 9, 20] {
+            writer.write(frame, vec![0; layout.size]);
+        }
+        drop(writer);
+        let expected = vec![raw_frame_path(&dir, 9), raw_frame_path(&dir, 20)];
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let mut files: Vec<_> = fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect();
+            files.sort();
+            if files == expected {
+                break;
+            }
+            assert!(Instant::now() < deadline, "{files:?}");
+            thread::sleep(Duration::from_millis(10));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn srgb_round_trips() {
+        for i in 0..=1000 {
+            let c = i as f32 / 1000.0;
+            assert!((srgb_to_linear(linear_to_srgb(c)) - c).abs() < 1e-4, "{c}");
+        }
+    }
+
+    // Runs one playlist through a writer and returns the seconds from the
+    // stream start to each segment's program date
+    fn annotate(state: &Arc<Mutex<PlaylistState>>, name: &str, playlist: &str) -> Vec<f64> {
+        let filename = std::env::temp_dir().join(format!("playlist-{}-{name}.m3u8", process::id()));
+        let mut writer = PlaylistWriter {
+            filename: filename.to_string_lossy().into_owned(),
+            inner: Cursor::new(Vec::new()),
+            playlist_state: state.clone(),
+        };
+        writer.write_all(playlist.as_bytes()).unwrap();
+        drop(writer);
+        let output = fs::read_to_string(&filename).unwrap();
+        let _ = fs::remove_file(&filename);
+
+        let started_at = state.lock().unwrap().started_at;
+        let lines: Vec<&str> = output.lines().collect();
+        let mut starts = Vec::new();
+        for (i, line) in lines.iter().enumerate() {
+            if let Some(rest) = line.strip_prefix("#EXT-X-PROGRAM-DATE-TIME:") {
+                assert!(lines[i + 1].starts_with("#EXTINF:"));
+                let datetime = DateTime::parse_from_rfc3339(rest).unwrap();
+                let start = datetime.with_timezone(&Utc) - started_at;
+                starts.push(start.num_milliseconds() as f64 / 1000.0);
+            }
+        }
+        assert_eq!(
+            starts.len(),
+            lines
+                .iter()
+                .filter(|line| line.starts_with("#EXTINF:"))
+                .count()
+        );
+        starts
+    }
+
+    fn playlist_state() -> Arc<Mutex<PlaylistState>> {
+        Arc::new(Mutex::new(PlaylistState {
+            // Whole seconds, as dates are only written to the millisecond
+            started_at: Utc.timestamp_opt(1_600_000_000, 0).unwrap(),
+            last_sequence_no: 0,
+            total_elapsed: 0.0,
+            next_duration: 0.0,
+        }))
+    }
+
+    #[test]
+    fn playlist_dates_every_segment() {
+        let state = playlist_state();
+        let starts = annotate(
+            &state,
+            "single",
+            "#EXTM3U\n#EXT-X-MEDIA-SEQUENCE:1\n#EXTINF:2.000,\nsegment1.ts\n\
+             #EXTINF:2.500,\nsegment2.ts\n#EXTINF:2.000,\nsegment3.ts\n",
+        );
+        assert_eq!(starts, [0.0, 2.0, 4.5]);
+    }
+
+    #[test]
+    fn playlist_dates_carry_over_as_the_window_slides() {
+        let state = playlist_state();
+        let first = annotate(
+            &state,
+            "first",
+            "#EXTM3U\n#EXT-X-MEDIA-SEQUENCE:1\n#EXTINF:2.000,\nsegment1.ts\n\
+             #EXTINF:2.500,\nsegment2.ts\n",
+        );
+        let second = annotate(
+            &state,
+            "second",
+            "#EXTM3U\n#EXT-X-MEDIA-SEQUENCE:2\n#EXTINF:2.500,\nsegment2.ts\n\
+             #EXTINF:1.500,\nsegment3.ts\n",
+        );
+        assert_eq!(first, [0.0, 2.0]);
+        // The same segment keeps its date once the window moves past its predecessor
+        assert_eq!(second, [2.0, 4.5]);
+    }
 }
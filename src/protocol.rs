@@ -1,24 +1,82 @@
-use std::sync::Arc;
+use std::{
+    ops::{Add, Mul, Neg, Sub},
+    sync::Arc,
+};
 
-use serde::{Deserialize, Serialize};
+use serde::{
+    ser::{SerializeStruct, SerializeTupleVariant},
+    Deserialize, Serialize, Serializer,
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
     ReserveRays,
     SubmitResults(Vec<Result>),
     SetName(String),
+    SubmitDebugInfo(String),
+    ReserveRaysWithDeadline,
+    ReserveRaysParametric,
+    // Answered by the connection handler itself, without waiting on the server thread
+    Ping(u64),
+    SubmitResultsWithStats(Vec<Result>),
+    GetStats,
+    GetLeaderboard {
+        limit: u32,
+    },
+    // Hints used to size tile leases, clamped by the server
+    SetCapabilities {
+        estimated_rays_per_second: u64,
+        max_concurrent_tiles: u32,
+    },
+    // A smaller SubmitResults carrying colors only, see `unpack_results`
+    SubmitResultsPacked(Vec<u8>),
+    // Results without colors, as runs of (length, hit), see `expand_hit_mask`
+    SubmitHitMask(Vec<(u32, bool)>),
+    // Part of a tile's results, starting at pixel `offset` in row-major order.
+    // Chunks must arrive in order, and the tile completes with the last one.
+    SubmitPartialResults {
+        offset: u32,
+        results: Vec<Result>,
+    },
+    // Render this client's tiles from a different viewpoint
+    SetCamera(CameraView),
+    // The client is leaving. Its tiles are handed out again straight away,
+    // and the connection is closed after the reply.
+    Goodbye,
+    // The number of samples per pixel behind this client's results. Each of
+    // its tiles is blended into what's already on screen with a weight of
+    // 1 / samples, rather than replacing it. Capped at 16, and 0 counts as 1.
+    SetSamplesPerPixel(u8),
 }
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+// Written as `[x, y, z]` with the `json-compact` feature, which JSON and CBOR
+// clients must be built with too
+// This uses serde's `from`/`into`, as `serde_with` would need each field annotated
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "json-compact", serde(from = "[f32; 3]", into = "[f32; 3]"))]
 pub struct Vec3 {
     pub x: f32,
     pub y: f32,
     pub z: f32,
 }
 
+impl From<[f32; 3]> for Vec3 {
+    fn from([x, y, z]: [f32; 3]) -> Self {
+        Self { x, y, z }
+    }
+}
+
+impl From<Vec3> for [f32; 3] {
+    fn from(v: Vec3) -> Self {
+        [v.x, v.y, v.z]
+    }
+}
+
+// The server itself only needs a few of these, the rest are for client code
+// built against this module.
 impl Vec3 {
     pub fn length(&self) -> f32 {
-        (self.x * self.x + self.y * self.y + self.z * self.z).sqrt()
+        self.dot(*self).sqrt()
     }
     pub fn normalize(&mut self) {
         let scale = 1.0 / self.length();
@@ -26,6 +84,77 @@ impl Vec3 {
         self.y *= scale;
         self.z *= scale;
     }
+    #[inline]
+    pub fn dot(self, other: Vec3) -> f32 {
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+    #[inline]
+    pub fn cross(self, other: Vec3) -> Vec3 {
+        Vec3 {
+            x: self.y * other.z - self.z * other.y,
+            y: self.z * other.x - self.x * other.z,
+            z: self.x * other.y - self.y * other.x,
+        }
+    }
+    #[cfg(test)]
+    #[inline]
+    pub fn lerp(self, other: Vec3, t: f32) -> Vec3 {
+        self + (other - self) * t
+    }
+    // Mirrors the vector about a plane with the given unit normal
+    #[cfg(test)]
+    #[inline]
+    pub fn reflect(self, normal: Vec3) -> Vec3 {
+        self - normal * (2.0 * self.dot(normal))
+    }
+}
+
+impl Add for Vec3 {
+    type Output = Vec3;
+    #[inline]
+    fn add(self, other: Vec3) -> Vec3 {
+        Vec3 {
+            x: self.x + other.x,
+            y: self.y + other.y,
+            z: self.z + other.z,
+        }
+    }
+}
+
+impl Sub for Vec3 {
+    type Output = Vec3;
+    #[inline]
+    fn sub(self, other: Vec3) -> Vec3 {
+        Vec3 {
+            x: self.x - other.x,
+            y: self.y - other.y,
+            z: self.z - other.z,
+        }
+    }
+}
+
+impl Mul<f32> for Vec3 {
+    type Output = Vec3;
+    #[inline]
+    fn mul(self, scale: f32) -> Vec3 {
+        Vec3 {
+            x: self.x * scale,
+            y: self.y * scale,
+            z: self.z * scale,
+        }
+    }
+}
+
+impl Neg for Vec3 {
+    type Output = Vec3;
+    #[inline]
+    fn neg(self) -> Vec3 {
+        Vec3 {
+            x: -self.x,
+            y: -self.y,
+            z: -self.z,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -38,24 +167,709 @@ pub struct Ray {
 pub struct Sphere {
     pub center: Vec3,
     pub radius: f32,
+    // Light given off by the sphere itself, black for most spheres
+    #[serde(default)]
+    pub emissive: Vec3,
+}
+
+// `right` of a leaf node in `Scene::bvh_nodes`
+pub const BVH_LEAF: u32 = u32::MAX;
+
+// A node of `Scene::bvh_nodes`, which are stored depth-first from the root,
+// so the left child of an interior node is always the next node. Interior
+// nodes hold the indices of their two children, and leaves hold the index of
+// one sphere in `left`, with `right` set to `BVH_LEAF`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BvhNode {
+    pub left: u32,
+    pub right: u32,
+    pub aabb_min: Vec3,
+    pub aabb_max: Vec3,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Scene {
     pub frame: u64,
     pub spheres: Vec<Sphere>,
+    // Light reaching every surface regardless of direction, black if left out
+    #[serde(default)]
+    pub ambient: Vec3,
+    // Only sent with --send-bvh, and empty for scenes of fewer than four spheres
+    #[serde(default)]
+    pub bvh_nodes: Vec<BvhNode>,
 }
 
+// The ray through pixel (px, py), with (0, 0) the top-left of the whole
+// image, is computed in f32 as:
+//
+//     fx = px / image_width - 0.5
+//     fy = (py / image_height - 0.5) * image_height / image_width
+//     d = (fx * scale, fy * scale, 1.0)
+//     direction = d * (1.0 / sqrt(d.x * d.x + d.y * d.y + d.z * d.z))
+//
+// and every ray starts at `origin`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Camera {
+    pub origin: Vec3,
+    pub image_width: u32,
+    pub image_height: u32,
+    pub scale: f32,
+}
+
+// A viewpoint for `Request::SetCamera`. `fov_degrees` is the horizontal
+// field of view and must be strictly between 0 and 180. The image keeps the
+// size and layout of `Camera`, with +y pointing down the screen, so the
+// camera can't look straight up or down.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct CameraView {
+    pub origin: Vec3,
+    pub target: Vec3,
+    pub fov_degrees: f32,
+}
+
+// A tile's pixels in image coordinates. Results are expected row by row,
+// starting from (x, y).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TileRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+// Only `hit` and `color` were in the original version 2 layout. Version 2
+// clients send the rest only once they negotiate `FEATURE_EXTENDED_FIELDS`,
+// see `LegacyRequest`.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Result {
     pub hit: bool,
     #[serde(default)]
     pub color: Option<Vec3>,
+    // Distance along the ray to the hit, also accepted as `t`. Hits without a
+    // color or normal are shaded by this instead of being drawn flat white.
+    #[serde(default, alias = "t")]
+    pub depth: Option<f32>,
+    // Surface normal at the hit. Hits without a color are lit by a fixed
+    // light using this, and it needn't be unit length.
+    #[serde(default)]
+    pub normal: Option<Vec3>,
+    // Number of bounces traced for this ray, for statistics only. Results
+    // without it count as 0, which for version 2 means any client that
+    // didn't negotiate `FEATURE_EXTENDED_FIELDS`.
+    #[serde(default)]
+    pub ray_depth: u8,
+}
+
+// `Result` as version 2 clients sent it before depths, normals and ray depths
+// were added. Postcard has no field names to go by, so missing fields can't
+// default.
+#[derive(Deserialize)]
+#[serde(rename = "Result")]
+pub struct LegacyResult {
+    hit: bool,
+    color: Option<Vec3>,
+}
+
+impl From<LegacyResult> for Result {
+    fn from(result: LegacyResult) -> Self {
+        Self {
+            hit: result.hit,
+            color: result.color,
+            depth: None,
+            normal: None,
+            ray_depth: 0,
+        }
+    }
+}
+
+fn upgrade_results(results: Vec<LegacyResult>) -> Vec<Result> {
+    results.into_iter().map(Result::from).collect()
+}
+
+// `Request` with results in their original layout, as decoded for version 2
+// clients that haven't negotiated `FEATURE_EXTENDED_FIELDS`. The variants
+// must stay in the same order as `Request`'s.
+#[derive(Deserialize)]
+#[serde(rename = "Request")]
+pub enum LegacyRequest {
+    ReserveRays,
+    SubmitResults(Vec<LegacyResult>),
+    SetName(String),
+    SubmitDebugInfo(String),
+    ReserveRaysWithDeadline,
+    ReserveRaysParametric,
+    Ping(u64),
+    SubmitResultsWithStats(Vec<LegacyResult>),
+    GetStats,
+    GetLeaderboard {
+        limit: u32,
+    },
+    SetCapabilities {
+        estimated_rays_per_second: u64,
+        max_concurrent_tiles: u32,
+    },
+    SubmitResultsPacked(Vec<u8>),
+    SubmitHitMask(Vec<(u32, bool)>),
+    SubmitPartialResults {
+        offset: u32,
+        results: Vec<LegacyResult>,
+    },
+    SetCamera(CameraView),
+    Goodbye,
+    SetSamplesPerPixel(u8),
+}
+
+impl From<LegacyRequest> for Request {
+    fn from(request: LegacyRequest) -> Self {
+        match request {
+            LegacyRequest::ReserveRays => Request::ReserveRays,
+            LegacyRequest::SubmitResults(results) => {
+                Request::SubmitResults(upgrade_results(results))
+            }
+            LegacyRequest::SetName(name) => Request::SetName(name),
+            LegacyRequest::SubmitDebugInfo(info) => Request::SubmitDebugInfo(info),
+            LegacyRequest::ReserveRaysWithDeadline => Request::ReserveRaysWithDeadline,
+            LegacyRequest::ReserveRaysParametric => Request::ReserveRaysParametric,
+            LegacyRequest::Ping(n) => Request::Ping(n),
+            LegacyRequest::SubmitResultsWithStats(results) => {
+                Request::SubmitResultsWithStats(upgrade_results(results))
+            }
+            LegacyRequest::GetStats => Request::GetStats,
+            LegacyRequest::GetLeaderboard { limit } => Request::GetLeaderboard { limit },
+            LegacyRequest::SetCapabilities {
+                estimated_rays_per_second,
+                max_concurrent_tiles,
+            } => Request::SetCapabilities {
+                estimated_rays_per_second,
+                max_concurrent_tiles,
+            },
+            LegacyRequest::SubmitResultsPacked(data) => Request::SubmitResultsPacked(data),
+            LegacyRequest::SubmitHitMask(runs) => Request::SubmitHitMask(runs),
+            LegacyRequest::SubmitPartialResults { offset, results } => {
+                Request::SubmitPartialResults {
+                    offset,
+                    results: upgrade_results(results),
+                }
+            }
+            LegacyRequest::SetCamera(view) => Request::SetCamera(view),
+            LegacyRequest::Goodbye => Request::Goodbye,
+            LegacyRequest::SetSamplesPerPixel(samples) => Request::SetSamplesPerPixel(samples),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Response {
     ReserveRays(Arc<Vec<Ray>>, Arc<Scene>),
     SubmitResults,
-    SetName,
+    // The name the client ended up with, which has a numeric suffix if another
+    // connected client already had it. Only binary protocol version 2 and later
+    // include it, see `JsonResponse`.
+    SetName(String),
+    SubmitDebugInfoAck,
+    Error(String),
+    // Milliseconds left before the tile is reclaimed and given to another client
+    ReserveRaysWithDeadline(Arc<Vec<Ray>>, Arc<Scene>, u64),
+    ReserveRaysParametric(TileRect, Camera, Arc<Scene>),
+    Pong(u64),
+    // Pushed after a period of inactivity. Clients that want to stay connected
+    // while idle should answer with any request, such as a Ping.
+    Keepalive,
+    SubmitResultsWithStats(SubmitStats),
+    Stats {
+        total_tiles: u64,
+        avg_time: f64,
+        // Over the last minute
+        rays_per_second: f64,
+        // 1-based position on the leaderboard, or 0 before the first tile
+        rank: u32,
+        connected_clients: u32,
+    },
+    // The top entries in rank order, followed by the requesting client's own
+    // entry if it didn't make the cut
+    Leaderboard(Vec<LeaderboardEntry>),
+    SetCapabilities,
+    // Pushed to every client when an instructor broadcasts a message
+    Announcement(String),
+    // A chunk was accepted but the tile isn't complete yet
+    SubmitPartialResults,
+    SetCamera,
+    // Sent instead of rays when the server is too many frames ahead of the
+    // oldest unfinished one. Only servers run with --max-frames-ahead send
+    // this, and clients should wait a moment before asking again.
+    Throttled,
+    Goodbye,
+    SetSamplesPerPixel,
+}
+
+// Serializes a response for the JSON based encodings, which predate SetName
+// carrying a name and still send it as a unit variant
+pub struct JsonResponse<'a>(pub &'a Response);
+
+impl Serialize for JsonResponse<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self.0 {
+            Response::SetName(_) => serializer.serialize_unit_variant("Response", 2, "SetName"),
+            response => response.serialize(serializer),
+        }
+    }
+}
+
+// Serializes a response for version 2 clients that haven't negotiated
+// `FEATURE_EXTENDED_FIELDS`. Their scenes keep the original layout of just
+// the frame and each sphere's center and radius, since postcard has no
+// field names that would let them skip the rest.
+pub struct LegacyResponse<'a>(pub &'a Response);
+
+impl Serialize for LegacyResponse<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self.0 {
+            Response::ReserveRays(rays, scene) => {
+                let mut variant =
+                    serializer.serialize_tuple_variant("Response", 0, "ReserveRays", 2)?;
+                variant.serialize_field(rays)?;
+                variant.serialize_field(&LegacyScene(scene))?;
+                variant.end()
+            }
+            Response::ReserveRaysWithDeadline(rays, scene, millis) => {
+                let mut variant = serializer.serialize_tuple_variant(
+                    "Response",
+                    5,
+                    "ReserveRaysWithDeadline",
+                    3,
+                )?;
+                variant.serialize_field(rays)?;
+                variant.serialize_field(&LegacyScene(scene))?;
+                variant.serialize_field(millis)?;
+                variant.end()
+            }
+            Response::ReserveRaysParametric(rect, camera, scene) => {
+                let mut variant = serializer.serialize_tuple_variant(
+                    "Response",
+                    6,
+                    "ReserveRaysParametric",
+                    3,
+                )?;
+                variant.serialize_field(rect)?;
+                variant.serialize_field(camera)?;
+                variant.serialize_field(&LegacyScene(scene))?;
+                variant.end()
+            }
+            response => response.serialize(serializer),
+        }
+    }
+}
+
+struct LegacyScene<'a>(&'a Scene);
+
+impl Serialize for LegacyScene<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut scene = serializer.serialize_struct("Scene", 2)?;
+        scene.serialize_field("frame", &self.0.frame)?;
+        scene.serialize_field("spheres", &LegacySpheres(&self.0.spheres))?;
+        scene.end()
+    }
+}
+
+struct LegacySpheres<'a>(&'a [Sphere]);
+
+impl Serialize for LegacySpheres<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        // Postcard writes a tuple the same way as a struct of the same fields
+        serializer.collect_seq(self.0.iter().map(|sphere| (sphere.center, sphere.radius)))
+    }
+}
+
+fn packed_len(count: usize) -> usize {
+    count * 3 + count.div_ceil(8)
+}
+
+// The sRGB transfer function, from an encoded channel to linear light
+pub fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// Packed results are three bytes of sRGB per pixel, decoded to linear light
+// with `srgb_to_linear`, then one hit bit per pixel, least significant first.
+// Returns `None` if the length doesn't match any pixel count.
+pub fn unpack_results(data: &[u8]) -> Option<Vec<Result>> {
+    let max_count = data.len() * 8 / 25;
+    let count = (max_count.saturating_sub(1)..=max_count).find(|&n| packed_len(n) == data.len())?;
+    let (colors, hits) = data.split_at(count * 3);
+    Some(
+        colors
+            .chunks_exact(3)
+            .enumerate()
+            .map(|(i, rgb)| {
+                let hit = hits[i / 8] & (1 << (i % 8)) != 0;
+                Result {
+                    hit,
+                    color: hit.then(|| Vec3 {
+                        x: srgb_to_linear(rgb[0] as f32 / 255.0),
+                        y: srgb_to_linear(rgb[1] as f32 / 255.0),
+                        z: srgb_to_linear(rgb[2] as f32 / 255.0),
+                    }),
+                    depth: None,
+                    normal: None,
+                    ray_depth: 0,
+                }
+            })
+            .collect(),
+    )
+}
+
+// Expands runs of identical hit flags into results without colors, which
+// are drawn white or black. Returns `None` if the runs add up to more than
+// `max_len` results.
+pub fn expand_hit_mask(runs: &[(u32, bool)], max_len: usize) -> Option<Vec<Result>> {
+    let mut total = 0usize;
+    for &(len, _) in runs {
+        total = total.checked_add(len as usize).filter(|&n| n <= max_len)?;
+    }
+    let mut results = Vec::with_capacity(total);
+    for &(len, hit) in runs {
+        results.extend((0..len).map(|_| Result {
+            hit,
+            color: None,
+            depth: None,
+            normal: None,
+            ray_depth: 0,
+        }));
+    }
+    Some(results)
+}
+
+// Optional features, negotiated by binary clients sending a u64 of these bits
+// after their version. The server replies with the ones it supports.
+
+// Every frame is prefixed with a request ID, and results reuse the ID of the
+// ReserveRays their tile came from, so tiles can be submitted in any order
+pub const FEATURE_TAGGED: u64 = 1 << 0;
+// SubmitResultsPacked and SubmitHitMask are accepted
+pub const FEATURE_PACKED_RESULTS: u64 = 1 << 1;
+// SubmitPartialResults is accepted
+pub const FEATURE_PARTIAL_RESULTS: u64 = 1 << 2;
+// The client copes with unsolicited Keepalive and Announcement responses
+pub const FEATURE_PUSH: u64 = 1 << 3;
+// The client copes with Throttled in place of rays
+pub const FEATURE_THROTTLED: u64 = 1 << 4;
+// Version 2 bodies use the current layout, with the fields added since
+// version 2 first shipped. Without it they keep the original layout, so
+// existing postcard clients still decode. Other versions always use the
+// current layout.
+pub const FEATURE_EXTENDED_FIELDS: u64 = 1 << 5;
+
+pub const SUPPORTED_FEATURES: u64 = FEATURE_TAGGED
+    | FEATURE_PACKED_RESULTS
+    | FEATURE_PARTIAL_RESULTS
+    | FEATURE_PUSH
+    | FEATURE_THROTTLED
+    | FEATURE_EXTENDED_FIELDS;
+// Assumed for clients that don't negotiate, matching what the server did
+// before negotiation existed
+pub const LEGACY_FEATURES: u64 =
+    FEATURE_PACKED_RESULTS | FEATURE_PARTIAL_RESULTS | FEATURE_PUSH | FEATURE_THROTTLED;
+
+// Variant names, used to label statistics
+pub fn request_name(request: &Request) -> &'static str {
+    match request {
+        Request::ReserveRays => "ReserveRays",
+        Request::SubmitResults(_) => "SubmitResults",
+        Request::SetName(_) => "SetName",
+        Request::SubmitDebugInfo(_) => "SubmitDebugInfo",
+        Request::ReserveRaysWithDeadline => "ReserveRaysWithDeadline",
+        Request::ReserveRaysParametric => "ReserveRaysParametric",
+        Request::Ping(_) => "Ping",
+        Request::SubmitResultsWithStats(_) => "SubmitResultsWithStats",
+        Request::GetStats => "GetStats",
+        Request::GetLeaderboard { .. } => "GetLeaderboard",
+        Request::SetCapabilities { .. } => "SetCapabilities",
+        Request::SubmitResultsPacked(_) => "SubmitResultsPacked",
+        Request::SubmitHitMask(_) => "SubmitHitMask",
+        Request::SubmitPartialResults { .. } => "SubmitPartialResults",
+        Request::SetCamera(_) => "SetCamera",
+        Request::Goodbye => "Goodbye",
+        Request::SetSamplesPerPixel(_) => "SetSamplesPerPixel",
+    }
+}
+
+pub fn response_name(response: &Response) -> &'static str {
+    match response {
+        Response::ReserveRays(..) => "ReserveRays",
+        Response::SubmitResults => "SubmitResults",
+        Response::SetName(_) => "SetName",
+        Response::SubmitDebugInfoAck => "SubmitDebugInfoAck",
+        Response::Error(_) => "Error",
+        Response::ReserveRaysWithDeadline(..) => "ReserveRaysWithDeadline",
+        Response::ReserveRaysParametric(..) => "ReserveRaysParametric",
+        Response::Pong(_) => "Pong",
+        Response::Keepalive => "Keepalive",
+        Response::SubmitResultsWithStats(_) => "SubmitResultsWithStats",
+        Response::Stats { .. } => "Stats",
+        Response::Leaderboard(_) => "Leaderboard",
+        Response::SetCapabilities => "SetCapabilities",
+        Response::Announcement(_) => "Announcement",
+        Response::SubmitPartialResults => "SubmitPartialResults",
+        Response::SetCamera => "SetCamera",
+        Response::Throttled => "Throttled",
+        Response::Goodbye => "Goodbye",
+        Response::SetSamplesPerPixel => "SetSamplesPerPixel",
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubmitStats {
+    pub total_tiles: u64,
+    // Mean of the client's most recent tile times, in seconds
+    pub avg_time: f64,
+    // 1-based position on the leaderboard
+    pub rank: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LeaderboardEntry {
+    // 1-based
+    pub rank: u32,
+    pub name: String,
+    pub total_tiles: u64,
+    pub avg_time: f64,
+    pub connected: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(not(feature = "json-compact"))]
+    fn cbor(value: &impl Serialize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(value, &mut bytes).unwrap();
+        bytes
+    }
+
+    #[cfg(not(feature = "json-compact"))]
+    fn cbor_request_round_trips(fixture: &[u8]) -> Request {
+        let request: Request = ciborium::de::from_reader(fixture).unwrap();
+        assert_eq!(cbor(&request), fixture);
+        request
+    }
+
+    // The fixtures are written with `Vec3` as a map, so they only hold
+    // without the `json-compact` feature
+    #[cfg(not(feature = "json-compact"))]
+    #[test]
+    fn cbor_reserve_rays_response_fixture() {
+        let fixture = include_bytes!("../test-data/cbor/reserve_rays_response.cbor");
+        let response: Response = ciborium::de::from_reader(&fixture[..]).unwrap();
+        match &response {
+            Response::ReserveRays(rays, scene) => {
+                assert_eq!(rays.len(), 1);
+                assert_eq!(rays[0].origin.z, -350.0);
+                assert_eq!(rays[0].direction.y, 0.6);
+                assert_eq!(scene.frame, 7);
+                assert_eq!(scene.spheres.len(), 1);
+                assert_eq!(scene.spheres[0].radius, 4.0);
+                assert!(scene.bvh_nodes.is_empty());
+            }
+            other => panic!("Unexpected response {other:?}"),
+        }
+        assert_eq!(cbor(&response), fixture);
+    }
+
+    const VEC3: Vec3 = Vec3 {
+        x: 1.0,
+        y: 2.0,
+        z: 3.0,
+    };
+
+    fn xyz(v: Vec3) -> (f32, f32, f32) {
+        (v.x, v.y, v.z)
+    }
+
+    #[test]
+    fn reflect_mirrors_about_the_normal() {
+        let up = Vec3 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        };
+        let incoming = Vec3 {
+            x: 1.0,
+            y: -1.0,
+            z: 0.5,
+        };
+        assert_eq!(xyz(incoming.reflect(up)), (1.0, 1.0, 0.5));
+        // Grazing rays are unchanged, and head-on ones come straight back
+        let grazing = Vec3 {
+            x: 1.0,
+            y: 0.0,
+            z: 2.0,
+        };
+        assert_eq!(xyz(grazing.reflect(up)), xyz(grazing));
+        assert_eq!(xyz((-up).reflect(up)), xyz(up));
+        // Against a tilted plane, (1, -1) off a 45 degree normal goes back up the slope
+        let mut tilted = Vec3 {
+            x: -1.0,
+            y: 1.0,
+            z: 0.0,
+        };
+        tilted.normalize();
+        let reflected = Vec3 {
+            x: 1.0,
+            y: -1.0,
+            z: 0.0,
+        }
+        .reflect(tilted);
+        assert!((reflected.x + 1.0).abs() < 1e-6 && (reflected.y - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn cross_is_orthogonal_to_both_inputs() {
+        let a = VEC3;
+        let b = Vec3 {
+            x: -4.0,
+            y: 0.5,
+            z: 2.0,
+        };
+        let c = a.cross(b);
+        assert!(c.dot(a).abs() < 1e-5);
+        assert!(c.dot(b).abs() < 1e-5);
+        assert_eq!(xyz(b.cross(a)), xyz(-c));
+        let x = Vec3 {
+            x: 1.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        let y = Vec3 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        };
+        assert_eq!(xyz(x.cross(y)), (0.0, 0.0, 1.0));
+        assert_eq!(xyz(a.cross(a)), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn lerp_runs_between_the_ends() {
+        let start = Vec3::default();
+        assert_eq!(xyz(start.lerp(VEC3, 0.0)), xyz(start));
+        assert_eq!(xyz(start.lerp(VEC3, 0.5)), (0.5, 1.0, 1.5));
+        assert_eq!(xyz(start.lerp(VEC3, 1.0)), xyz(VEC3));
+    }
+
+    #[cfg(feature = "json-compact")]
+    #[test]
+    fn compact_json_writes_vectors_as_arrays() {
+        assert_eq!(serde_json::to_string(&VEC3).unwrap(), "[1.0,2.0,3.0]");
+        let vec: Vec3 = serde_json::from_str("[1.0,2.0,3.0]").unwrap();
+        assert_eq!((vec.x, vec.y, vec.z), (1.0, 2.0, 3.0));
+    }
+
+    #[cfg(not(feature = "json-compact"))]
+    #[test]
+    fn json_writes_vectors_as_objects() {
+        assert_eq!(
+            serde_json::to_string(&VEC3).unwrap(),
+            r#"{"x":1.0,"y":2.0,"z":3.0}"#
+        );
+    }
+
+    #[test]
+    fn lighting_round_trips_through_postcard() {
+        let gray = |v: f32| Vec3 { x: v, y: v, z: v };
+        let scene = Scene {
+            frame: 3,
+            spheres: vec![Sphere {
+                center: VEC3,
+                radius: 2.0,
+                emissive: Vec3 {
+                    x: 1.0,
+                    y: 0.5,
+                    z: 0.25,
+                },
+            }],
+            ambient: gray(0.1),
+            ..Scene::default()
+        };
+        let bytes = postcard::to_allocvec(&scene).unwrap();
+        let decoded: Scene = postcard::from_bytes(&bytes).unwrap();
+        let ambient = decoded.ambient;
+        assert_eq!((ambient.x, ambient.y, ambient.z), (0.1, 0.1, 0.1));
+        let emissive = decoded.spheres[0].emissive;
+        assert_eq!((emissive.x, emissive.y, emissive.z), (1.0, 0.5, 0.25));
+        assert_eq!(postcard::to_allocvec(&decoded).unwrap(), bytes);
+    }
+
+    #[test]
+    fn lighting_defaults_to_black_for_older_json() {
+        let older = serde_json::json!({
+            "frame": 1,
+            "spheres": [{"center": VEC3, "radius": 1.0}],
+        });
+        let scene: Scene = serde_json::from_value(older).unwrap();
+        let black = |v: Vec3| (v.x, v.y, v.z) == (0.0, 0.0, 0.0);
+        assert!(black(scene.ambient));
+        assert!(black(scene.spheres[0].emissive));
+    }
+
+    #[test]
+    fn hit_distances_can_be_sent_as_t() {
+        let result: Result = serde_json::from_str(r#"{"hit": true, "t": 3.5}"#).unwrap();
+        assert_eq!(result.depth, Some(3.5));
+    }
+
+    #[test]
+    fn packed_colors_are_decoded_as_srgb() {
+        // One hit pixel, then its hit bit
+        let results = unpack_results(&[0, 188, 255, 0b1]).unwrap();
+        let color = results[0].color.unwrap();
+        assert_eq!(color.x, 0.0);
+        assert!((color.y - 0.5).abs() < 0.005, "{}", color.y);
+        assert_eq!(color.z, 1.0);
+    }
+
+    #[test]
+    fn hit_masks_expand_run_by_run() {
+        let results = expand_hit_mask(&[(2, true), (0, false), (3, false), (1, true)], 6).unwrap();
+        let hits: Vec<_> = results.iter().map(|result| result.hit).collect();
+        assert_eq!(hits, [true, true, false, false, false, true]);
+        assert!(results.iter().all(|result| result.color.is_none()));
+        assert!(expand_hit_mask(&[], 6).unwrap().is_empty());
+    }
+
+    #[test]
+    fn hit_masks_longer_than_the_limit_are_refused() {
+        assert!(expand_hit_mask(&[(4, true), (3, false)], 6).is_none());
+        // Checked before allocating, and without overflowing
+        assert!(expand_hit_mask(&[(u32::MAX, true); 4], 6).is_none());
+    }
+
+    #[cfg(not(feature = "json-compact"))]
+    #[test]
+    fn cbor_request_fixtures() {
+        let request = cbor_request_round_trips(include_bytes!(
+            "../test-data/cbor/reserve_rays_request.cbor"
+        ));
+        assert!(matches!(request, Request::ReserveRays));
+        let request =
+            cbor_request_round_trips(include_bytes!("../test-data/cbor/set_name_request.cbor"));
+        assert!(matches!(request, Request::SetName(name) if name == "Alice"));
+        let request = cbor_request_round_trips(include_bytes!(
+            "../test-data/cbor/submit_results_request.cbor"
+        ));
+        match request {
+            Request::SubmitResults(results) => {
+                assert_eq!(results.len(), 2);
+                assert!(results[0].hit && !results[1].hit);
+                assert_eq!(results[0].depth, Some(12.5));
+                assert_eq!(results[0].ray_depth, 2);
+                assert!(results[1].color.is_none());
+            }
+            other => panic!("Unexpected request {other:?}"),
+        }
+    }
 }
@@ -0,0 +1,126 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, RwLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use notify::{RecursiveMode, Watcher};
+
+use crate::{load_scene, utils::SyncSenderExt, AdminCommand};
+
+// Editors write a file in several steps, so a change is only loaded once
+// the events for it have been quiet for this long
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+fn scene_dir(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(dir) if dir != Path::new("") => dir.to_owned(),
+        _ => PathBuf::from("."),
+    }
+}
+
+// Editors often save by replacing the file, so watch the directory rather
+// than the file itself, and look up the current scene path on every event
+// as it can be changed through the admin API.
+pub fn spawn(
+    scene_path: Arc<RwLock<PathBuf>>,
+    admin_tx: mpsc::SyncSender<AdminCommand>,
+) -> anyhow::Result<()> {
+    let mut dir = scene_dir(&scene_path.read().unwrap());
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+
+    thread::spawn(move || {
+        // When the scene file last changed, if that change is still to be loaded
+        let mut changed_at: Option<Instant> = None;
+        loop {
+            let res = match rx.recv_timeout(DEBOUNCE) {
+                Ok(res) => Some(res),
+                Err(mpsc::RecvTimeoutError::Timeout) => None,
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            };
+            let path = scene_path.read().unwrap().clone();
+            // The admin API may have moved the scene to another directory
+            let new_dir = scene_dir(&path);
+            if new_dir != dir {
+                let _ = watcher.unwatch(&dir);
+                if let Err(e) = watcher.watch(&new_dir, RecursiveMode::NonRecursive) {
+                    log::warn!("Error watching {}: {}", new_dir.display(), e);
+                }
+                dir = new_dir;
+            }
+            match res {
+                Some(Ok(event)) => {
+                    let file_name = path.file_name();
+                    if (event.kind.is_create() || event.kind.is_modify())
+                        && event.paths.iter().any(|p| p.file_name() == file_name)
+                    {
+                        changed_at = Some(Instant::now());
+                    }
+                }
+                Some(Err(e)) => log::warn!("Error watching scene file: {}", e),
+                None => {}
+            }
+            if changed_at.is_none_or(|at| at.elapsed() < DEBOUNCE) {
+                continue;
+            }
+            changed_at = None;
+            match load_scene(&path) {
+                Ok(scene_elements) => {
+                    log::info!(
+                        "Scene file {} changed, applying it from the next frame",
+                        path.display()
+                    );
+                    if admin_tx
+                        .send_realtime(
+                            AdminCommand::UpdateScene(scene_elements),
+                            "scene_watcher.admin_tx",
+                        )
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                Err(e) => log::warn!("Keeping the old scene: {:?}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn follows_the_scene_into_a_new_directory_and_waits_for_writes_to_settle() {
+        let (old_dir, new_dir) = (temp_dir("watch-old"), temp_dir("watch-new"));
+        let scene_path = Arc::new(RwLock::new(old_dir.join("scene.csv")));
+        let (admin_tx, admin_rx) = mpsc::sync_channel(4);
+        spawn(scene_path.clone(), admin_tx).unwrap();
+
+        *scene_path.write().unwrap() = new_dir.join("scene.csv");
+        // Give the watcher a turn to notice the move
+        thread::sleep(DEBOUNCE * 2);
+        let new_path = new_dir.join("scene.csv");
+        std::fs::write(&new_path, "x,y,z,r\n1,2,3,4\n").unwrap();
+        thread::sleep(DEBOUNCE / 5);
+        std::fs::write(&new_path, "x,y,z,r\n1,2,3,4\n5,6,7,8\n").unwrap();
+
+        match admin_rx.recv_timeout(Duration::from_secs(5)) {
+            Ok(AdminCommand::UpdateScene(scene_elements)) => assert_eq!(scene_elements.len(), 2),
+            _ => panic!("expected the finished scene"),
+        }
+        assert!(admin_rx.recv_timeout(DEBOUNCE * 3).is_err());
+        std::fs::remove_dir_all(old_dir).unwrap();
+        std::fs::remove_dir_all(new_dir).unwrap();
+    }
+}
@@ -1,85 +1,682 @@
 use std::{
-    collections::{HashMap, VecDeque},
-    sync::{mpsc, Arc},
+    cell::Cell,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fs, iter, mem,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, RwLock,
+    },
     time::{Duration, Instant},
 };
 
+use chrono::{DateTime, Utc};
+use ordered_float::NotNan;
 use rand::{distributions::Uniform, prelude::Distribution, thread_rng};
+use serde::{Deserialize, Serialize};
+use unicode_segmentation::UnicodeSegmentation;
 
 use crate::{
+    animation::AnimationScript,
+    ban_list::BanList,
+    bvh,
     client_id::ClientId,
+    leaderboard::{Leaderboard, LeaderboardEntry},
+    load_scene,
+    name_filter::NameFilter,
     output::{BlitTileEvent, OutputEvent},
-    protocol::{Ray, Request, Response, Scene, Sphere, Vec3},
-    utils::SyncSenderExt,
-    ClientCommand, ClientEvent, ClientEventPayload, SceneElement, TILES_X, TILES_Y, TILE_SIZE,
+    protocol::{
+        self, expand_hit_mask, unpack_results, Camera, CameraView, Ray, Request, Response, Scene,
+        Sphere, SubmitStats, TileRect, Vec3,
+    },
+    stats::{RenderTimeHistogram, SlidingCounter},
+    utils::{self, FileWriter, SyncSenderExt},
+    AdminCommand, ClientCommand, ClientEvent, ClientEventPayload, SceneElement, TILES_X, TILES_Y,
+    TILE_SIZE,
 };
 
 struct ClientState {
     name: String,
-    tx: mpsc::SyncSender<ClientCommand>,
+    addr: String,
+    connected_since: DateTime<Utc>,
+    total_tiles: u64,
+    tx: crossbeam_channel::Sender<ClientCommand>,
+    render_times: RenderTimeHistogram,
+    session_tiles: u64,
+    session_time: f64,
+    last_seen: Instant,
+    probed: bool,
+    max_ray_depth: u8,
+    ray_depth_sum: u64,
+    rays_submitted: u64,
+    recent_rays: SlidingCounter,
+    // Refreshed whenever this client's leaderboard entry changes
+    rank: u32,
+    capabilities: Option<Capabilities>,
+    // Smoothed from submitted tiles, including the round-trip
+    seconds_per_ray: Option<f64>,
+    // Smoothed from the round-trip times reported with each ping, in seconds
+    round_trip_time: Option<f64>,
+    // Used instead of the server's camera for this client's rays
+    camera: Option<CameraView>,
+    // Set when a response couldn't be delivered in time. A Cell so that it
+    // can be set wherever we only have shared access to the client.
+    too_slow: Cell<bool>,
+    // Negotiated in the handshake, see `protocol::SUPPORTED_FEATURES`
+    features: u64,
+    // From SetSamplesPerPixel, the weight of this client's tiles when blitted
+    samples_per_pixel: u8,
+}
+
+struct Capabilities {
+    rays_per_second: u64,
+    max_concurrent_tiles: u32,
+}
+
+impl ClientState {
+    // A client that stops reading its responses mustn't stall everyone else,
+    // so it is disconnected by `drop_slow_clients` instead
+    fn respond(&self, request_id: u32, response: Response) {
+        if self.too_slow.get() {
+            return;
+        }
+        // Not the inherent crossbeam method, so that backpressure is counted
+        if let Err(utils::SendTimeoutError::Timeout(_)) = SyncSenderExt::send_timeout(
+            &self.tx,
+            ClientCommand::Response(request_id, response),
+            RESPONSE_TIMEOUT,
+            "ServerState.clients.tx",
+        ) {
+            self.too_slow.set(true);
+        }
+    }
+    // Clients that haven't told us their speed get the configured timeout
+    fn tile_lease(&self, default: Duration, rays: usize) -> Duration {
+        match &self.capabilities {
+            Some(capabilities) => {
+                let expected = rays as f64 / capabilities.rays_per_second as f64;
+                Duration::from_secs_f64(expected * TILE_LEASE_MARGIN)
+                    .clamp(MIN_TILE_LEASE, MAX_TILE_LEASE)
+            }
+            None => default,
+        }
+    }
+    // Clients that told us their speed are taken at their word, otherwise
+    // go by how they've done so far
+    fn tile_level(&self) -> TileLevel {
+        let seconds_per_ray = match (&self.capabilities, self.seconds_per_ray) {
+            (Some(capabilities), _) => 1.0 / capabilities.rays_per_second as f64,
+            (None, Some(seconds_per_ray)) => seconds_per_ray,
+            (None, None) => return TileLevel::Base,
+        };
+        let base_tile_time = seconds_per_ray * (TILE_SIZE * TILE_SIZE) as f64;
+        if base_tile_time > SLOW_TILE_TIME {
+            TileLevel::Quarter
+        } else if base_tile_time < FAST_TILE_TIME {
+            TileLevel::Super
+        } else {
+            TileLevel::Base
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct PublicClientState {
+    id: ClientId,
+    name: String,
+    addr: String,
+    connected_since: String,
+    tiles_in_flight: usize,
+    total_tiles: u64,
+    p50_time: f64,
+    p95_time: f64,
+    p99_time: f64,
+    max_ray_depth: u8,
+    avg_ray_depth: f32,
+    round_trip_time: Option<f64>,
+}
+
+#[derive(Serialize, Default)]
+pub struct PublicServerState {
+    pub clients: Vec<PublicClientState>,
+    // Messages thrown away rather than waiting for a full channel
+    pub dropped_total: u64,
+}
+
+// Tiles come in three sizes so that slow clients get less work per tile and
+// fast ones spend less time on round-trips. Each level splits into four
+// tiles of the level below.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TileLevel {
+    Quarter,
+    Base,
+    Super,
+}
+
+impl TileLevel {
+    const ALL: [TileLevel; 3] = [Self::Quarter, Self::Base, Self::Super];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+    fn size(self) -> usize {
+        match self {
+            Self::Quarter => TILE_SIZE / 2,
+            Self::Base => TILE_SIZE,
+            Self::Super => TILE_SIZE * 2,
+        }
+    }
+    fn tiles_x(self) -> usize {
+        TILES_X * TILE_SIZE / self.size()
+    }
+    fn tiles_y(self) -> usize {
+        TILES_Y * TILE_SIZE / self.size()
+    }
+    fn smaller(self) -> Self {
+        match self {
+            Self::Quarter => panic!("Quarter tiles can't be split"),
+            Self::Base => Self::Quarter,
+            Self::Super => Self::Base,
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
 pub struct TileAddr {
     pub frame: u64,
+    pub level: TileLevel,
     pub x: usize,
     pub y: usize,
 }
 
 impl TileAddr {
     fn rays_index(&self) -> usize {
-        self.y * TILES_X + self.x
+        self.y * self.level.tiles_x() + self.x
+    }
+    /// Top-left corner and side length in pixels.
+    pub fn rect(&self) -> (usize, usize, usize) {
+        let size = self.level.size();
+        (self.x * size, self.y * size, size)
+    }
+    fn area(&self) -> usize {
+        self.level.size() * self.level.size()
+    }
+    /// Indices of the base sized tiles this tile overlaps.
+    pub fn base_cells(&self) -> impl Iterator<Item = usize> {
+        let (px, py, size) = self.rect();
+        let (x0, y0) = (px / TILE_SIZE, py / TILE_SIZE);
+        let cells = size.div_ceil(TILE_SIZE);
+        (y0..y0 + cells).flat_map(move |y| (x0..x0 + cells).map(move |x| y * TILES_X + x))
+    }
+    fn split(&self) -> [TileAddr; 4] {
+        let level = self.level.smaller();
+        let (x, y) = (self.x * 2, self.y * 2);
+        [(0, 0), (1, 0), (0, 1), (1, 1)].map(|(dx, dy)| TileAddr {
+            frame: self.frame,
+            level,
+            x: x + dx,
+            y: y + dy,
+        })
+    }
+}
+
+const MAX_DEBUG_INFO_LEN: usize = 1024;
+// In user-perceived characters, so emoji and accents count as one
+const MAX_NAME_GRAPHEMES: usize = 32;
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+const ADMIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// The public client list is rebuilt at most this often, rather than per tile
+const PUBLISH_INTERVAL: Duration = Duration::from_millis(250);
+// Depths at or beyond this distance are drawn black
+const MAX_VISUALIZED_DEPTH: f32 = 1000.0;
+// Hits without a color are shaded from white to gray across this range of
+// depths, which spans the scene as seen from the default camera
+const SHADED_DEPTH_NEAR: f32 = 300.0;
+const SHADED_DEPTH_FAR: f32 = 400.0;
+const SHADED_DEPTH_MIN_BRIGHTNESS: f32 = 0.2;
+// Unit vector towards the light used for hits with a normal but no color,
+// up and to the left of the camera (image y grows downwards)
+const LIGHT_DIRECTION: Vec3 = Vec3 {
+    x: -0.5,
+    y: -0.5,
+    z: -std::f32::consts::FRAC_1_SQRT_2,
+};
+const AMBIENT_LIGHT: f32 = 0.1;
+// World space direction that points down the screen for the server's camera
+const SCREEN_DOWN: Vec3 = Vec3 {
+    x: 0.0,
+    y: 1.0,
+    z: 0.0,
+};
+const RAYS_PER_SECOND_WINDOW: Duration = Duration::from_secs(60);
+const MAX_LEADERBOARD_LIMIT: u32 = 100;
+// Limits applied to the hints from SetCapabilities
+const MIN_RAYS_PER_SECOND: u64 = 1000;
+const MAX_RAYS_PER_SECOND: u64 = 1_000_000_000;
+const MAX_CONCURRENT_TILES: u32 = 16;
+const MAX_SAMPLES_PER_PIXEL: u8 = 16;
+// A tile may take this many times longer than the client's estimate
+const TILE_LEASE_MARGIN: f64 = 4.0;
+const MIN_TILE_LEASE: Duration = Duration::from_secs(1);
+const MAX_TILE_LEASE: Duration = Duration::from_secs(60);
+// Expected time for a base tile beyond which a client gets quarter tiles,
+// and below which it gets super tiles
+const SLOW_TILE_TIME: f64 = 2.0;
+const FAST_TILE_TIME: f64 = 0.25;
+const FRAME_AREA: usize = TILES_X * TILES_Y * TILE_SIZE * TILE_SIZE;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OutputChannel {
+    Color,
+    Depth,
+    Normal,
+}
+
+impl FromStr for OutputChannel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "color" => Ok(Self::Color),
+            "depth" => Ok(Self::Depth),
+            "normal" => Ok(Self::Normal),
+            _ => Err(anyhow::anyhow!("Unknown channel: {s}")),
+        }
+    }
+}
+
+// Names end up on the projector and in the dashboard's metadata, so keep
+// them to one short line. Bidi overrides are dropped too, as they can flip
+// the rest of the overlay, but right-to-left text itself is fine.
+fn sanitize_name(name: &str) -> String {
+    let cleaned: String = name
+        .chars()
+        .filter(|&c| {
+            !c.is_control() && !matches!(c, '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+        })
+        .collect();
+    let truncated: String = cleaned
+        .trim()
+        .graphemes(true)
+        .take(MAX_NAME_GRAPHEMES)
+        .collect();
+    let truncated = truncated.trim_end();
+    if truncated.is_empty() {
+        "Unnamed".into()
+    } else {
+        truncated.into()
+    }
+}
+
+// Two clients with the same name would share a line on the dashboard, so a
+// name another connected client already has gets the first free suffix
+fn unique_name(name: String, taken: &HashSet<&str>) -> String {
+    if !taken.contains(name.as_str()) {
+        return name;
+    }
+    (2..)
+        .map(|n| format!("{name} ({n})"))
+        .find(|candidate| !taken.contains(candidate.as_str()))
+        .unwrap()
+}
+
+// How the server's own rays leave the image plane. Per-client cameras
+// from `Request::SetCamera` are always perspective.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Projection {
+    Perspective,
+    // Parallel rays along +z, this many world units apart per pixel
+    Orthographic(f32),
+}
+
+// The `--projection` option. The orthographic scale comes from its own option.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ProjectionKind {
+    Perspective,
+    Orthographic,
+}
+
+impl FromStr for ProjectionKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "perspective" => Ok(Self::Perspective),
+            "orthographic" => Ok(Self::Orthographic),
+            _ => Err(anyhow::anyhow!("Unknown projection: {s}")),
+        }
+    }
+}
+
+// Moves the server's camera in a circle around the scene's center, turning
+// `radians_per_frame` each frame. Orbiting cameras are always perspective.
+#[derive(Debug, Copy, Clone)]
+pub struct CameraOrbit {
+    pub radius: f32,
+    // Above the center, which is towards -y since +y points down the screen
+    pub height: f32,
+    pub radians_per_frame: f32,
+}
+
+impl CameraOrbit {
+    // Starts from the default camera's side of the scene, at -z
+    fn view_at(&self, frame: u64, fov_degrees: f32) -> CameraView {
+        // In f64, so the angle stays accurate over long runs
+        let angle = (frame as f64 * self.radians_per_frame as f64) % std::f64::consts::TAU;
+        let (sin, cos) = (angle as f32).sin_cos();
+        CameraView {
+            origin: Vec3 {
+                x: self.radius * sin,
+                y: -self.height,
+                z: -self.radius * cos,
+            },
+            target: Vec3::default(),
+            fov_degrees,
+        }
+    }
+}
+
+// Clients often send normals that aren't unit length, so renormalize.
+// Returns `None` if there's no usable direction.
+fn lambert_shade(mut normal: Vec3) -> Option<f32> {
+    normal.normalize();
+    if !normal.length().is_finite() {
+        return None;
+    }
+    let diffuse = normal.dot(LIGHT_DIRECTION).max(0.0);
+    Some(AMBIENT_LIGHT + (1.0 - AMBIENT_LIGHT) * diffuse)
+}
+
+fn depth_shade(depth: f32) -> f32 {
+    let t = (depth - SHADED_DEPTH_NEAR) / (SHADED_DEPTH_FAR - SHADED_DEPTH_NEAR);
+    1.0 - t.clamp(0.0, 1.0) * (1.0 - SHADED_DEPTH_MIN_BRIGHTNESS)
+}
+
+impl OutputChannel {
+    fn to_pixel(self, result: protocol::Result) -> Vec3 {
+        const BLACK: Vec3 = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        match self {
+            Self::Color => {
+                if let Some(color) = result.color {
+                    color
+                } else if result.hit {
+                    // Shade with whatever we have, so the early exercises show
+                    // some shape rather than a flat silhouette
+                    let v = result
+                        .normal
+                        .and_then(lambert_shade)
+                        .or_else(|| result.depth.map(depth_shade))
+                        .unwrap_or(1.0);
+                    Vec3 { x: v, y: v, z: v }
+                } else {
+                    BLACK
+                }
+            }
+            Self::Depth => result.depth.map_or(BLACK, |depth| {
+                let v = 1.0 - depth / MAX_VISUALIZED_DEPTH;
+                Vec3 { x: v, y: v, z: v }
+            }),
+            Self::Normal => result.normal.map_or(BLACK, |normal| Vec3 {
+                x: normal.x * 0.5 + 0.5,
+                y: normal.y * 0.5 + 0.5,
+                z: normal.z * 0.5 + 0.5,
+            }),
+        }
+    }
+}
+
+impl ServerConfig {
+    // The longest a well behaved client may go without sending anything: while
+    // it renders a tile, or while idle until it is probed and then dropped
+    pub fn max_client_silence(&self) -> Duration {
+        let lease = self.tile_timeout.max(MAX_TILE_LEASE);
+        match self.idle_timeout {
+            Some(idle_timeout) => lease.max(idle_timeout * 2),
+            None => lease,
+        }
     }
 }
 
+pub struct ServerConfig {
+    pub send_bvh: bool,
+    pub tile_timeout: Duration,
+    pub debug_log_size: usize,
+    pub animation_script: Option<AnimationScript>,
+    pub frame_deadline: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    pub resume_from: Option<PathBuf>,
+    pub channel: OutputChannel,
+    pub diff_threshold: f32,
+    // Give every client base sized tiles, as before tile levels existed
+    pub uniform_tiles: bool,
+    pub ambient_light: Vec3,
+    // The server's camera, which clients without their own view render from
+    pub camera_origin: Vec3,
+    pub fov_degrees: f32,
+    pub projection: Projection,
+    pub camera_orbit: Option<CameraOrbit>,
+    // Stop starting new frames once this many are unfinished
+    pub max_frames_ahead: Option<u64>,
+    // Times every tile is rendered per frame, each with jittered rays
+    pub samples_per_tile: u32,
+    // Jitter every frame's rays, reproducibly for the same seed
+    pub jitter_seed: Option<u64>,
+    // Bumped on every pass of the server loop, for the watchdog
+    pub heartbeat: Arc<AtomicU64>,
+    // Shared with the accept loops, which refuse banned IPs
+    pub ban_list: BanList,
+    // From --name-blocklist
+    pub name_filter: Option<NameFilter>,
+}
+
+#[derive(Serialize)]
+pub struct DebugLogEntry {
+    timestamp: String,
+    name: String,
+    message: String,
+}
+
+// What the output thread last showed for a tile, used to skip unchanged tiles
+struct BlittedTile {
+    checksum: u64,
+    pixels: Vec<u8>,
+}
+
+fn quantize(pixels: &[Vec3]) -> Vec<u8> {
+    pixels
+        .iter()
+        .flat_map(|p| [p.x, p.y, p.z])
+        .map(|c| (c.clamp(0.0, 1.0) * 255.0) as u8)
+        .collect()
+}
+
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// Enough to carry on from the same frame after a restart. Tiles that were
+// pending or in flight are simply queued again.
+#[derive(Serialize, Deserialize)]
+struct ServerSnapshot {
+    current_frame: u64,
+    scene_elements: Vec<SceneElement>,
+    random_displacements: Vec<Vec3>,
+    leaderboard: Vec<LeaderboardEntry>,
+}
+
 struct InFlightTile {
     client_id: ClientId,
     addr: TileAddr,
     expires: Instant,
     requested_at: Instant,
+    lease: Duration,
+    // ID of the ReserveRays that reserved it, which results must reuse
+    reserved_by: u32,
+    // Results from SubmitPartialResults so far, and how many have arrived
+    partial: Vec<protocol::Result>,
+    received: usize,
+}
+
+// Leases differ between clients, so keep the soonest expiry first
+fn insert_in_flight(tiles: &mut VecDeque<InFlightTile>, tile: InFlightTile) {
+    let idx = tiles.partition_point(|other| other.expires <= tile.expires);
+    tiles.insert(idx, tile);
+}
+
+// Results name their tile by reusing the request ID of the ReserveRays that
+// reserved it, so pipelined tiles can be submitted in any order. Untagged
+// clients always send ID 0, so theirs go to the tile they've started sending
+// in parts, if any, and otherwise to their first reserved tile.
+fn current_tile_index(
+    tiles: &VecDeque<InFlightTile>,
+    client_id: ClientId,
+    request_id: u32,
+) -> Option<usize> {
+    let mut client_tiles = tiles
+        .iter()
+        .enumerate()
+        .filter(|(_, tile)| tile.client_id == client_id && tile.reserved_by == request_id);
+    let first = client_tiles.next()?;
+    Some(
+        iter::once(first)
+            .chain(client_tiles)
+            .find(|(_, tile)| tile.received > 0)
+            .unwrap_or(first)
+            .0,
+    )
 }
 
 struct ServerState {
-    rx: mpsc::Receiver<ClientEvent>,
+    rx: crossbeam_channel::Receiver<ClientEvent>,
+    admin_rx: mpsc::Receiver<AdminCommand>,
     tx: mpsc::SyncSender<OutputEvent>,
     clients: HashMap<ClientId, ClientState>,
     pending_tiles: VecDeque<TileAddr>,
     in_flight_tiles: VecDeque<InFlightTile>,
+    blitted_tiles: Vec<Option<BlittedTile>>,
     pending_frame: u64,
     current_frame: u64,
-    scene: Arc<Scene>,
-    all_rays: Vec<Arc<Vec<Ray>>>,
+    frame_deadline: Option<Instant>,
+    // The scene of each frame that may still have tiles handed out, as tiles
+    // requeued from an older frame must be rendered against its spheres
+    frame_scenes: BTreeMap<u64, Arc<Scene>>,
+    // Indexed by tile level, then by tile. Empty when the camera orbits.
+    all_rays: Vec<Vec<Arc<Vec<Ray>>>>,
+    // Jittered or orbiting rays, by frame, tile level and tile, built as
+    // tiles are issued
+    frame_rays: HashMap<(u64, usize, usize), Arc<Vec<Ray>>>,
+    // Pixels rendered so far for each unfinished frame
+    completed_area: HashMap<u64, usize>,
     random_displacements: Vec<Vec3>,
     scene_elements: Vec<SceneElement>,
+    pending_scene_elements: Option<Vec<SceneElement>>,
+    public_state: Arc<RwLock<PublicServerState>>,
+    leaderboard: Leaderboard,
+    // Saves the leaderboard and --resume-from snapshots as frames complete
+    file_writer: FileWriter,
+    debug_log: Arc<RwLock<VecDeque<DebugLogEntry>>>,
+    // Channels of slow clients that were dropped while full, still waiting
+    // for their Disconnect to fit
+    evicted: Vec<crossbeam_channel::Sender<ClientCommand>>,
+    // Set when something shown in the public state changes, cleared once `run`
+    // publishes it
+    state_changed: bool,
+    last_published: Instant,
+    camera: Camera,
+    config: ServerConfig,
+}
+
+fn make_camera(origin: Vec3, fov_degrees: f32) -> Camera {
+    Camera {
+        origin,
+        image_width: (TILES_X * TILE_SIZE) as u32,
+        image_height: (TILES_Y * TILE_SIZE) as u32,
+        scale: 2.0 * (fov_degrees.to_radians() / 2.0).tan(),
+    }
+}
+
+// Image plane offsets of a pixel, with the same scale on both axes so that
+// pixels stay square whatever the image's aspect ratio
+fn image_plane_offset(camera: &Camera, x: f32, y: f32) -> (f32, f32) {
+    let width = camera.image_width as f32;
+    let height = camera.image_height as f32;
+    (x / width - 0.5, (y / height - 0.5) * height / width)
 }
 
-fn generate_ray(x: usize, y: usize) -> Ray {
-    let fx = (x as f32) / ((TILES_X * TILE_SIZE) as f32) - 0.5;
-    let fy = (y as f32) / ((TILES_Y * TILE_SIZE) as f32) - 0.5;
+fn generate_ray(camera: &Camera, projection: Projection, x: usize, y: usize) -> Ray {
+    generate_ray_at(camera, projection, x as f32, y as f32)
+}
+
+fn generate_ray_at(camera: &Camera, projection: Projection, x: f32, y: f32) -> Ray {
+    match projection {
+        Projection::Perspective => generate_perspective_ray(camera, x, y),
+        Projection::Orthographic(units_per_pixel) => {
+            generate_orthographic_ray(camera, units_per_pixel, x, y)
+        }
+    }
+}
+
+// Must match the formula documented on `Camera`, which parametric clients rely on
+fn generate_perspective_ray(camera: &Camera, x: f32, y: f32) -> Ray {
+    let (fx, fy) = image_plane_offset(camera, x, y);
     let mut direction = Vec3 {
-        x: fx * 0.25,
-        y: fy * 0.25,
+        x: fx * camera.scale,
+        y: fy * camera.scale,
         z: 1.0,
     };
     direction.normalize();
     Ray {
-        origin: Vec3 {
+        origin: camera.origin,
+        direction,
+    }
+}
+
+// Centered on the camera's origin, so the image covers `image_width` times
+// `units_per_pixel` world units across
+fn generate_orthographic_ray(camera: &Camera, units_per_pixel: f32, x: f32, y: f32) -> Ray {
+    let (fx, fy) = image_plane_offset(camera, x, y);
+    let width = camera.image_width as f32 * units_per_pixel;
+    Ray {
+        origin: camera.origin
+            + Vec3 {
+                x: fx * width,
+                y: fy * width,
+                z: 0.0,
+            },
+        direction: Vec3 {
             x: 0.0,
             y: 0.0,
-            z: -350.0,
+            z: 1.0,
         },
-        direction,
     }
 }
 
-fn generate_all_rays() -> Vec<Arc<Vec<Ray>>> {
-    let mut res = Vec::with_capacity(TILES_X * TILES_Y);
-    for ty in 0..TILES_Y {
-        for tx in 0..TILES_X {
-            let mut rays = Vec::with_capacity(TILE_SIZE * TILE_SIZE);
-            for dy in 0..TILE_SIZE {
-                for dx in 0..TILE_SIZE {
-                    rays.push(generate_ray(tx * TILE_SIZE + dx, ty * TILE_SIZE + dy));
+fn generate_all_rays(
+    camera: &Camera,
+    projection: Projection,
+    level: TileLevel,
+) -> Vec<Arc<Vec<Ray>>> {
+    let size = level.size();
+    let mut res = Vec::with_capacity(level.tiles_x() * level.tiles_y());
+    for ty in 0..level.tiles_y() {
+        for tx in 0..level.tiles_x() {
+            let mut rays = Vec::with_capacity(size * size);
+            for dy in 0..size {
+                for dx in 0..size {
+                    rays.push(generate_ray(
+                        camera,
+                        projection,
+                        tx * size + dx,
+                        ty * size + dy,
+                    ));
                 }
             }
             res.push(Arc::new(rays));
@@ -87,6 +684,95 @@ fn generate_all_rays() -> Vec<Arc<Vec<Ray>>> {
     }
     res
 }
+
+// Uses the same image plane as `generate_perspective_ray`, with the field of view in
+// place of `Camera::scale`. Coordinates are in pixels, and may be fractional.
+fn generate_ray_for_camera(image: &Camera, camera: &CameraView, x: f32, y: f32) -> Ray {
+    let mut forward = camera.target - camera.origin;
+    forward.normalize();
+    let mut right = SCREEN_DOWN.cross(forward);
+    right.normalize();
+    let down = forward.cross(right);
+    let scale = 2.0 * (camera.fov_degrees.to_radians() / 2.0).tan();
+    let (fx, fy) = image_plane_offset(image, x, y);
+    let mut direction = forward + right * (fx * scale) + down * (fy * scale);
+    direction.normalize();
+    Ray {
+        origin: camera.origin,
+        direction,
+    }
+}
+
+fn validate_camera(camera: &CameraView) -> Result<(), String> {
+    if !(camera.fov_degrees > 0.0 && camera.fov_degrees < 180.0) {
+        return Err(format!(
+            "Field of view must be between 0 and 180 degrees, got {}",
+            camera.fov_degrees
+        ));
+    }
+    let mut forward = camera.target - camera.origin;
+    forward.normalize();
+    if !SCREEN_DOWN.cross(forward).length().is_normal() {
+        return Err(
+            "Camera target must differ from its origin, and not be straight up or down".into(),
+        );
+    }
+    Ok(())
+}
+
+// How rays are offset within their pixel, by up to half a pixel each way
+#[derive(Copy, Clone)]
+enum Jitter {
+    None,
+    // Different every time, so that averaging samples smooths the edges
+    Random,
+    // The same for a given seed, frame and pixel, whatever the tile size
+    Seeded(u64),
+}
+
+// SplitMix64's finalizer, enough to turn pixel coordinates into noise
+fn mix64(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+fn seeded_jitter(seed: u64, frame: u64, x: usize, y: usize) -> (f32, f32) {
+    let pixel = (y * TILES_X * TILE_SIZE + x) as u64;
+    let hash = mix64(mix64(seed ^ mix64(frame)) ^ pixel);
+    let unit = |bits: u64| (bits & 0xffffff) as f32 / (1 << 24) as f32 - 0.5;
+    (unit(hash), unit(hash >> 32))
+}
+
+// Rays for one issue of a tile, used when they can't come from `all_rays`
+fn generate_tile_rays(
+    image: &Camera,
+    projection: Projection,
+    addr: TileAddr,
+    camera: Option<&CameraView>,
+    jitter: Jitter,
+) -> Arc<Vec<Ray>> {
+    let (px, py, size) = addr.rect();
+    let mut rng = thread_rng();
+    let distr = Uniform::new(-0.5, 0.5);
+    let mut rays = Vec::with_capacity(size * size);
+    for y in py..py + size {
+        for x in px..px + size {
+            let (dx, dy) = match jitter {
+                Jitter::None => (0.0, 0.0),
+                Jitter::Random => (distr.sample(&mut rng), distr.sample(&mut rng)),
+                Jitter::Seeded(seed) => seeded_jitter(seed, addr.frame, x, y),
+            };
+            let (fx, fy) = (x as f32 + dx, y as f32 + dy);
+            rays.push(match camera {
+                Some(camera) => generate_ray_for_camera(image, camera, fx, fy),
+                None => generate_ray_at(image, projection, fx, fy),
+            });
+        }
+    }
+    Arc::new(rays)
+}
+
 fn generate_random_displacements(count: usize) -> Vec<Vec3> {
     let mut rng = thread_rng();
     let distr = Uniform::new_inclusive(-1.0, 1.0);
@@ -100,49 +786,168 @@ fn generate_random_displacements(count: usize) -> Vec<Vec3> {
 }
 
 impl ServerState {
+    #[allow(clippy::too_many_arguments)]
     fn new(
-        rx: mpsc::Receiver<ClientEvent>,
+        rx: crossbeam_channel::Receiver<ClientEvent>,
+        admin_rx: mpsc::Receiver<AdminCommand>,
         tx: mpsc::SyncSender<OutputEvent>,
         scene_elements: Vec<SceneElement>,
+        public_state: Arc<RwLock<PublicServerState>>,
+        leaderboard: Leaderboard,
+        debug_log: Arc<RwLock<VecDeque<DebugLogEntry>>>,
+        config: ServerConfig,
     ) -> Self {
+        let camera = make_camera(config.camera_origin, config.fov_degrees);
         Self {
             rx,
+            admin_rx,
             tx,
             clients: HashMap::new(),
             pending_tiles: VecDeque::new(),
             in_flight_tiles: VecDeque::new(),
+            blitted_tiles: (0..TILES_X * TILES_Y).map(|_| None).collect(),
             pending_frame: 1,
             current_frame: 0,
-            scene: Default::default(),
-            all_rays: generate_all_rays(),
+            frame_deadline: None,
+            frame_scenes: BTreeMap::new(),
+            // Uniform tiles never need the other levels
+            all_rays: TileLevel::ALL
+                .iter()
+                .map(|&level| {
+                    if config.camera_orbit.is_some()
+                        || (config.uniform_tiles && level != TileLevel::Base)
+                    {
+                        Vec::new()
+                    } else {
+                        generate_all_rays(&camera, config.projection, level)
+                    }
+                })
+                .collect(),
+            frame_rays: HashMap::new(),
+            completed_area: HashMap::new(),
             random_displacements: generate_random_displacements(scene_elements.len()),
             scene_elements,
+            pending_scene_elements: None,
+            public_state,
+            leaderboard,
+            file_writer: FileWriter::spawn(),
+            debug_log,
+            evicted: Vec::new(),
+            state_changed: true,
+            last_published: Instant::now(),
+            camera,
+            config,
+        }
+    }
+    fn mark_state_changed(&mut self) {
+        self.state_changed = true;
+    }
+    fn next_publish(&self) -> Option<Instant> {
+        self.state_changed
+            .then(|| self.last_published + PUBLISH_INTERVAL)
+    }
+    fn publish_state(&mut self) {
+        let mut tiles_in_flight = HashMap::new();
+        for tile in &self.in_flight_tiles {
+            *tiles_in_flight.entry(tile.client_id).or_insert(0) += 1;
         }
+        let clients = self
+            .clients
+            .iter()
+            .map(|(&id, client)| PublicClientState {
+                id,
+                name: client.name.clone(),
+                addr: client.addr.clone(),
+                connected_since: client
+                    .connected_since
+                    .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                tiles_in_flight: tiles_in_flight.get(&id).copied().unwrap_or(0),
+                total_tiles: client.total_tiles,
+                p50_time: client.render_times.p50(),
+                p95_time: client.render_times.p95(),
+                p99_time: client.render_times.p99(),
+                max_ray_depth: client.max_ray_depth,
+                avg_ray_depth: if client.rays_submitted > 0 {
+                    (client.ray_depth_sum as f64 / client.rays_submitted as f64) as f32
+                } else {
+                    0.0
+                },
+                round_trip_time: client.round_trip_time,
+            })
+            .collect();
+        *self.public_state.write().unwrap() = PublicServerState {
+            clients,
+            dropped_total: utils::dropped_total(),
+        };
+        self.state_changed = false;
+        self.last_published = Instant::now();
     }
-    fn pop_tile_addr(&mut self) -> TileAddr {
-        if let Some(addr) = self.pending_tiles.pop_front() {
-            addr
+    fn should_blit(prev: &[u8], new: &[Vec3], threshold: f32) -> bool {
+        prev.len() != new.len() * 3
+            || new
+                .iter()
+                .flat_map(|p| [p.x, p.y, p.z])
+                .zip(prev)
+                .any(|(c, &b)| (c.clamp(0.0, 1.0) - b as f32 / 255.0).abs() > threshold)
+    }
+    // The oldest frame that still has tiles waiting to be rendered or returned
+    fn oldest_unfinished_frame(&self) -> u64 {
+        self.pending_tiles
+            .iter()
+            .map(|addr| addr.frame)
+            .chain(self.in_flight_tiles.iter().map(|tile| tile.addr.frame))
+            .min()
+            .unwrap_or(self.pending_frame)
+    }
+    // Frames are queued as super tiles, and split down when a slower client
+    // asks. The pieces not handed out stay at the front of the queue. Tiles
+    // are never merged back up, so a fast client may get smaller tiles.
+    // Returns `None` rather than start a frame beyond `max_frames_ahead`
+    fn pop_tile_addr(&mut self, level: TileLevel) -> Option<TileAddr> {
+        if let Some(mut addr) = self.pending_tiles.pop_front() {
+            while addr.level.size() > level.size() {
+                let [first, rest @ ..] = addr.split();
+                for piece in rest.into_iter().rev() {
+                    self.pending_tiles.push_front(piece);
+                }
+                addr = first;
+            }
+            Some(addr)
         } else {
-            for y in 0..TILES_Y {
-                for x in 0..TILES_X {
-                    self.pending_tiles.push_back(TileAddr {
-                        frame: self.pending_frame,
-                        x,
-                        y,
-                    });
+            if let Some(max_frames_ahead) = self.config.max_frames_ahead {
+                if self.pending_frame - self.oldest_unfinished_frame() >= max_frames_ahead {
+                    return None;
+                }
+            }
+            let frame_level = if self.config.uniform_tiles {
+                TileLevel::Base
+            } else {
+                TileLevel::Super
+            };
+            // Samples are queued as whole passes over the frame, so the image
+            // refines evenly and one client rarely gets the same tile twice
+            for _ in 0..self.config.samples_per_tile {
+                for y in 0..frame_level.tiles_y() {
+                    for x in 0..frame_level.tiles_x() {
+                        self.pending_tiles.push_back(TileAddr {
+                            frame: self.pending_frame,
+                            level: frame_level,
+                            x,
+                            y,
+                        });
+                    }
                 }
             }
             self.pending_frame += 1;
-            self.pop_tile_addr()
+            self.pop_tile_addr(level)
         }
     }
-    fn regenerate_scene(&mut self) {
+    fn default_animation(&self) -> Vec<Sphere> {
         let time = self.current_frame as f32 * 0.008;
         let angle = time * 5.0;
         let displacement = 1000.0 / (1.0 + time.tan().powi(2)).powi(4);
         let (sa, ca) = angle.sin_cos();
-        let spheres = self
-            .scene_elements
+        self.scene_elements
             .iter()
             .enumerate()
             .map(|(i, elem)| {
@@ -154,136 +959,2561 @@ impl ServerState {
                         z: elem.z * ca - elem.x * sa + offset.z * displacement,
                     },
                     radius: elem.r + 1.0,
+                    emissive: elem.emissive(),
                 }
             })
+            .collect()
+    }
+    fn regenerate_scene(&mut self) {
+        let spheres = if let Some(animation_script) = &self.config.animation_script {
+            animation_script.apply(&self.scene_elements, self.current_frame)
+        } else {
+            self.default_animation()
+        };
+        // Elements stay in the scene file's order, which animation scripts
+        // refer to, but clients get spheres sorted by their unanimated x
+        let mut keyed: Vec<_> = self
+            .scene_elements
+            .iter()
+            .map(|elem| NotNan::new(elem.x).unwrap())
+            .zip(spheres)
             .collect();
-        self.scene = Arc::new(Scene {
+        keyed.sort_by_key(|(x, _)| *x);
+        let spheres: Vec<_> = keyed.into_iter().map(|(_, sphere)| sphere).collect();
+        let bvh_nodes = if self.config.send_bvh {
+            bvh::build(&spheres)
+        } else {
+            Vec::new()
+        };
+        let scene = Arc::new(Scene {
             frame: self.current_frame,
             spheres,
+            ambient: self.config.ambient_light,
+            bvh_nodes,
         });
+        self.frame_scenes.insert(self.current_frame, scene);
+        let oldest = self.oldest_unfinished_frame().min(self.current_frame);
+        self.frame_scenes.retain(|&frame, _| frame >= oldest);
+    }
+    fn scene_for(&self, frame: u64) -> Arc<Scene> {
+        self.frame_scenes
+            .get(&frame)
+            .or_else(|| self.frame_scenes.values().next_back())
+            .cloned()
+            .unwrap_or_default()
+    }
+    // Returns the whole tile's results once the last chunk is in. Each chunk
+    // extends the lease in proportion to its size, so a client making steady
+    // progress gets at most one extra lease.
+    fn add_partial_results(
+        &mut self,
+        client_id: ClientId,
+        request_id: u32,
+        offset: u32,
+        results: Vec<protocol::Result>,
+    ) -> Result<Option<Vec<protocol::Result>>, String> {
+        let idx = current_tile_index(&self.in_flight_tiles, client_id, request_id)
+            .ok_or_else(|| format!("No tile was reserved by request {}", request_id))?;
+        let tile = &mut self.in_flight_tiles[idx];
+        let area = tile.addr.area();
+        if offset as usize != tile.received {
+            return Err(format!(
+                "Expected results from offset {} but got {}",
+                tile.received, offset
+            ));
+        }
+        if results.is_empty() || results.len() > area - tile.received {
+            return Err(format!(
+                "A chunk of {} results doesn't fit in the {} left",
+                results.len(),
+                area - tile.received
+            ));
+        }
+        tile.received += results.len();
+        tile.partial.extend(results);
+        if tile.received == area {
+            return Ok(Some(mem::take(&mut tile.partial)));
+        }
+        let mut tile = self.in_flight_tiles.remove(idx).unwrap();
+        tile.expires += tile
+            .lease
+            .mul_f64((tile.received - offset as usize) as f64 / area as f64);
+        insert_in_flight(&mut self.in_flight_tiles, tile);
+        Ok(None)
     }
     fn disconnect_client(&mut self, client_id: ClientId) {
         self.clients.remove(&client_id);
+        let _ = self
+            .tx
+            .send_realtime(OutputEvent::ClientDisconnected(client_id), "ServerState.tx");
+        // Frames only finish once every pixel is in, so their tiles go back in the queue
+        let lost: Vec<TileAddr> = self
+            .in_flight_tiles
+            .iter()
+            .filter(|tile| tile.client_id == client_id)
+            .map(|tile| tile.addr)
+            .collect();
         self.in_flight_tiles
             .retain(|tile| tile.client_id != client_id);
+        for addr in lost.into_iter().rev() {
+            self.pending_tiles.push_front(addr);
+        }
+        self.mark_state_changed();
+    }
+    fn force_complete_frame(&mut self) {
+        self.frame_deadline = None;
+        let frame = self.current_frame;
+        let outstanding = self.pending_tiles.len() + self.in_flight_tiles.len();
+        self.pending_tiles.retain(|addr| addr.frame > frame);
+        self.in_flight_tiles.retain(|tile| tile.addr.frame > frame);
+        self.completed_area
+            .retain(|&area_frame, _| area_frame > frame);
+        let skipped = outstanding - (self.pending_tiles.len() + self.in_flight_tiles.len());
+        if skipped == 0 {
+            return;
+        }
+        log::warn!("Frame {frame} missed its deadline, skipping {skipped} tiles");
+        let _ = self
+            .tx
+            .send_realtime(OutputEvent::ForceCompleteFrame(frame), "ServerState.tx");
+        self.persist();
+        self.mark_state_changed();
+    }
+    fn snapshot(&self) -> Result<Vec<u8>, postcard::Error> {
+        postcard::to_allocvec(&ServerSnapshot {
+            current_frame: self.current_frame,
+            scene_elements: self.scene_elements.clone(),
+            random_displacements: self.random_displacements.clone(),
+            leaderboard: self.leaderboard.entries(),
+        })
+    }
+    // Runs as each frame completes, so a restart loses at most the frames
+    // still in progress
+    fn persist(&self) {
+        self.leaderboard.save(&self.file_writer);
+        if let Some(path) = &self.config.resume_from {
+            match self.snapshot() {
+                Ok(data) => self.file_writer.write(path.clone(), data),
+                Err(e) => log::warn!("Failed to save state to {}: {:?}", path.display(), e),
+            }
+        }
+    }
+    fn load_snapshot(&mut self, path: &Path) -> anyhow::Result<()> {
+        let snapshot: ServerSnapshot = postcard::from_bytes(&fs::read(path)?)?;
+        self.current_frame = snapshot.current_frame;
+        self.pending_frame = snapshot.current_frame + 1;
+        self.scene_elements = snapshot.scene_elements;
+        self.random_displacements = snapshot.random_displacements;
+        let mut leaderboard = snapshot.leaderboard;
+        if let Some(name_filter) = &self.config.name_filter {
+            name_filter.retain_allowed(&mut leaderboard);
+        }
+        self.leaderboard.restore(leaderboard);
+        self.regenerate_scene();
+        log::info!(
+            "Resumed from {} at frame {}",
+            path.display(),
+            self.current_frame
+        );
+        Ok(())
+    }
+    fn reload_scene(&mut self, path: &Path) -> anyhow::Result<()> {
+        self.scene_elements = load_scene(path)?;
+        self.pending_scene_elements = None;
+        self.random_displacements = generate_random_displacements(self.scene_elements.len());
+        // Frame numbers keep going up, as the output thread relies on that. The
+        // new scene starts with the next frame handed out, at `pending_frame`.
+        self.frame_deadline = None;
+        self.pending_tiles.clear();
+        // Results for the old scene are acknowledged but never blitted
+        self.in_flight_tiles.clear();
+        self.completed_area.clear();
+        self.frame_scenes.clear();
+        self.regenerate_scene();
+        self.mark_state_changed();
+        log::info!(
+            "Reloaded scene from {} ({} spheres)",
+            path.display(),
+            self.scene_elements.len()
+        );
+        Ok(())
+    }
+    fn handle_admin_command(&mut self, command: AdminCommand) {
+        match command {
+            AdminCommand::ReloadScene(path, reply_tx) => {
+                let _ = reply_tx.send(self.reload_scene(&path));
+            }
+            AdminCommand::UpdateScene(scene_elements) => {
+                self.pending_scene_elements = Some(scene_elements);
+            }
+            AdminCommand::Broadcast(message) => {
+                // Never wait on a slow client here, it would hold up everyone else
+                for client in self.clients.values() {
+                    if client.features & protocol::FEATURE_PUSH == 0 {
+                        continue;
+                    }
+                    let announcement = Response::Announcement(message.clone());
+                    client.tx.send_or_drop(
+                        ClientCommand::Response(0, announcement),
+                        "ServerState.clients.tx",
+                    );
+                }
+            }
+        }
+    }
+    fn drop_slow_clients(&mut self) {
+        let slow_clients: Vec<ClientId> = self
+            .clients
+            .iter()
+            .filter(|(_, client)| client.too_slow.get())
+            .map(|(&id, _)| id)
+            .collect();
+        for id in slow_clients {
+            log::warn!(
+                "Disconnecting client {:?}, it stopped reading responses",
+                id
+            );
+            self.evicted.push(self.clients[&id].tx.clone());
+            self.disconnect_client(id);
+        }
+        self.evicted.retain(|tx| {
+            matches!(
+                tx.try_send(ClientCommand::Disconnect(
+                    "Too slow reading responses".into()
+                )),
+                Err(crossbeam_channel::TrySendError::Full(_))
+            )
+        });
+    }
+    fn check_idle_clients(&mut self) {
+        let idle_timeout = match self.config.idle_timeout {
+            Some(idle_timeout) => idle_timeout,
+            None => return,
+        };
+        let now = Instant::now();
+        let mut idle_clients = Vec::new();
+        for (&id, client) in &mut self.clients {
+            // Rendering a tile counts as activity, however long it takes
+            if self.in_flight_tiles.iter().any(|tile| tile.client_id == id) {
+                client.last_seen = now;
+                client.probed = false;
+                continue;
+            }
+            let idle = now.duration_since(client.last_seen);
+            if idle >= idle_timeout * 2 {
+                let _ = client.tx.send_realtime(
+                    ClientCommand::Disconnect("Idle for too long".into()),
+                    "ServerState.clients.tx",
+                );
+                idle_clients.push(id);
+            } else if idle >= idle_timeout && !client.probed {
+                // Clients that can't take pushes just get the full timeout
+                if client.features & protocol::FEATURE_PUSH != 0 {
+                    client.respond(0, Response::Keepalive);
+                }
+                client.probed = true;
+            }
+        }
+        for id in idle_clients {
+            self.disconnect_client(id);
+        }
     }
     fn run(&mut self) {
         loop {
-            let res = if let Some(next_tile) = self.in_flight_tiles.front() {
-                self.rx
-                    .recv_timeout(next_tile.expires.duration_since(Instant::now()))
-            } else {
-                self.rx
-                    .recv()
-                    .map_err(|_| mpsc::RecvTimeoutError::Disconnected)
-            };
+            self.config.heartbeat.fetch_add(1, Ordering::Relaxed);
+            while let Ok(command) = self.admin_rx.try_recv() {
+                self.handle_admin_command(command);
+            }
+            self.check_idle_clients();
+            self.drop_slow_clients();
+            if self.next_publish().is_some_and(|at| at <= Instant::now()) {
+                self.publish_state();
+            }
+            // Admin commands arrive on their own channel, so wake up periodically to check it
+            let now = Instant::now();
+            let next_deadline = self
+                .in_flight_tiles
+                .front()
+                .map(|tile| tile.expires)
+                .into_iter()
+                .chain(self.frame_deadline)
+                .chain(self.next_publish())
+                .fold(now + ADMIN_POLL_INTERVAL, Instant::min);
+            let res = self
+                .rx
+                .recv_timeout(next_deadline.saturating_duration_since(now));
             let event = match res {
                 Ok(event) => event,
-                Err(mpsc::RecvTimeoutError::Timeout) => {
-                    let client_id = self.in_flight_tiles.front().unwrap().client_id;
-                    self.disconnect_client(client_id);
+                Err(crossbeam_channel::RecvTimeoutError::Timeout)
+                    if self
+                        .frame_deadline
+                        .is_some_and(|deadline| deadline <= Instant::now()) =>
+                {
+                    self.force_complete_frame();
                     continue;
                 }
-                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                Err(crossbeam_channel::RecvTimeoutError::Timeout)
+                    if self
+                        .in_flight_tiles
+                        .front()
+                        .is_some_and(|tile| tile.expires <= Instant::now()) =>
+                {
+                    // Hand the expired tile to someone else so the frame can still complete
+                    let expired_tile = self.in_flight_tiles.pop_front().unwrap();
+                    self.pending_tiles.push_front(expired_tile.addr);
+                    if let Some(client) = self.clients.get(&expired_tile.client_id) {
+                        let _ = client.tx.send_realtime(
+                            ClientCommand::Disconnect("Timed out rendering a tile".into()),
+                            "ServerState.clients.tx",
+                        );
+                    }
+                    self.disconnect_client(expired_tile.client_id);
+                    continue;
+                }
+                Err(crossbeam_channel::RecvTimeoutError::Timeout) => continue,
+                Err(crossbeam_channel::RecvTimeoutError::Disconnected) => break,
             };
+            if matches!(
+                event.payload,
+                ClientEventPayload::Request(..) | ClientEventPayload::Heartbeat(_)
+            ) {
+                if let Some(client) = self.clients.get_mut(&event.from_id) {
+                    client.last_seen = Instant::now();
+                    client.probed = false;
+                }
+            }
             match event.payload {
-                ClientEventPayload::Connected(tx) => {
+                ClientEventPayload::Heartbeat(rtt) => {
+                    if let (Some(client), Some(rtt)) = (self.clients.get_mut(&event.from_id), rtt) {
+                        let rtt = rtt.as_secs_f64();
+                        client.round_trip_time = Some(match client.round_trip_time {
+                            Some(prev) => prev * 0.8 + rtt * 0.2,
+                            None => rtt,
+                        });
+                    }
+                }
+                ClientEventPayload::Connected(tx, addr) => {
                     self.clients.insert(
                         event.from_id,
                         ClientState {
                             tx,
                             name: "Unnamed".into(),
+                            addr,
+                            connected_since: Utc::now(),
+                            total_tiles: 0,
+                            render_times: RenderTimeHistogram::default(),
+                            session_tiles: 0,
+                            session_time: 0.0,
+                            last_seen: Instant::now(),
+                            probed: false,
+                            max_ray_depth: 0,
+                            ray_depth_sum: 0,
+                            rays_submitted: 0,
+                            recent_rays: SlidingCounter::new(RAYS_PER_SECOND_WINDOW),
+                            rank: 0,
+                            capabilities: None,
+                            seconds_per_ray: None,
+                            round_trip_time: None,
+                            camera: None,
+                            too_slow: Cell::new(false),
+                            features: protocol::LEGACY_FEATURES,
+                            samples_per_pixel: 1,
                         },
                     );
+                    self.mark_state_changed();
                 }
                 ClientEventPayload::Disconnected => {
                     self.disconnect_client(event.from_id);
                 }
-                ClientEventPayload::Request(Request::ReserveRays) => {
-                    let addr = self.pop_tile_addr();
+                ClientEventPayload::SetFeatures(features) => {
+                    if let Some(client) = self.clients.get_mut(&event.from_id) {
+                        client.features = features;
+                    }
+                }
+                ClientEventPayload::Kick(ban, reply_tx) => {
+                    // The handler unregisters itself once it sees the command
+                    let found = if let Some(client) = self.clients.get(&event.from_id) {
+                        if let Some(duration) = ban {
+                            // Unix socket clients have no IP, so they can only be kicked
+                            match client.addr.parse::<SocketAddr>() {
+                                Ok(addr) => {
+                                    log::info!("Banned {} for {:?}", addr.ip(), duration);
+                                    self.config.ban_list.ban(addr.ip(), duration);
+                                }
+                                Err(_) => log::warn!(
+                                    "Client ({:?} - {}) has no IP to ban",
+                                    event.from_id,
+                                    client.addr
+                                ),
+                            }
+                        }
+                        let reason = if ban.is_some() {
+                            "Banned by admin"
+                        } else {
+                            "Kicked by admin"
+                        };
+                        let _ = client.tx.send_realtime(
+                            ClientCommand::Disconnect(reason.into()),
+                            "ClientState.tx",
+                        );
+                        true
+                    } else {
+                        false
+                    };
+                    let _ = reply_tx.send(found);
+                }
+                ClientEventPayload::Request(
+                    request_id,
+                    request @ (Request::ReserveRays
+                    | Request::ReserveRaysWithDeadline
+                    | Request::ReserveRaysParametric),
+                ) => {
+                    let level = if let Some(client) = self.clients.get(&event.from_id) {
+                        // `Camera` only describes perspective rays from a fixed origin
+                        if matches!(request, Request::ReserveRaysParametric)
+                            && (self.config.projection != Projection::Perspective
+                                || self.config.camera_orbit.is_some())
+                        {
+                            client.respond(
+                                request_id,
+                                Response::Error(
+                                    "Parametric rays need a fixed perspective camera".into(),
+                                ),
+                            );
+                            continue;
+                        }
+                        if let Some(capabilities) = &client.capabilities {
+                            let in_flight = self
+                                .in_flight_tiles
+                                .iter()
+                                .filter(|tile| tile.client_id == event.from_id)
+                                .count();
+                            if in_flight >= capabilities.max_concurrent_tiles as usize {
+                                client.respond(
+                                    request_id,
+                                    Response::Error(format!(
+                                        "At most {} tiles may be reserved at once",
+                                        capabilities.max_concurrent_tiles
+                                    )),
+                                );
+                                continue;
+                            }
+                        }
+                        if self.config.uniform_tiles {
+                            TileLevel::Base
+                        } else {
+                            client.tile_level()
+                        }
+                    } else {
+                        continue;
+                    };
+                    let addr = if let Some(addr) = self.pop_tile_addr(level) {
+                        addr
+                    } else {
+                        if let Some(client) = self.clients.get(&event.from_id) {
+                            let response = if client.features & protocol::FEATURE_THROTTLED != 0 {
+                                Response::Throttled
+                            } else {
+                                Response::Error("Too many frames in progress, try again".into())
+                            };
+                            client.respond(request_id, response);
+                        }
+                        continue;
+                    };
                     if addr.frame > self.current_frame {
                         self.current_frame = addr.frame;
+                        // Requeued tiles from older frames just regenerate the same rays
+                        let current_frame = self.current_frame;
+                        self.frame_rays
+                            .retain(|&(frame, _, _), _| frame >= current_frame);
+                        self.frame_deadline = self
+                            .config
+                            .frame_deadline
+                            .map(|deadline| Instant::now() + deadline);
+                        if let Some(scene_elements) = self.pending_scene_elements.take() {
+                            self.random_displacements =
+                                generate_random_displacements(scene_elements.len());
+                            self.scene_elements = scene_elements;
+                        }
                         self.regenerate_scene();
                     }
+                    let scene = self.scene_for(addr.frame);
                     if let Some(client) = self.clients.get_mut(&event.from_id) {
                         let now = Instant::now();
-                        self.in_flight_tiles.push_back(InFlightTile {
-                            client_id: event.from_id,
-                            addr,
-                            expires: now + Duration::from_secs(5),
-                            requested_at: now,
-                        });
-                        let _ = client.tx.send_realtime(
-                            ClientCommand::Response(Response::ReserveRays(
-                                self.all_rays[addr.rays_index()].clone(),
-                                self.scene.clone(),
-                            )),
-                            "ServerState.clients.tx",
+                        let lease = client.tile_lease(self.config.tile_timeout, addr.area());
+                        insert_in_flight(
+                            &mut self.in_flight_tiles,
+                            InFlightTile {
+                                client_id: event.from_id,
+                                addr,
+                                expires: now + lease,
+                                requested_at: now,
+                                lease,
+                                reserved_by: request_id,
+                                partial: Vec::new(),
+                                received: 0,
+                            },
                         );
+                        // Parametric clients compute their own rays from `self.camera`, so
+                        // their rays aren't jittered and custom cameras don't apply
+                        let jitter = if self.config.samples_per_tile > 1 {
+                            Jitter::Random
+                        } else if let Some(seed) = self.config.jitter_seed {
+                            Jitter::Seeded(seed)
+                        } else {
+                            Jitter::None
+                        };
+                        let orbit = self
+                            .config
+                            .camera_orbit
+                            .map(|orbit| orbit.view_at(addr.frame, self.config.fov_degrees));
+                        let rays = match (&client.camera, jitter) {
+                            (None, Jitter::None) if orbit.is_none() => {
+                                self.all_rays[addr.level.index()][addr.rays_index()].clone()
+                            }
+                            (None, Jitter::None | Jitter::Seeded(_)) => self
+                                .frame_rays
+                                .entry((addr.frame, addr.level.index(), addr.rays_index()))
+                                .or_insert_with(|| {
+                                    generate_tile_rays(
+                                        &self.camera,
+                                        self.config.projection,
+                                        addr,
+                                        orbit.as_ref(),
+                                        jitter,
+                                    )
+                                })
+                                .clone(),
+                            (camera, jitter) => generate_tile_rays(
+                                &self.camera,
+                                self.config.projection,
+                                addr,
+                                camera.as_ref().or(orbit.as_ref()),
+                                jitter,
+                            ),
+                        };
+                        let (x, y, size) = addr.rect();
+                        let response = match request {
+                            Request::ReserveRaysWithDeadline => Response::ReserveRaysWithDeadline(
+                                rays,
+                                scene,
+                                lease.as_millis() as u64,
+                            ),
+                            Request::ReserveRaysParametric => Response::ReserveRaysParametric(
+                                TileRect {
+                                    x: x as u32,
+                                    y: y as u32,
+                                    width: size as u32,
+                                    height: size as u32,
+                                },
+                                self.camera.clone(),
+                                scene,
+                            ),
+                            _ => Response::ReserveRays(rays, scene),
+                        };
+                        client.respond(request_id, response);
+                    }
+                }
+                ClientEventPayload::Request(request_id, Request::Ping(timestamp)) => {
+                    // Handlers normally answer pings themselves
+                    if let Some(client) = self.clients.get(&event.from_id) {
+                        client.respond(request_id, Response::Pong(timestamp));
                     }
                 }
-                ClientEventPayload::Request(Request::SetName(name)) => {
+                ClientEventPayload::Request(request_id, Request::GetStats) => {
+                    // Clients may poll this, so it only reads per-client aggregates
+                    let connected_clients = self.clients.len() as u32;
                     if let Some(client) = self.clients.get_mut(&event.from_id) {
-                        let _ = client.tx.send_realtime(
-                            ClientCommand::Response(Response::SetName),
-                            "ServerState.clients.tx",
-                        );
-                        client.name = name;
+                        let response = Response::Stats {
+                            total_tiles: client.total_tiles,
+                            avg_time: client.render_times.mean(),
+                            rays_per_second: client.recent_rays.per_second(Instant::now()),
+                            rank: client.rank,
+                            connected_clients,
+                        };
+                        client.respond(request_id, response);
                     }
                 }
-                ClientEventPayload::Request(Request::SubmitResults(results)) => {
+                ClientEventPayload::Request(request_id, Request::GetLeaderboard { limit }) => {
+                    if let Some(client) = self.clients.get(&event.from_id) {
+                        let connected: HashSet<&str> =
+                            self.clients.values().map(|c| c.name.as_str()).collect();
+                        let to_protocol =
+                            |rank: u32, entry: LeaderboardEntry| protocol::LeaderboardEntry {
+                                rank,
+                                connected: connected.contains(entry.name.as_str()),
+                                name: entry.name,
+                                total_tiles: entry.total_tiles,
+                                avg_time: entry.best_avg_time,
+                            };
+                        let limit = limit.min(MAX_LEADERBOARD_LIMIT);
+                        let mut entries: Vec<_> = self
+                            .leaderboard
+                            .top(limit as usize)
+                            .into_iter()
+                            .zip(1..)
+                            .map(|(entry, rank)| to_protocol(rank, entry))
+                            .collect();
+                        if let Some((rank, entry)) = self.leaderboard.find(&client.name) {
+                            if rank > limit {
+                                entries.push(to_protocol(rank, entry));
+                            }
+                        }
+                        client.respond(request_id, Response::Leaderboard(entries));
+                    }
+                }
+                ClientEventPayload::Request(
+                    request_id,
+                    Request::SetCapabilities {
+                        estimated_rays_per_second,
+                        max_concurrent_tiles,
+                    },
+                ) => {
                     if let Some(client) = self.clients.get_mut(&event.from_id) {
-                        let _ = client.tx.send_realtime(
-                            ClientCommand::Response(Response::SubmitResults),
-                            "ServerState.clients.tx",
-                        );
-                        if let Some(idx) = self
-                            .in_flight_tiles
+                        client.capabilities = Some(Capabilities {
+                            rays_per_second: estimated_rays_per_second
+                                .clamp(MIN_RAYS_PER_SECOND, MAX_RAYS_PER_SECOND),
+                            max_concurrent_tiles: max_concurrent_tiles
+                                .clamp(1, MAX_CONCURRENT_TILES),
+                        });
+                        client.respond(request_id, Response::SetCapabilities);
+                    }
+                }
+                ClientEventPayload::Request(request_id, Request::SetSamplesPerPixel(samples)) => {
+                    if let Some(client) = self.clients.get_mut(&event.from_id) {
+                        client.samples_per_pixel = samples.clamp(1, MAX_SAMPLES_PER_PIXEL);
+                        client.respond(request_id, Response::SetSamplesPerPixel);
+                    }
+                }
+                ClientEventPayload::Request(request_id, Request::Goodbye) => {
+                    if let Some(client) = self.clients.get(&event.from_id) {
+                        log::info!("Client ({:?} - {}) - Goodbye", event.from_id, client.name);
+                        client.respond(request_id, Response::Goodbye);
+                    }
+                    // The handler won't send Disconnected after this
+                    self.disconnect_client(event.from_id);
+                }
+                ClientEventPayload::Request(request_id, Request::SetCamera(camera)) => {
+                    if let Some(client) = self.clients.get_mut(&event.from_id) {
+                        match validate_camera(&camera) {
+                            Ok(()) => {
+                                client.camera = Some(camera);
+                                client.respond(request_id, Response::SetCamera);
+                            }
+                            Err(message) => client.respond(request_id, Response::Error(message)),
+                        }
+                    }
+                }
+                ClientEventPayload::Request(request_id, Request::SetName(name)) => {
+                    let name = {
+                        // Names are freed as soon as their client disconnects
+                        let taken: HashSet<&str> = self
+                            .clients
                             .iter()
-                            .position(|x| x.client_id == event.from_id)
+                            .filter(|&(&id, _)| id != event.from_id)
+                            .map(|(_, c)| c.name.as_str())
+                            .collect();
+                        let mut name = sanitize_name(&name);
+                        // Replaced rather than refused, so the client keeps working
+                        if let Some(name_filter) = &self.config.name_filter {
+                            if name_filter.is_blocked(&name) {
+                                log::info!(
+                                    "Client ({:?}) - Blocked name {:?}",
+                                    event.from_id,
+                                    name
+                                );
+                                name = format!("Client #{}", event.from_id.0);
+                            }
+                        }
+                        unique_name(name, &taken)
+                    };
+                    if let Some(client) = self.clients.get_mut(&event.from_id) {
+                        client.respond(request_id, Response::SetName(name.clone()));
+                        // Scores stay with the old name, the new name starts from scratch
+                        client.rank = self.leaderboard.rank(&name).unwrap_or(0);
+                        client.name = name;
+                        client.session_tiles = 0;
+                        client.session_time = 0.0;
+                    }
+                    self.mark_state_changed();
+                }
+                ClientEventPayload::Request(request_id, Request::SubmitDebugInfo(message)) => {
+                    if let Some(client) = self.clients.get_mut(&event.from_id) {
+                        if message.len() > MAX_DEBUG_INFO_LEN {
+                            client.respond(
+                                request_id,
+                                Response::Error(format!(
+                                    "Debug info exceeds {MAX_DEBUG_INFO_LEN} bytes"
+                                )),
+                            );
+                            continue;
+                        }
+                        log::info!(
+                            "Client ({:?} - {}) - Debug info: {}",
+                            event.from_id,
+                            client.name,
+                            message
+                        );
+                        let mut debug_log = self.debug_log.write().unwrap();
+                        if debug_log.len() >= self.config.debug_log_size {
+                            debug_log.pop_front();
+                        }
+                        debug_log.push_back(DebugLogEntry {
+                            timestamp: Utc::now()
+                                .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+                            name: client.name.clone(),
+                            message,
+                        });
+                        client.respond(request_id, Response::SubmitDebugInfoAck);
+                    }
+                }
+                ClientEventPayload::Request(
+                    request_id,
+                    request @ (Request::SubmitResults(_)
+                    | Request::SubmitResultsWithStats(_)
+                    | Request::SubmitResultsPacked(_)
+                    | Request::SubmitHitMask(_)
+                    | Request::SubmitPartialResults { .. }),
+                ) => {
+                    let (results, with_stats) = match request {
+                        Request::SubmitResults(results) => (results, false),
+                        Request::SubmitResultsWithStats(results) => (results, true),
+                        Request::SubmitResultsPacked(data) => match unpack_results(&data) {
+                            Some(results) => (results, false),
+                            None => {
+                                if let Some(client) = self.clients.get(&event.from_id) {
+                                    client.respond(
+                                        request_id,
+                                        Response::Error(format!(
+                                            "Packed results of {} bytes are malformed",
+                                            data.len()
+                                        )),
+                                    );
+                                }
+                                continue;
+                            }
+                        },
+                        // The exact length is checked against the tile below
+                        Request::SubmitHitMask(runs) => {
+                            let max_len = TileLevel::Super.size() * TileLevel::Super.size();
+                            match expand_hit_mask(&runs, max_len) {
+                                Some(results) => (results, false),
+                                None => {
+                                    if let Some(client) = self.clients.get(&event.from_id) {
+                                        client.respond(
+                                            request_id,
+                                            Response::Error(format!(
+                                                "Hit mask is longer than {} results",
+                                                max_len
+                                            )),
+                                        );
+                                    }
+                                    continue;
+                                }
+                            }
+                        }
+                        Request::SubmitPartialResults { offset, results } => {
+                            match self.add_partial_results(
+                                event.from_id,
+                                request_id,
+                                offset,
+                                results,
+                            ) {
+                                Ok(Some(results)) => (results, false),
+                                Ok(None) => {
+                                    if let Some(client) = self.clients.get(&event.from_id) {
+                                        client.respond(request_id, Response::SubmitPartialResults);
+                                    }
+                                    continue;
+                                }
+                                Err(message) => {
+                                    if let Some(client) = self.clients.get(&event.from_id) {
+                                        client.respond(request_id, Response::Error(message));
+                                    }
+                                    continue;
+                                }
+                            }
+                        }
+                        _ => unreachable!(),
+                    };
+                    if let Some(client) = self.clients.get_mut(&event.from_id) {
+                        if let Some(idx) =
+                            current_tile_index(&self.in_flight_tiles, event.from_id, request_id)
                         {
                             let in_flight_tile = self.in_flight_tiles.remove(idx).unwrap();
-                            let _ = self.tx.send_realtime(
-                                OutputEvent::BlitTile(BlitTileEvent {
-                                    client_id: event.from_id,
-                                    time: in_flight_tile.requested_at.elapsed().as_secs_f64(),
-                                    addr: in_flight_tile.addr,
-                                    name: client.name.clone(),
-                                    pixels: results
-                                        .into_iter()
-                                        .map(|result| {
-                                            if let Some(color) = result.color {
-                                                color
-                                            } else if result.hit {
-                                                Vec3 {
-                                                    x: 1.0,
-                                                    y: 1.0,
-                                                    z: 1.0,
-                                                }
-                                            } else {
-                                                Vec3 {
-                                                    x: 0.0,
-                                                    y: 0.0,
-                                                    z: 0.0,
-                                                }
-                                            }
-                                        })
-                                        .collect(),
-                                }),
+                            let expected = in_flight_tile.addr.area();
+                            if results.len() != expected {
+                                self.pending_tiles.push_front(in_flight_tile.addr);
+                                client.respond(
+                                    request_id,
+                                    Response::Error(format!(
+                                        "Expected {} results but got {}",
+                                        expected,
+                                        results.len()
+                                    )),
+                                );
+                                continue;
+                            }
+                            let time = in_flight_tile.requested_at.elapsed().as_secs_f64();
+                            let seconds_per_ray = time / expected as f64;
+                            client.seconds_per_ray = Some(match client.seconds_per_ray {
+                                Some(prev) => prev * 0.8 + seconds_per_ray * 0.2,
+                                None => seconds_per_ray,
+                            });
+                            client.render_times.insert(time);
+                            client.total_tiles += 1;
+                            client.session_tiles += 1;
+                            client.session_time += time;
+                            for result in &results {
+                                client.max_ray_depth = client.max_ray_depth.max(result.ray_depth);
+                                client.ray_depth_sum += result.ray_depth as u64;
+                            }
+                            client.rays_submitted += results.len() as u64;
+                            client.recent_rays.add(Instant::now(), results.len() as u64);
+                            let rank = self.leaderboard.record_tile(
+                                &client.name,
+                                client.session_time / client.session_tiles as f64,
+                            );
+                            client.rank = rank;
+                            let response = if with_stats {
+                                Response::SubmitResultsWithStats(SubmitStats {
+                                    total_tiles: client.total_tiles,
+                                    avg_time: client.render_times.mean(),
+                                    rank,
+                                })
+                            } else {
+                                Response::SubmitResults
+                            };
+                            client.respond(request_id, response);
+                            let addr = in_flight_tile.addr;
+                            let area = self.completed_area.entry(addr.frame).or_default();
+                            *area += addr.area();
+                            let frame_area = FRAME_AREA * self.config.samples_per_tile as usize;
+                            let completes_frame = *area >= frame_area;
+                            // The next update supersedes this one, so it's fine to lose
+                            self.tx.send_or_drop(
+                                OutputEvent::FrameProgress {
+                                    frame: addr.frame,
+                                    completed: *area / (TILE_SIZE * TILE_SIZE),
+                                    total: frame_area / (TILE_SIZE * TILE_SIZE),
+                                },
                                 "ServerState.tx",
                             );
+                            if completes_frame {
+                                self.completed_area.remove(&addr.frame);
+                                self.persist();
+                            }
+                            let pixels: Vec<_> = results
+                                .into_iter()
+                                .map(|result| self.config.channel.to_pixel(result))
+                                .collect();
+                            // Every sample moves the average, so there's nothing to skip
+                            let diff_cache = self.config.diff_threshold > 0.0
+                                && self.config.samples_per_tile == 1;
+                            if diff_cache && addr.level != TileLevel::Base {
+                                // Other sizes don't line up with the cache, so forget what they cover
+                                for cell in addr.base_cells() {
+                                    self.blitted_tiles[cell] = None;
+                                }
+                            } else if diff_cache {
+                                let quantized = quantize(&pixels);
+                                let checksum = fnv1a(&quantized);
+                                let blitted = &mut self.blitted_tiles[addr.rays_index()];
+                                let changed = match blitted {
+                                    Some(prev) if prev.checksum == checksum => false,
+                                    Some(prev) => Self::should_blit(
+                                        &prev.pixels,
+                                        &pixels,
+                                        self.config.diff_threshold,
+                                    ),
+                                    None => true,
+                                };
+                                if !changed && !completes_frame {
+                                    self.state_changed = true;
+                                    continue;
+                                }
+                                *blitted = Some(BlittedTile {
+                                    checksum,
+                                    pixels: quantized,
+                                });
+                            }
+                            let blit = OutputEvent::BlitTile(BlitTileEvent {
+                                client_id: event.from_id,
+                                time,
+                                addr,
+                                name: client.name.clone(),
+                                pixels,
+                                completes_frame,
+                                samples: client.samples_per_pixel,
+                            });
+                            if completes_frame {
+                                // Frames only advance on this one, so it has to get through
+                                let _ = self.tx.send_realtime(blit, "ServerState.tx");
+                            } else if !self.tx.send_or_drop(blit, "ServerState.tx") {
+                                // A wedged encoder mustn't hold up every client. The tile
+                                // was never shown, so it has to be rendered again before
+                                // the frame can complete, and the diff cache mustn't skip it.
+                                if let Some(area) = self.completed_area.get_mut(&addr.frame) {
+                                    *area -= addr.area();
+                                }
+                                self.pending_tiles.push_front(addr);
+                                if addr.level == TileLevel::Base {
+                                    self.blitted_tiles[addr.rays_index()] = None;
+                                }
+                            }
+                        } else {
+                            let response = if with_stats {
+                                Response::SubmitResultsWithStats(SubmitStats {
+                                    total_tiles: client.total_tiles,
+                                    avg_time: client.render_times.mean(),
+                                    rank: client.rank,
+                                })
+                            } else {
+                                Response::SubmitResults
+                            };
+                            client.respond(request_id, response);
                         }
                     }
+                    self.mark_state_changed();
                 }
             }
         }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn server_thread(
-    rx: mpsc::Receiver<ClientEvent>,
+    rx: crossbeam_channel::Receiver<ClientEvent>,
+    admin_rx: mpsc::Receiver<AdminCommand>,
     tx: mpsc::SyncSender<OutputEvent>,
     scene_elements: Vec<SceneElement>,
+    public_state: Arc<RwLock<PublicServerState>>,
+    leaderboard: Leaderboard,
+    debug_log: Arc<RwLock<VecDeque<DebugLogEntry>>>,
+    config: ServerConfig,
 ) {
-    ServerState::new(rx, tx, scene_elements).run()
+    let mut state = ServerState::new(
+        rx,
+        admin_rx,
+        tx,
+        scene_elements,
+        public_state,
+        leaderboard,
+        debug_log,
+        config,
+    );
+    if let Some(path) = state.config.resume_from.clone() {
+        if path.exists() {
+            if let Err(e) = state.load_snapshot(&path) {
+                log::warn!("Ignoring corrupt state file {}: {:?}", path.display(), e);
+            }
+        }
+    }
+    state.run()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn test_config() -> ServerConfig {
+        ServerConfig {
+            send_bvh: false,
+            tile_timeout: Duration::from_secs(30),
+            debug_log_size: 16,
+            animation_script: None,
+            frame_deadline: None,
+            idle_timeout: None,
+            resume_from: None,
+            channel: OutputChannel::Color,
+            diff_threshold: 0.0,
+            // Saves generating rays for the other tile levels
+            uniform_tiles: true,
+            ambient_light: Vec3::default(),
+            camera_origin: Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: -350.0,
+            },
+            fov_degrees: 14.25,
+            projection: Projection::Perspective,
+            camera_orbit: None,
+            max_frames_ahead: None,
+            samples_per_tile: 1,
+            jitter_seed: None,
+            heartbeat: Arc::default(),
+            ban_list: BanList::default(),
+            name_filter: None,
+        }
+    }
+
+    fn sphere(x: f32, y: f32, z: f32, r: f32) -> SceneElement {
+        SceneElement {
+            x,
+            y,
+            z,
+            r,
+            ex: 0.0,
+            ey: 0.0,
+            ez: 0.0,
+        }
+    }
+
+    // A server with one sphere, and the receiving end of its output channel
+    fn test_state(config: ServerConfig) -> (ServerState, mpsc::Receiver<OutputEvent>) {
+        // Nothing reads admin commands here, so the sender can go straight away
+        let (_, admin_rx) = mpsc::sync_channel(1);
+        // Large enough that the loop never blocks on output during a test
+        let (output_tx, output_rx) = mpsc::sync_channel(100_000);
+        let leaderboard_path = std::env::temp_dir().join(format!(
+            "leaderboard-test-{}-{:?}.json",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        let state = ServerState::new(
+            crossbeam_channel::never(),
+            admin_rx,
+            output_tx,
+            vec![sphere(0.0, 0.0, 0.0, 50.0)],
+            Arc::default(),
+            Leaderboard::new(leaderboard_path, Arc::default()),
+            Arc::default(),
+            config,
+        );
+        (state, output_rx)
+    }
+
+    // Runs the server loop over these events, returning once all of them
+    // have been handled
+    fn process(state: &mut ServerState, events: impl IntoIterator<Item = ClientEvent>) {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        for event in events {
+            tx.send(event).unwrap();
+        }
+        drop(tx);
+        state.rx = rx;
+        state.run();
+    }
+
+    fn event(from_id: ClientId, payload: ClientEventPayload) -> ClientEvent {
+        ClientEvent { from_id, payload }
+    }
+
+    // Registers a client, returning its ID and where its commands arrive
+    fn connect(state: &mut ServerState) -> (ClientId, crossbeam_channel::Receiver<ClientCommand>) {
+        let id = ClientId::new();
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let addr = "127.0.0.1:4000".to_string();
+        process(state, [event(id, ClientEventPayload::Connected(tx, addr))]);
+        (id, rx)
+    }
+
+    // The state a server publishes once a client has connected from `addr`,
+    // for the HTTP endpoints to be tested against
+    pub(crate) fn published_with_client(addr: &str) -> (ClientId, Arc<RwLock<PublicServerState>>) {
+        let (mut state, _output) = test_state(test_config());
+        let id = ClientId::new();
+        let (tx, _commands) = crossbeam_channel::unbounded();
+        process(
+            &mut state,
+            [event(id, ClientEventPayload::Connected(tx, addr.into()))],
+        );
+        state.publish_state();
+        (id, state.public_state.clone())
+    }
+
+    #[test]
+    fn run_bumps_the_heartbeat() {
+        let (mut state, _output) = test_state(test_config());
+        let heartbeat = state.config.heartbeat.clone();
+        assert_eq!(heartbeat.load(Ordering::Relaxed), 0);
+        let (id, _commands) = connect(&mut state);
+        assert!(state.clients.contains_key(&id));
+        assert!(heartbeat.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn idle_servers_keep_bumping_the_heartbeat() {
+        let (mut state, _output) = test_state(test_config());
+        let heartbeat = state.config.heartbeat.clone();
+        // With no events at all, only the admin poll wakes the loop up
+        idle(&mut state, ADMIN_POLL_INTERVAL * 5);
+        assert!(heartbeat.load(Ordering::Relaxed) >= 3);
+    }
+
+    #[test]
+    fn heartbeats_smooth_the_round_trip_time() {
+        let (mut state, _output) = test_state(test_config());
+        let (id, _commands) = connect(&mut state);
+        process(
+            &mut state,
+            [
+                event(id, ClientEventPayload::Heartbeat(None)),
+                event(
+                    id,
+                    ClientEventPayload::Heartbeat(Some(Duration::from_millis(10))),
+                ),
+                event(
+                    id,
+                    ClientEventPayload::Heartbeat(Some(Duration::from_millis(20))),
+                ),
+            ],
+        );
+        let rtt = state.clients[&id].round_trip_time.unwrap();
+        assert!((rtt - 0.012).abs() < 1e-9, "{rtt}");
+    }
+
+    fn misses(count: usize) -> Vec<protocol::Result> {
+        (0..count)
+            .map(|_| protocol::Result {
+                hit: false,
+                color: None,
+                depth: None,
+                normal: None,
+                ray_depth: 0,
+            })
+            .collect()
+    }
+
+    fn blitted_tiles(output: &mpsc::Receiver<OutputEvent>) -> Vec<(u64, usize, usize)> {
+        output
+            .try_iter()
+            .filter_map(|event| match event {
+                OutputEvent::BlitTile(blit) => Some((blit.addr.frame, blit.addr.x, blit.addr.y)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn orbit_turns_by_its_speed_each_frame() {
+        let orbit = CameraOrbit {
+            radius: 350.0,
+            height: 20.0,
+            radians_per_frame: 0.01,
+        };
+        let start = orbit.view_at(0, 14.25);
+        assert_eq!(
+            (start.origin.x, start.origin.y, start.origin.z),
+            (0.0, -20.0, -350.0)
+        );
+        // A quarter turn puts the camera on the +x side, still facing the center
+        let quarter = orbit.view_at(157, 14.25);
+        assert!((quarter.origin.x - 350.0).abs() < 0.01);
+        assert!((quarter.origin.z + 350.0 * 0.0008).abs() < 0.01);
+        assert_eq!(quarter.origin.y, -20.0);
+        assert_eq!(quarter.fov_degrees, 14.25);
+        // And it keeps going round rather than snapping back
+        let later = orbit.view_at(157 + 629, 14.25);
+        assert!((later.origin.x - quarter.origin.x).abs() < 1.0);
+    }
+
+    #[test]
+    fn orbiting_rays_start_at_the_camera() {
+        let orbit = CameraOrbit {
+            radius: 200.0,
+            height: 10.0,
+            radians_per_frame: 0.5,
+        };
+        let (mut state, _output) = test_state(ServerConfig {
+            camera_orbit: Some(orbit),
+            ..test_config()
+        });
+        let (id, commands) = connect(&mut state);
+        process(
+            &mut state,
+            [event(
+                id,
+                ClientEventPayload::Request(1, Request::ReserveRays),
+            )],
+        );
+        let frame = state.in_flight_tiles[0].addr.frame;
+        let expected = orbit.view_at(frame, state.config.fov_degrees).origin;
+        let rays = commands
+            .try_iter()
+            .find_map(|command| match command {
+                ClientCommand::Response(1, Response::ReserveRays(rays, _)) => Some(rays),
+                _ => None,
+            })
+            .unwrap();
+        for ray in rays.iter() {
+            assert_eq!(
+                (ray.origin.x, ray.origin.y, ray.origin.z),
+                (expected.x, expected.y, expected.z)
+            );
+        }
+        // Not where the fixed camera would be
+        assert_eq!(expected.y, -10.0);
+        assert!((expected.x.hypot(expected.z) - 200.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn animation_indices_follow_the_scene_file() {
+        let script = "[[sphere]]\nindex = 0\norbit_radius = 10.0\n";
+        let (mut state, _output) = test_state(ServerConfig {
+            animation_script: Some(AnimationScript::parse(script).unwrap()),
+            ..test_config()
+        });
+        // Out of order by x, as a scene file may well be
+        state.scene_elements = vec![sphere(100.0, 0.0, 0.0, 5.0), sphere(-100.0, 0.0, 0.0, 5.0)];
+        state.regenerate_scene();
+        let scene = state.scene_for(state.current_frame);
+        let centers: Vec<_> = scene
+            .spheres
+            .iter()
+            .map(|sphere| (sphere.center.x, sphere.center.z))
+            .collect();
+        // Sorted by x for clients, with the track on the first row's sphere
+        assert_eq!(centers, [(-100.0, 0.0), (110.0, 0.0)]);
+    }
+
+    #[test]
+    fn disconnecting_requeues_in_flight_tiles() {
+        let (mut state, _output) = test_state(test_config());
+        let (id, _commands) = connect(&mut state);
+        let (other, _other_commands) = connect(&mut state);
+        process(
+            &mut state,
+            [
+                event(id, ClientEventPayload::Request(1, Request::ReserveRays)),
+                event(other, ClientEventPayload::Request(1, Request::ReserveRays)),
+                event(id, ClientEventPayload::Request(2, Request::ReserveRays)),
+            ],
+        );
+        let lost: Vec<_> = state
+            .in_flight_tiles
+            .iter()
+            .filter(|tile| tile.client_id == id)
+            .map(|tile| (tile.addr.frame, tile.addr.x, tile.addr.y))
+            .collect();
+        assert_eq!(lost.len(), 2);
+
+        process(&mut state, [event(id, ClientEventPayload::Disconnected)]);
+        assert!(!state.clients.contains_key(&id));
+        assert_eq!(state.in_flight_tiles.len(), 1);
+        let reissued: Vec<_> = (0..2)
+            .map(|_| {
+                let addr = state.pop_tile_addr(TileLevel::Base).unwrap();
+                (addr.frame, addr.x, addr.y)
+            })
+            .collect();
+        assert_eq!(reissued, lost);
+    }
+
+    #[test]
+    fn pipelined_results_go_to_the_tile_they_name() {
+        let (mut state, output) = test_state(test_config());
+        let (id, commands) = connect(&mut state);
+        process(
+            &mut state,
+            (1..=3).map(|request_id| {
+                event(
+                    id,
+                    ClientEventPayload::Request(request_id, Request::ReserveRays),
+                )
+            }),
+        );
+        let mut ray_counts = HashMap::new();
+        for command in commands.try_iter() {
+            if let ClientCommand::Response(request_id, Response::ReserveRays(rays, _)) = command {
+                ray_counts.insert(request_id, rays.len());
+            }
+        }
+        assert_eq!(ray_counts.len(), 3);
+        let reserved: HashMap<_, _> = state
+            .in_flight_tiles
+            .iter()
+            .map(|tile| {
+                (
+                    tile.reserved_by,
+                    (tile.addr.frame, tile.addr.x, tile.addr.y),
+                )
+            })
+            .collect();
+        assert_eq!(reserved.len(), 3);
+        blitted_tiles(&output);
+
+        let order = [3, 1, 2];
+        process(
+            &mut state,
+            order.map(|request_id| {
+                let results = misses(ray_counts[&request_id]);
+                event(
+                    id,
+                    ClientEventPayload::Request(request_id, Request::SubmitResults(results)),
+                )
+            }),
+        );
+        for command in commands.try_iter() {
+            if let ClientCommand::Response(_, response) = command {
+                assert!(
+                    matches!(response, Response::SubmitResults),
+                    "{:?}",
+                    response
+                );
+            }
+        }
+        let expected: Vec<_> = order
+            .iter()
+            .map(|request_id| reserved[request_id])
+            .collect();
+        assert_eq!(blitted_tiles(&output), expected);
+        assert!(state.in_flight_tiles.is_empty());
+    }
+
+    #[test]
+    fn results_for_a_dropped_tile_are_ignored() {
+        let (mut state, output) = test_state(test_config());
+        let (id, commands) = connect(&mut state);
+        process(
+            &mut state,
+            [
+                event(id, ClientEventPayload::Request(1, Request::ReserveRays)),
+                event(id, ClientEventPayload::Request(2, Request::ReserveRays)),
+            ],
+        );
+        let count = state.in_flight_tiles[0].addr.area();
+        // As if the frame holding the first tile had been force completed
+        let dropped = state
+            .in_flight_tiles
+            .iter()
+            .position(|tile| tile.reserved_by == 1)
+            .unwrap();
+        state.in_flight_tiles.remove(dropped);
+        commands.try_iter().for_each(drop);
+        blitted_tiles(&output);
+
+        process(
+            &mut state,
+            [event(
+                id,
+                ClientEventPayload::Request(1, Request::SubmitResults(misses(count))),
+            )],
+        );
+        assert!(blitted_tiles(&output).is_empty());
+        assert_eq!(state.in_flight_tiles.len(), 1);
+        assert_eq!(state.in_flight_tiles[0].reserved_by, 2);
+    }
+
+    #[test]
+    fn hit_masks_must_cover_the_whole_tile() {
+        let (mut state, output) = test_state(test_config());
+        let (id, commands) = connect(&mut state);
+        process(
+            &mut state,
+            [event(
+                id,
+                ClientEventPayload::Request(1, Request::ReserveRays),
+            )],
+        );
+        let area = state.in_flight_tiles[0].addr.area() as u32;
+        commands.try_iter().for_each(drop);
+        blitted_tiles(&output);
+
+        let short = vec![(1, true), (area - 2, false)];
+        process(
+            &mut state,
+            [event(
+                id,
+                ClientEventPayload::Request(1, Request::SubmitHitMask(short)),
+            )],
+        );
+        let response = commands.try_iter().find_map(|command| match command {
+            ClientCommand::Response(1, response) => Some(response),
+            _ => None,
+        });
+        assert!(
+            matches!(&response, Some(Response::Error(m)) if m.contains(&format!("got {}", area - 1))),
+            "{response:?}"
+        );
+        assert!(blitted_tiles(&output).is_empty());
+        assert!(state.in_flight_tiles.is_empty());
+
+        process(
+            &mut state,
+            [event(
+                id,
+                ClientEventPayload::Request(2, Request::ReserveRays),
+            )],
+        );
+        let exact = vec![(1, true), (area - 1, false)];
+        process(
+            &mut state,
+            [event(
+                id,
+                ClientEventPayload::Request(2, Request::SubmitHitMask(exact)),
+            )],
+        );
+        let pixels = output
+            .try_iter()
+            .find_map(|event| match event {
+                OutputEvent::BlitTile(blit) => Some(blit.pixels),
+                _ => None,
+            })
+            .unwrap();
+        let black = |pixel: &Vec3| (pixel.x, pixel.y, pixel.z) == (0.0, 0.0, 0.0);
+        assert!(!black(&pixels[0]));
+        assert!(pixels[1..].iter().all(black));
+    }
+
+    // A tile of colored hits with a few misses, and the same packed as sRGB
+    fn packed_and_unpacked(count: usize) -> (Vec<protocol::Result>, Vec<u8>) {
+        let mut results = misses(count);
+        let mut colors = Vec::with_capacity(count * 3);
+        let mut hits = vec![0u8; count.div_ceil(8)];
+        for (i, result) in results.iter_mut().enumerate() {
+            let rgb = [(i % 256) as u8, (i * 7 % 256) as u8, 255 - (i % 256) as u8];
+            colors.extend_from_slice(&rgb);
+            if i % 5 != 0 {
+                hits[i / 8] |= 1 << (i % 8);
+                result.hit = true;
+                result.color = Some(Vec3 {
+                    x: protocol::srgb_to_linear(rgb[0] as f32 / 255.0),
+                    y: protocol::srgb_to_linear(rgb[1] as f32 / 255.0),
+                    z: protocol::srgb_to_linear(rgb[2] as f32 / 255.0),
+                });
+            }
+        }
+        colors.extend_from_slice(&hits);
+        (results, colors)
+    }
+
+    #[test]
+    fn packed_results_draw_the_same_pixels() {
+        let (mut state, output) = test_state(test_config());
+        let (id, commands) = connect(&mut state);
+        process(
+            &mut state,
+            (1..=2).map(|request_id| {
+                event(
+                    id,
+                    ClientEventPayload::Request(request_id, Request::ReserveRays),
+                )
+            }),
+        );
+        let count = state.in_flight_tiles[0].addr.area();
+        commands.try_iter().for_each(drop);
+        blitted_tiles(&output);
+
+        let (results, packed) = packed_and_unpacked(count);
+        process(
+            &mut state,
+            [
+                event(
+                    id,
+                    ClientEventPayload::Request(1, Request::SubmitResults(results)),
+                ),
+                event(
+                    id,
+                    ClientEventPayload::Request(2, Request::SubmitResultsPacked(packed)),
+                ),
+            ],
+        );
+        for command in commands.try_iter() {
+            if let ClientCommand::Response(_, response) = command {
+                assert!(matches!(response, Response::SubmitResults), "{response:?}");
+            }
+        }
+        let tiles: Vec<_> = output
+            .try_iter()
+            .filter_map(|event| match event {
+                OutputEvent::BlitTile(blit) => Some(blit.pixels),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(tiles.len(), 2);
+        assert_eq!(tiles[0].len(), count);
+        let bits = |pixels: &[Vec3]| -> Vec<_> {
+            pixels
+                .iter()
+                .map(|p| [p.x.to_bits(), p.y.to_bits(), p.z.to_bits()])
+                .collect()
+        };
+        assert_eq!(bits(&tiles[0]), bits(&tiles[1]));
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "{name}-{}-{:?}",
+            std::process::id(),
+            std::thread::current().id()
+        ))
+    }
+
+    fn postcard_bytes(value: &impl Serialize) -> Vec<u8> {
+        postcard::to_allocvec(value).unwrap()
+    }
+
+    #[test]
+    fn snapshots_round_trip() {
+        let (mut state, _output) = test_state(test_config());
+        state.scene_elements = vec![sphere(-50.0, 0.0, 0.0, 10.0), sphere(50.0, 5.0, 0.0, 20.0)];
+        state.random_displacements = generate_random_displacements(2);
+        state.current_frame = 100;
+        state.pending_frame = 101;
+        state.regenerate_scene();
+        state.leaderboard.record_tile("Alice", 0.5);
+        let path = temp_path("snapshot.bin");
+        fs::write(&path, state.snapshot().unwrap()).unwrap();
+
+        let (mut resumed, _output) = test_state(test_config());
+        resumed.load_snapshot(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(resumed.current_frame, 100);
+        assert_eq!(resumed.pending_frame, 101);
+        assert_eq!(
+            postcard_bytes(&resumed.scene_elements),
+            postcard_bytes(&state.scene_elements)
+        );
+        assert_eq!(
+            postcard_bytes(&resumed.random_displacements),
+            postcard_bytes(&state.random_displacements)
+        );
+        assert_eq!(
+            postcard_bytes(&*resumed.scene_for(100)),
+            postcard_bytes(&*state.scene_for(100))
+        );
+        let leaderboard = resumed.leaderboard.entries();
+        assert_eq!(leaderboard.len(), 1);
+        assert_eq!(leaderboard[0].name, "Alice");
+        assert_eq!(leaderboard[0].total_tiles, 1);
+    }
+
+    #[test]
+    fn reloading_the_scene_swaps_it_for_the_next_tile() {
+        let (mut state, _output) = test_state(test_config());
+        let (id, commands) = connect(&mut state);
+        process(
+            &mut state,
+            [event(
+                id,
+                ClientEventPayload::Request(1, Request::ReserveRays),
+            )],
+        );
+        assert_eq!(state.in_flight_tiles.len(), 1);
+        commands.try_iter().for_each(drop);
+
+        let path = temp_path("scene-b.csv");
+        fs::write(&path, "x,y,z,r\n-20,0,0,5\n0,0,0,5\n20,0,0,5\n").unwrap();
+        let (reply_tx, reply_rx) = mpsc::sync_channel(1);
+        state.handle_admin_command(AdminCommand::ReloadScene(path.clone(), reply_tx));
+        fs::remove_file(&path).unwrap();
+        reply_rx.recv().unwrap().unwrap();
+        // The tile from the old scene won't be drawn
+        assert!(state.in_flight_tiles.is_empty());
+
+        process(
+            &mut state,
+            [event(
+                id,
+                ClientEventPayload::Request(2, Request::ReserveRays),
+            )],
+        );
+        let scene = commands
+            .try_iter()
+            .find_map(|command| match command {
+                ClientCommand::Response(2, Response::ReserveRays(_, scene)) => Some(scene),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(scene.spheres.len(), 3);
+    }
+
+    #[test]
+    fn a_failed_reload_keeps_the_old_scene() {
+        let (mut state, _output) = test_state(test_config());
+        state.regenerate_scene();
+        let (reply_tx, reply_rx) = mpsc::sync_channel(1);
+        let path = temp_path("missing-scene.csv");
+        state.handle_admin_command(AdminCommand::ReloadScene(path, reply_tx));
+        assert!(reply_rx.recv().unwrap().is_err());
+        assert_eq!(state.scene_elements.len(), 1);
+        assert_eq!(state.scene_for(state.current_frame).spheres.len(), 1);
+    }
+
+    #[test]
+    fn short_results_are_refused_and_the_tile_requeued() {
+        let (mut state, output) = test_state(test_config());
+        let (id, commands) = connect(&mut state);
+        process(
+            &mut state,
+            [event(
+                id,
+                ClientEventPayload::Request(1, Request::ReserveRays),
+            )],
+        );
+        let addr = state.in_flight_tiles[0].addr;
+        let count = addr.area();
+        commands.try_iter().for_each(drop);
+        blitted_tiles(&output);
+
+        process(
+            &mut state,
+            [event(
+                id,
+                ClientEventPayload::Request(1, Request::SubmitResults(misses(count - 1))),
+            )],
+        );
+        let response = commands.try_iter().find_map(|command| match command {
+            ClientCommand::Response(1, response) => Some(response),
+            _ => None,
+        });
+        let expected = format!("Expected {count} results but got {}", count - 1);
+        assert!(
+            matches!(&response, Some(Response::Error(m)) if *m == expected),
+            "{response:?}"
+        );
+        assert!(blitted_tiles(&output).is_empty());
+        // Still connected, and the tile goes out again
+        assert!(state.clients.contains_key(&id));
+        process(
+            &mut state,
+            [event(
+                id,
+                ClientEventPayload::Request(2, Request::ReserveRays),
+            )],
+        );
+        assert_eq!(state.in_flight_tiles[0].addr.x, addr.x);
+        assert_eq!(state.in_flight_tiles[0].addr.y, addr.y);
+        process(
+            &mut state,
+            [event(
+                id,
+                ClientEventPayload::Request(2, Request::SubmitResults(misses(count))),
+            )],
+        );
+        assert_eq!(blitted_tiles(&output), [(addr.frame, addr.x, addr.y)]);
+    }
+
+    #[test]
+    fn unchanged_tiles_are_not_blitted_again() {
+        let (mut state, output) = test_state(ServerConfig {
+            diff_threshold: 0.01,
+            ..test_config()
+        });
+        let (id, commands) = connect(&mut state);
+        let white = Vec3 {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+        let mut submitted = Vec::new();
+        for (request_id, changed) in [(1, false), (2, false), (3, true)] {
+            process(
+                &mut state,
+                [event(
+                    id,
+                    ClientEventPayload::Request(request_id, Request::ReserveRays),
+                )],
+            );
+            let addr = state.in_flight_tiles.back().unwrap().addr;
+            let count = addr.area();
+            // As if the same tile had already been shown as all misses
+            let shown: Vec<_> = misses(count)
+                .into_iter()
+                .map(|result| state.config.channel.to_pixel(result))
+                .collect();
+            let quantized = quantize(&shown);
+            state.blitted_tiles[addr.rays_index()] = Some(BlittedTile {
+                checksum: fnv1a(&quantized),
+                pixels: quantized,
+            });
+            let results = if changed {
+                (0..count).map(|_| hit(Some(white), None, None)).collect()
+            } else {
+                misses(count)
+            };
+            process(
+                &mut state,
+                [event(
+                    id,
+                    ClientEventPayload::Request(request_id, Request::SubmitResults(results)),
+                )],
+            );
+            submitted.push(addr);
+        }
+        commands.try_iter().for_each(drop);
+        let changed = submitted[2];
+        assert_eq!(
+            blitted_tiles(&output),
+            [(changed.frame, changed.x, changed.y)]
+        );
+    }
+
+    #[test]
+    fn blits_are_dropped_while_the_output_is_full() {
+        let (mut state, _) = test_state(ServerConfig {
+            diff_threshold: 0.01,
+            ..test_config()
+        });
+        let (id, commands) = connect(&mut state);
+        process(
+            &mut state,
+            [event(
+                id,
+                ClientEventPayload::Request(1, Request::ReserveRays),
+            )],
+        );
+        let addr = state.in_flight_tiles[0].addr;
+        // An output thread that has stopped reading
+        let (tx, output) = mpsc::sync_channel(1);
+        tx.send(OutputEvent::ClientDisconnected(id)).unwrap();
+        state.tx = tx;
+
+        process(
+            &mut state,
+            [event(
+                id,
+                ClientEventPayload::Request(2, Request::SubmitResults(misses(addr.area()))),
+            )],
+        );
+        // The client still hears back, and the tile can be shown next time
+        assert!(commands
+            .try_iter()
+            .any(|command| matches!(command, ClientCommand::Response(2, Response::SubmitResults))));
+        assert!(state.blitted_tiles[addr.rays_index()].is_none());
+        assert!(matches!(
+            output.try_recv(),
+            Ok(OutputEvent::ClientDisconnected(_))
+        ));
+        assert!(output.try_recv().is_err());
+    }
+
+    // Has the client render tiles until one completes a frame, returning
+    // every tile blitted along the way
+    fn render_frame(
+        state: &mut ServerState,
+        id: ClientId,
+        output: &mpsc::Receiver<OutputEvent>,
+    ) -> Vec<(u64, usize, usize)> {
+        let mut blitted = Vec::new();
+        for request_id in 2..10_000 {
+            process(
+                state,
+                [event(
+                    id,
+                    ClientEventPayload::Request(request_id, Request::ReserveRays),
+                )],
+            );
+            let addr = state.in_flight_tiles[0].addr;
+            process(
+                state,
+                [event(
+                    id,
+                    ClientEventPayload::Request(
+                        request_id,
+                        Request::SubmitResults(misses(addr.area())),
+                    ),
+                )],
+            );
+            let blit = output
+                .try_iter()
+                .find_map(|event| match event {
+                    OutputEvent::BlitTile(blit) => Some(blit),
+                    _ => None,
+                })
+                .unwrap();
+            blitted.push((blit.addr.frame, blit.addr.x, blit.addr.y));
+            if blit.completes_frame {
+                return blitted;
+            }
+        }
+        panic!("The frame never completed");
+    }
+
+    #[test]
+    fn dropped_blits_are_rendered_before_the_frame_completes() {
+        let (mut state, _) = test_state(test_config());
+        let (id, _commands) = connect(&mut state);
+        process(
+            &mut state,
+            [event(
+                id,
+                ClientEventPayload::Request(1, Request::ReserveRays),
+            )],
+        );
+        let dropped = state.in_flight_tiles[0].addr;
+        // An output thread that has stopped reading
+        let (tx, _wedged) = mpsc::sync_channel(1);
+        tx.send(OutputEvent::ClientDisconnected(id)).unwrap();
+        state.tx = tx;
+        process(
+            &mut state,
+            [event(
+                id,
+                ClientEventPayload::Request(1, Request::SubmitResults(misses(dropped.area()))),
+            )],
+        );
+
+        // Once the output catches up, the tile goes out again with the rest
+        // of the frame
+        let (tx, output) = mpsc::sync_channel(100_000);
+        state.tx = tx;
+        let blitted = render_frame(&mut state, id, &output);
+        assert!(blitted.contains(&(dropped.frame, dropped.x, dropped.y)));
+    }
+
+    #[test]
+    fn state_is_saved_as_each_frame_completes() {
+        let path = temp_path("completed-frame.bin");
+        let (mut state, output) = test_state(ServerConfig {
+            resume_from: Some(path.clone()),
+            ..test_config()
+        });
+        let (id, _commands) = connect(&mut state);
+        let tiles = render_frame(&mut state, id, &output).len();
+        // Waits for the file writer to finish
+        drop(state);
+
+        let (mut resumed, _output) = test_state(test_config());
+        resumed.load_snapshot(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let leaderboard = resumed.leaderboard.entries();
+        assert_eq!(leaderboard.len(), 1);
+        assert_eq!(leaderboard[0].total_tiles, tiles as u64);
+    }
+
+    fn kick(state: &mut ServerState, id: ClientId, ban: Option<Duration>) -> bool {
+        let (reply_tx, reply_rx) = mpsc::sync_channel(1);
+        process(state, [event(id, ClientEventPayload::Kick(ban, reply_tx))]);
+        reply_rx.recv().unwrap()
+    }
+
+    fn disconnect_reason(commands: &crossbeam_channel::Receiver<ClientCommand>) -> Option<String> {
+        commands.try_iter().find_map(|command| match command {
+            ClientCommand::Disconnect(reason) => Some(reason),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn kicked_clients_are_told_to_disconnect() {
+        let (mut state, _output) = test_state(test_config());
+        let (id, commands) = connect(&mut state);
+        assert!(kick(&mut state, id, None));
+        assert_eq!(
+            disconnect_reason(&commands).as_deref(),
+            Some("Kicked by admin")
+        );
+        let ip = "127.0.0.1".parse().unwrap();
+        assert!(!state.config.ban_list.is_banned(ip));
+
+        assert!(kick(&mut state, id, Some(Duration::from_secs(60))));
+        assert_eq!(
+            disconnect_reason(&commands).as_deref(),
+            Some("Banned by admin")
+        );
+        assert!(state.config.ban_list.is_banned(ip));
+
+        assert!(!kick(&mut state, ClientId::new(), None));
+    }
+
+    #[test]
+    fn broadcasts_reach_every_client_that_takes_pushes() {
+        let (mut state, _output) = test_state(test_config());
+        let listeners: Vec<_> = (0..3).map(|_| connect(&mut state)).collect();
+        let (legacy, legacy_commands) = connect(&mut state);
+        process(
+            &mut state,
+            listeners
+                .iter()
+                .map(|&(id, _)| event(id, ClientEventPayload::SetFeatures(protocol::FEATURE_PUSH)))
+                .chain([event(legacy, ClientEventPayload::SetFeatures(0))]),
+        );
+
+        state.handle_admin_command(AdminCommand::Broadcast("Switch to part two".into()));
+        for (_, commands) in &listeners {
+            let announcements: Vec<_> = commands
+                .try_iter()
+                .filter_map(|command| match command {
+                    ClientCommand::Response(0, Response::Announcement(message)) => Some(message),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(announcements, ["Switch to part two"]);
+        }
+        // It would have no way to tell the push from a response
+        assert!(legacy_commands.try_iter().all(|command| !matches!(
+            command,
+            ClientCommand::Response(_, Response::Announcement(_))
+        )));
+    }
+
+    fn hit(color: Option<Vec3>, normal: Option<Vec3>, depth: Option<f32>) -> protocol::Result {
+        protocol::Result {
+            hit: true,
+            color,
+            depth,
+            normal,
+            ray_depth: 0,
+        }
+    }
+
+    #[test]
+    fn lambert_shading_follows_the_light() {
+        // Facing the light, then side on, then facing away
+        assert!((lambert_shade(LIGHT_DIRECTION).unwrap() - 1.0).abs() < 1e-6);
+        let side_on = Vec3 {
+            x: 1.0,
+            y: -1.0,
+            z: 0.0,
+        };
+        assert!((lambert_shade(side_on).unwrap() - AMBIENT_LIGHT).abs() < 1e-6);
+        assert_eq!(lambert_shade(-LIGHT_DIRECTION), Some(AMBIENT_LIGHT));
+        // Straight back at the camera, which the light is 45 degrees off
+        let towards_camera = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: -1.0,
+        };
+        let expected = AMBIENT_LIGHT + (1.0 - AMBIENT_LIGHT) * std::f32::consts::FRAC_1_SQRT_2;
+        assert!((lambert_shade(towards_camera).unwrap() - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn lambert_shading_renormalizes() {
+        let long = LIGHT_DIRECTION * 5.0;
+        assert!((lambert_shade(long).unwrap() - 1.0).abs() < 1e-6);
+        assert_eq!(lambert_shade(Vec3::default()), None);
+    }
+
+    #[test]
+    fn pixels_prefer_color_then_normal_then_depth() {
+        let gray = |pixel: Vec3| {
+            assert_eq!((pixel.x, pixel.x), (pixel.y, pixel.z));
+            pixel.x
+        };
+        let color = Vec3 {
+            x: 0.2,
+            y: 0.4,
+            z: 0.6,
+        };
+        let to_pixel = |result| OutputChannel::Color.to_pixel(result);
+        let pixel = to_pixel(hit(Some(color), Some(LIGHT_DIRECTION), Some(350.0)));
+        assert_eq!((pixel.x, pixel.y, pixel.z), (0.2, 0.4, 0.6));
+        let shaded = gray(to_pixel(hit(None, Some(-LIGHT_DIRECTION), Some(350.0))));
+        assert_eq!(shaded, AMBIENT_LIGHT);
+        let by_depth = gray(to_pixel(hit(None, None, Some(SHADED_DEPTH_FAR))));
+        assert!((by_depth - SHADED_DEPTH_MIN_BRIGHTNESS).abs() < 1e-6);
+        // An unusable normal falls through to the depth
+        let by_depth = gray(to_pixel(hit(None, Some(Vec3::default()), Some(0.0))));
+        assert_eq!(by_depth, 1.0);
+        assert_eq!(gray(to_pixel(hit(None, None, None))), 1.0);
+    }
+
+    #[test]
+    fn scenes_carry_the_ambient_light_and_emissive_columns() {
+        let ambient = Vec3 {
+            x: 0.1,
+            y: 0.1,
+            z: 0.1,
+        };
+        let (mut state, _output) = test_state(ServerConfig {
+            ambient_light: ambient,
+            ..test_config()
+        });
+        let lamp = SceneElement {
+            ex: 1.0,
+            ey: 0.8,
+            ..sphere(0.0, 0.0, 0.0, 10.0)
+        };
+        state.scene_elements = vec![sphere(-50.0, 0.0, 0.0, 10.0), lamp];
+        state.random_displacements = generate_random_displacements(2);
+        state.regenerate_scene();
+        let scene = state.scene_for(state.current_frame);
+        assert_eq!(
+            (scene.ambient.x, scene.ambient.y, scene.ambient.z),
+            (0.1, 0.1, 0.1)
+        );
+        let emissive: Vec<_> = scene
+            .spheres
+            .iter()
+            .map(|sphere| (sphere.emissive.x, sphere.emissive.y, sphere.emissive.z))
+            .collect();
+        assert_eq!(emissive, [(0.0, 0.0, 0.0), (1.0, 0.8, 0.0)]);
+    }
+
+    fn reserved_rays(
+        state: &mut ServerState,
+        id: ClientId,
+        commands: &crossbeam_channel::Receiver<ClientCommand>,
+    ) -> Arc<Vec<Ray>> {
+        process(
+            state,
+            [event(
+                id,
+                ClientEventPayload::Request(9, Request::ReserveRays),
+            )],
+        );
+        commands
+            .try_iter()
+            .find_map(|command| match command {
+                ClientCommand::Response(9, Response::ReserveRays(rays, _)) => Some(rays),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn clients_get_rays_from_their_own_camera() {
+        let (mut state, _output) = test_state(test_config());
+        let camera = |x: f32| CameraView {
+            origin: Vec3 {
+                x,
+                y: 0.0,
+                z: -300.0,
+            },
+            target: Vec3::default(),
+            fov_degrees: 30.0,
+        };
+        let (left, left_commands) = connect(&mut state);
+        let (right, right_commands) = connect(&mut state);
+        process(
+            &mut state,
+            [
+                event(
+                    left,
+                    ClientEventPayload::Request(1, Request::SetCamera(camera(-100.0))),
+                ),
+                event(
+                    right,
+                    ClientEventPayload::Request(1, Request::SetCamera(camera(100.0))),
+                ),
+            ],
+        );
+
+        let left_rays = reserved_rays(&mut state, left, &left_commands);
+        let addr = state.in_flight_tiles[0].addr;
+        // Put the tile back so the other client gets the same one
+        process(&mut state, [event(left, ClientEventPayload::Disconnected)]);
+        let right_rays = reserved_rays(&mut state, right, &right_commands);
+        assert_eq!(state.in_flight_tiles[0].addr.x, addr.x);
+        assert_eq!(state.in_flight_tiles[0].addr.y, addr.y);
+
+        assert_eq!(left_rays.len(), right_rays.len());
+        assert!(left_rays.iter().all(|ray| ray.origin.x == -100.0));
+        assert!(right_rays.iter().all(|ray| ray.origin.x == 100.0));
+        for (left, right) in left_rays.iter().zip(right_rays.iter()) {
+            // Each looks in towards the middle from its own side
+            assert!(left.direction.x > right.direction.x);
+        }
+    }
+
+    #[test]
+    fn cameras_outside_the_fov_range_are_refused() {
+        let (mut state, _output) = test_state(test_config());
+        let (id, commands) = connect(&mut state);
+        for fov_degrees in [0.0, 180.0, f32::NAN] {
+            let camera = CameraView {
+                origin: Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: -300.0,
+                },
+                target: Vec3::default(),
+                fov_degrees,
+            };
+            process(
+                &mut state,
+                [event(
+                    id,
+                    ClientEventPayload::Request(1, Request::SetCamera(camera)),
+                )],
+            );
+            let response = commands.try_iter().find_map(|command| match command {
+                ClientCommand::Response(1, response) => Some(response),
+                _ => None,
+            });
+            assert!(
+                matches!(&response, Some(Response::Error(m)) if m.contains("Field of view")),
+                "{fov_degrees}: {response:?}"
+            );
+        }
+        assert!(state.clients[&id].camera.is_none());
+    }
+
+    fn ray_bits(rays: &[Ray]) -> Vec<[u32; 6]> {
+        rays.iter()
+            .map(|ray| {
+                let (o, d) = (ray.origin, ray.direction);
+                [o.x, o.y, o.z, d.x, d.y, d.z].map(f32::to_bits)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn seeded_jitter_repeats_per_frame() {
+        let camera = make_camera(test_config().camera_origin, 14.25);
+        let rays = |jitter, frame| {
+            let addr = TileAddr {
+                frame,
+                level: TileLevel::Base,
+                x: 3,
+                y: 2,
+            };
+            ray_bits(&generate_tile_rays(
+                &camera,
+                Projection::Perspective,
+                addr,
+                None,
+                jitter,
+            ))
+        };
+        let seeded = Jitter::Seeded(42);
+        assert_eq!(rays(seeded, 1), rays(seeded, 1));
+        assert_ne!(rays(seeded, 1), rays(seeded, 2));
+        assert_ne!(rays(seeded, 1), rays(Jitter::Seeded(43), 1));
+        assert_ne!(rays(seeded, 1), rays(Jitter::None, 1));
+    }
+
+    #[test]
+    fn seeded_jitter_stays_within_the_pixel() {
+        for frame in 0..4 {
+            for (x, y) in [
+                (0, 0),
+                (17, 3),
+                (TILES_X * TILE_SIZE - 1, TILES_Y * TILE_SIZE - 1),
+            ] {
+                let (dx, dy) = seeded_jitter(7, frame, x, y);
+                assert!((-0.5..0.5).contains(&dx) && (-0.5..0.5).contains(&dy));
+            }
+        }
+    }
+
+    #[test]
+    fn reissued_tiles_keep_their_seeded_rays() {
+        let (mut state, _output) = test_state(ServerConfig {
+            jitter_seed: Some(42),
+            ..test_config()
+        });
+        let (first, first_commands) = connect(&mut state);
+        let (second, second_commands) = connect(&mut state);
+        let first_rays = reserved_rays(&mut state, first, &first_commands);
+        process(&mut state, [event(first, ClientEventPayload::Disconnected)]);
+        let second_rays = reserved_rays(&mut state, second, &second_commands);
+        assert_eq!(ray_bits(&first_rays), ray_bits(&second_rays));
+    }
+
+    #[test]
+    fn orthographic_rays_are_parallel_and_span_the_image() {
+        let origin = test_config().camera_origin;
+        let camera = make_camera(origin, 14.25);
+        let units_per_pixel = 0.5;
+        let projection = Projection::Orthographic(units_per_pixel);
+        let (width, height) = (TILES_X * TILE_SIZE, TILES_Y * TILE_SIZE);
+        let tiles = generate_all_rays(&camera, projection, TileLevel::Base);
+        let rays: Vec<&Ray> = tiles.iter().flat_map(|tile| tile.iter()).collect();
+        assert_eq!(rays.len(), width * height);
+        assert!(rays
+            .iter()
+            .all(|ray| (ray.direction.x, ray.direction.y, ray.direction.z) == (0.0, 0.0, 1.0)));
+        assert!(rays.iter().all(|ray| ray.origin.z == origin.z));
+
+        let extent = |axis: fn(&Ray) -> f32| {
+            let values = rays.iter().map(|&ray| axis(ray));
+            let min = values.clone().fold(f32::INFINITY, f32::min);
+            let max = values.fold(f32::NEG_INFINITY, f32::max);
+            (min, max)
+        };
+        // Pixels are square, and the last one starts a pixel short of the edge
+        let half_width = width as f32 * units_per_pixel / 2.0;
+        let half_height = height as f32 * units_per_pixel / 2.0;
+        let (min_x, max_x) = extent(|ray| ray.origin.x);
+        assert!((min_x + half_width).abs() < 1e-3);
+        assert!((max_x - (half_width - units_per_pixel)).abs() < 1e-3);
+        let (min_y, max_y) = extent(|ray| ray.origin.y);
+        assert!((min_y + half_height).abs() < 1e-3);
+        assert!((max_y - (half_height - units_per_pixel)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn perspective_rays_spread_from_the_camera() {
+        let origin = test_config().camera_origin;
+        let camera = make_camera(origin, 14.25);
+        let corner = generate_ray(&camera, Projection::Perspective, 0, 0);
+        let center = generate_ray(
+            &camera,
+            Projection::Perspective,
+            TILES_X * TILE_SIZE / 2,
+            TILES_Y * TILE_SIZE / 2,
+        );
+        for ray in [&corner, &center] {
+            assert_eq!(
+                (ray.origin.x, ray.origin.y, ray.origin.z),
+                (origin.x, origin.y, origin.z)
+            );
+            assert!((ray.direction.length() - 1.0).abs() < 1e-6);
+        }
+        assert_eq!((center.direction.x, center.direction.y), (0.0, 0.0));
+        assert!(corner.direction.x < 0.0 && corner.direction.y < 0.0);
+    }
+
+    #[test]
+    fn bvhs_are_only_built_with_send_bvh() {
+        for send_bvh in [false, true] {
+            let (mut state, _) = test_state(ServerConfig {
+                send_bvh,
+                ..test_config()
+            });
+            state.scene_elements = (0..4)
+                .map(|i| sphere(i as f32 * 200.0, 0.0, 0.0, 50.0))
+                .collect();
+            state.random_displacements = generate_random_displacements(4);
+            state.regenerate_scene();
+            let scene = state.scene_for(state.current_frame);
+            assert_eq!(scene.bvh_nodes.len(), if send_bvh { 7 } else { 0 });
+        }
+    }
+
+    #[test]
+    fn empty_scenes_render_blank_frames() {
+        crate::utils::tests::capture_logs();
+        let (mut state, output) = test_state(test_config());
+        let path = temp_path("empty-scene.csv");
+        fs::write(&path, "x,y,z,r\n").unwrap();
+        let (reply_tx, reply_rx) = mpsc::sync_channel(1);
+        state.handle_admin_command(AdminCommand::ReloadScene(path.clone(), reply_tx));
+        fs::remove_file(&path).unwrap();
+        reply_rx.recv().unwrap().unwrap();
+        assert!(crate::utils::tests::logged(&format!(
+            "Scene {} has no spheres, so every frame will be blank",
+            path.display()
+        )));
+
+        let (id, commands) = connect(&mut state);
+        process(
+            &mut state,
+            [event(
+                id,
+                ClientEventPayload::Request(1, Request::ReserveRays),
+            )],
+        );
+        let scene = commands
+            .try_iter()
+            .find_map(|command| match command {
+                ClientCommand::Response(1, Response::ReserveRays(_, scene)) => Some(scene),
+                _ => None,
+            })
+            .unwrap();
+        assert!(scene.spheres.is_empty() && scene.bvh_nodes.is_empty());
+
+        let count = state.in_flight_tiles[0].addr.area();
+        process(
+            &mut state,
+            [event(
+                id,
+                ClientEventPayload::Request(1, Request::SubmitResults(misses(count))),
+            )],
+        );
+        let pixels = output
+            .try_iter()
+            .find_map(|event| match event {
+                OutputEvent::BlitTile(blit) => Some(blit.pixels),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(pixels.len(), count);
+        assert!(pixels
+            .iter()
+            .all(|pixel| (pixel.x, pixel.y, pixel.z) == (0.0, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn ray_depths_are_averaged_per_client() {
+        let (mut state, _output) = test_state(test_config());
+        let (id, _commands) = connect(&mut state);
+        process(
+            &mut state,
+            [event(
+                id,
+                ClientEventPayload::Request(1, Request::ReserveRays),
+            )],
+        );
+        let count = state.in_flight_tiles[0].addr.area();
+        // Depths of 1, 2 and 3 in turn, with 2 to fill out the tile
+        let mut results = misses(count);
+        for (i, result) in results.iter_mut().enumerate() {
+            result.ray_depth = if i < count - count % 3 {
+                [1, 2, 3][i % 3]
+            } else {
+                2
+            };
+        }
+        process(
+            &mut state,
+            [event(
+                id,
+                ClientEventPayload::Request(1, Request::SubmitResults(results)),
+            )],
+        );
+        state.publish_state();
+        let public_state = state.public_state.read().unwrap();
+        let client = public_state
+            .clients
+            .iter()
+            .find(|client| client.id == id)
+            .unwrap();
+        assert_eq!(client.avg_ray_depth, 2.0);
+        assert_eq!(client.max_ray_depth, 3);
+    }
+
+    // Reserves a tile with request 1, returning its area and a function that
+    // submits `count` misses from `offset` and returns the response
+    fn reserve_for_partial_results(
+        state: &mut ServerState,
+    ) -> (
+        usize,
+        impl FnMut(&mut ServerState, u32, usize) -> Option<Response>,
+    ) {
+        let (id, commands) = connect(state);
+        process(
+            state,
+            [event(
+                id,
+                ClientEventPayload::Request(1, Request::ReserveRays),
+            )],
+        );
+        commands.try_iter().for_each(drop);
+        let area = state.in_flight_tiles[0].addr.area();
+        let submit = move |state: &mut ServerState, offset, count| {
+            let results = misses(count);
+            process(
+                state,
+                [event(
+                    id,
+                    ClientEventPayload::Request(
+                        1,
+                        Request::SubmitPartialResults { offset, results },
+                    ),
+                )],
+            );
+            commands.try_iter().find_map(|command| match command {
+                ClientCommand::Response(1, response) => Some(response),
+                _ => None,
+            })
+        };
+        (area, submit)
+    }
+
+    #[test]
+    fn partial_results_complete_a_tile_in_two_chunks() {
+        let (mut state, output) = test_state(test_config());
+        let (area, mut submit) = reserve_for_partial_results(&mut state);
+        let half = area / 2;
+        let expires = state.in_flight_tiles[0].expires;
+        assert!(matches!(
+            submit(&mut state, 0, half),
+            Some(Response::SubmitPartialResults)
+        ));
+        // Progress extends the lease, and nothing is drawn yet
+        assert!(state.in_flight_tiles[0].expires > expires);
+        assert!(blitted_tiles(&output).is_empty());
+
+        assert!(matches!(
+            submit(&mut state, half as u32, area - half),
+            Some(Response::SubmitResults)
+        ));
+        assert!(state.in_flight_tiles.is_empty());
+        assert_eq!(blitted_tiles(&output).len(), 1);
+    }
+
+    #[test]
+    fn partial_results_must_follow_on_from_the_last_chunk() {
+        let (mut state, output) = test_state(test_config());
+        let (area, mut submit) = reserve_for_partial_results(&mut state);
+        let half = area / 2;
+        let error = |response: Option<Response>| match response {
+            Some(Response::Error(message)) => message,
+            other => panic!("Unexpected response {other:?}"),
+        };
+        // Out of order
+        assert_eq!(
+            error(submit(&mut state, half as u32, area - half)),
+            format!("Expected results from offset 0 but got {half}")
+        );
+        assert!(matches!(
+            submit(&mut state, 0, half),
+            Some(Response::SubmitPartialResults)
+        ));
+        // Overlapping the chunk already in
+        assert_eq!(
+            error(submit(&mut state, half as u32 - 1, 2)),
+            format!("Expected results from offset {half} but got {}", half - 1)
+        );
+        // Running past the end of the tile
+        assert_eq!(
+            error(submit(&mut state, half as u32, area - half + 1)),
+            format!(
+                "A chunk of {} results doesn't fit in the {} left",
+                area - half + 1,
+                area - half
+            )
+        );
+        assert!(error(submit(&mut state, half as u32, 0)).starts_with("A chunk of 0 results"));
+
+        // None of that loses the tile, which still completes
+        assert!(matches!(
+            submit(&mut state, half as u32, area - half),
+            Some(Response::SubmitResults)
+        ));
+        assert_eq!(blitted_tiles(&output).len(), 1);
+    }
+
+    #[test]
+    fn clients_are_throttled_past_max_frames_ahead() {
+        let (mut state, _output) = test_state(ServerConfig {
+            max_frames_ahead: Some(1),
+            ..test_config()
+        });
+        let (modern, modern_commands) = connect(&mut state);
+        let (legacy, legacy_commands) = connect(&mut state);
+        process(
+            &mut state,
+            [event(
+                modern,
+                ClientEventPayload::SetFeatures(protocol::FEATURE_THROTTLED),
+            )],
+        );
+        // Reserving without submitting eventually takes every tile of the
+        // only frame allowed in progress
+        let throttled_at = (1..10_000)
+            .find(|&request_id| {
+                process(
+                    &mut state,
+                    [event(
+                        modern,
+                        ClientEventPayload::Request(request_id, Request::ReserveRays),
+                    )],
+                );
+                modern_commands.try_iter().any(|command| {
+                    matches!(command, ClientCommand::Response(_, Response::Throttled))
+                })
+            })
+            .unwrap();
+        assert!(throttled_at > 1);
+        let frame = state.in_flight_tiles[0].addr.frame;
+        assert!(state
+            .in_flight_tiles
+            .iter()
+            .all(|tile| tile.addr.frame == frame));
+
+        process(
+            &mut state,
+            [event(
+                legacy,
+                ClientEventPayload::Request(1, Request::ReserveRays),
+            )],
+        );
+        let response = legacy_commands
+            .try_iter()
+            .find_map(|command| match command {
+                ClientCommand::Response(1, response) => Some(response),
+                _ => None,
+            });
+        match response {
+            Some(Response::Error(message)) => {
+                assert_eq!(message, "Too many frames in progress, try again")
+            }
+            other => panic!("Unexpected response {other:?}"),
+        }
+    }
+
+    // Runs the server loop with nothing arriving for this long
+    fn idle(state: &mut ServerState, duration: Duration) {
+        let (tx, rx) = crossbeam_channel::unbounded::<ClientEvent>();
+        state.rx = rx;
+        let closer = std::thread::spawn(move || {
+            std::thread::sleep(duration);
+            drop(tx);
+        });
+        state.run();
+        closer.join().unwrap();
+    }
+
+    #[test]
+    fn expired_tiles_are_reissued() {
+        let (mut state, _output) = test_state(ServerConfig {
+            tile_timeout: Duration::from_millis(20),
+            ..test_config()
+        });
+        let (slow, slow_commands) = connect(&mut state);
+        let (fast, _fast_commands) = connect(&mut state);
+        process(
+            &mut state,
+            [event(
+                slow,
+                ClientEventPayload::Request(1, Request::ReserveRays),
+            )],
+        );
+        let addr = state.in_flight_tiles[0].addr;
+
+        idle(&mut state, Duration::from_millis(100));
+        assert!(state.in_flight_tiles.is_empty());
+        assert!(!state.clients.contains_key(&slow));
+        assert!(slow_commands
+            .try_iter()
+            .any(|command| matches!(command, ClientCommand::Disconnect(reason) if reason.contains("Timed out"))));
+
+        process(
+            &mut state,
+            [event(
+                fast,
+                ClientEventPayload::Request(1, Request::ReserveRays),
+            )],
+        );
+        let reissued = state.in_flight_tiles[0].addr;
+        assert_eq!(state.in_flight_tiles[0].client_id, fast);
+        assert_eq!(
+            (reissued.frame, reissued.x, reissued.y),
+            (addr.frame, addr.x, addr.y)
+        );
+    }
+
+    #[test]
+    fn sanitize_name_trims_and_strips_control_characters() {
+        assert_eq!(sanitize_name("  Alice \n"), "Alice");
+        assert_eq!(sanitize_name("Bo\nb\t\u{7}by"), "Bobby");
+        assert_eq!(sanitize_name("a\u{202e}b\u{2066}c"), "abc");
+    }
+
+    #[test]
+    fn sanitize_name_falls_back_to_unnamed() {
+        assert_eq!(sanitize_name(""), "Unnamed");
+        assert_eq!(sanitize_name(" \t\r\n "), "Unnamed");
+        assert_eq!(sanitize_name("\u{202e}\u{0}"), "Unnamed");
+    }
+
+    #[test]
+    fn sanitize_name_keeps_right_to_left_text() {
+        assert_eq!(sanitize_name("مرحبا بالعالم"), "مرحبا بالعالم");
+        assert_eq!(sanitize_name("שלום"), "שלום");
+    }
+
+    #[test]
+    fn sanitize_name_limits_graphemes() {
+        let long = "x".repeat(10_000);
+        assert_eq!(sanitize_name(&long), "x".repeat(MAX_NAME_GRAPHEMES));
+        // One grapheme each, made of several chars, which must stay whole
+        let family = "\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}";
+        let name = sanitize_name(&family.repeat(100));
+        assert_eq!(name, family.repeat(MAX_NAME_GRAPHEMES));
+        let accented = "e\u{301}".repeat(100);
+        assert_eq!(
+            sanitize_name(&accented),
+            "e\u{301}".repeat(MAX_NAME_GRAPHEMES)
+        );
+    }
+
+    #[test]
+    fn sanitize_name_trims_after_truncating() {
+        let name = format!("{} tail", "y".repeat(MAX_NAME_GRAPHEMES - 1));
+        assert_eq!(sanitize_name(&name), "y".repeat(MAX_NAME_GRAPHEMES - 1));
+    }
+
+    #[test]
+    fn unique_name_adds_the_first_free_suffix() {
+        let taken = HashSet::from(["Alice", "Alice (2)"]);
+        assert_eq!(unique_name("Bob".into(), &taken), "Bob");
+        assert_eq!(unique_name("Alice".into(), &taken), "Alice (3)");
+    }
 }
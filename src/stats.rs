@@ -0,0 +1,153 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusRecorder};
+
+const MAX_SAMPLES: usize = 1000;
+
+#[derive(Default)]
+pub struct RenderTimeHistogram {
+    recent: VecDeque<f64>,
+    sorted: VecDeque<f64>,
+    sum: f64,
+}
+
+impl RenderTimeHistogram {
+    pub fn insert(&mut self, t: f64) {
+        if self.recent.len() == MAX_SAMPLES {
+            let oldest = self.recent.pop_front().unwrap();
+            let idx = self.sorted.partition_point(|&x| x < oldest);
+            self.sorted.remove(idx);
+            self.sum -= oldest;
+        }
+        self.recent.push_back(t);
+        self.sum += t;
+        let idx = self.sorted.partition_point(|&x| x < t);
+        self.sorted.insert(idx, t);
+    }
+    fn percentile(&self, p: f64) -> f64 {
+        if self.sorted.is_empty() {
+            return 0.0;
+        }
+        let rank = (p * self.sorted.len() as f64).ceil() as usize;
+        self.sorted[rank.clamp(1, self.sorted.len()) - 1]
+    }
+    pub fn mean(&self) -> f64 {
+        if self.recent.is_empty() {
+            return 0.0;
+        }
+        self.sum / self.recent.len() as f64
+    }
+    pub fn p50(&self) -> f64 {
+        self.percentile(0.50)
+    }
+    pub fn p95(&self) -> f64 {
+        self.percentile(0.95)
+    }
+    pub fn p99(&self) -> f64 {
+        self.percentile(0.99)
+    }
+}
+
+// Running total of a quantity over the last `window`, for rates
+pub struct SlidingCounter {
+    window: Duration,
+    samples: VecDeque<(Instant, u64)>,
+    sum: u64,
+}
+
+impl SlidingCounter {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            samples: VecDeque::new(),
+            sum: 0,
+        }
+    }
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(at, n)) = self.samples.front() {
+            if now.duration_since(at) < self.window {
+                break;
+            }
+            self.samples.pop_front();
+            self.sum -= n;
+        }
+    }
+    pub fn add(&mut self, now: Instant, n: u64) {
+        self.expire(now);
+        self.samples.push_back((now, n));
+        self.sum += n;
+    }
+    pub fn per_second(&mut self, now: Instant) -> f64 {
+        self.expire(now);
+        self.sum as f64 / self.window.as_secs_f64()
+    }
+}
+
+// Upper bounds in bytes of each frame size bucket, with a final bucket for
+// anything larger
+const FRAME_SIZE_BUCKETS: [f64; 5] = [256.0, 4096.0, 65536.0, 1048576.0, 16777216.0];
+
+// Collects the `metrics` counters and histograms for /metrics
+pub fn prometheus_recorder() -> PrometheusRecorder {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("_frame_bytes".into()), &FRAME_SIZE_BUCKETS)
+        .unwrap()
+        .build_recorder()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1..=1000 in a scrambled but fixed order
+    fn scrambled() -> impl Iterator<Item = f64> {
+        (0..1000).map(|i| ((i * 7919) % 1000 + 1) as f64)
+    }
+
+    fn assert_near(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 0.1, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn empty_histogram_reports_zero() {
+        let histogram = RenderTimeHistogram::default();
+        assert_eq!(histogram.p50(), 0.0);
+        assert_eq!(histogram.p99(), 0.0);
+        assert_eq!(histogram.mean(), 0.0);
+    }
+
+    #[test]
+    fn percentiles_of_known_values() {
+        let mut histogram = RenderTimeHistogram::default();
+        scrambled().for_each(|t| histogram.insert(t));
+        assert_near(histogram.p50(), 500.0);
+        assert_near(histogram.p95(), 950.0);
+        assert_near(histogram.p99(), 990.0);
+        assert_near(histogram.mean(), 500.5);
+    }
+
+    #[test]
+    fn percentiles_after_eviction() {
+        let mut histogram = RenderTimeHistogram::default();
+        // All of these are pushed out again by the next MAX_SAMPLES values
+        (0..MAX_SAMPLES).for_each(|i| histogram.insert(1e6 + i as f64));
+        scrambled().for_each(|t| histogram.insert(t));
+        assert_near(histogram.p50(), 500.0);
+        assert_near(histogram.p95(), 950.0);
+        assert_near(histogram.p99(), 990.0);
+        assert_near(histogram.mean(), 500.5);
+    }
+
+    #[test]
+    fn eviction_removes_one_of_several_equal_values() {
+        let mut histogram = RenderTimeHistogram::default();
+        (0..MAX_SAMPLES).for_each(|_| histogram.insert(5.0));
+        (0..MAX_SAMPLES / 2).for_each(|_| histogram.insert(1.0));
+        assert_near(histogram.p50(), 1.0);
+        assert_near(histogram.p99(), 5.0);
+        assert_near(histogram.mean(), 3.0);
+    }
+}
@@ -0,0 +1,134 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, ErrorKind, Read, Write},
+    net::{Shutdown, TcpStream},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Context};
+use rustls_pemfile::Item;
+
+use crate::client_handler::{DuplexStream, ShutdownWrite};
+
+pub fn load_config(cert_path: &Path, key_path: &Path) -> anyhow::Result<Arc<rustls::ServerConfig>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(cert_path).with_context(|| format!("Opening {}", cert_path.display()))?,
+    ))?
+    .into_iter()
+    .map(rustls::Certificate)
+    .collect();
+
+    let key = rustls_pemfile::read_all(&mut BufReader::new(
+        File::open(key_path).with_context(|| format!("Opening {}", key_path.display()))?,
+    ))?
+    .into_iter()
+    .find_map(|item| match item {
+        Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(key),
+        _ => None,
+    })
+    .ok_or_else(|| anyhow!("No private key found in {}", key_path.display()))?;
+
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, rustls::PrivateKey(key))?;
+    Ok(Arc::new(config))
+}
+
+// rustls keeps the state for both directions in one connection object, so
+// the halves share it and only hold the lock while processing records.
+pub struct TlsReader {
+    conn: Arc<Mutex<rustls::ServerConnection>>,
+    sock: TcpStream,
+    buf: Vec<u8>,
+}
+
+pub struct TlsWriter {
+    conn: Arc<Mutex<rustls::ServerConnection>>,
+    sock: TcpStream,
+}
+
+impl DuplexStream for rustls::StreamOwned<rustls::ServerConnection, TcpStream> {
+    type Reader = TlsReader;
+    type Writer = TlsWriter;
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)> {
+        let conn = Arc::new(Mutex::new(self.conn));
+        let writer_sock = self.sock.try_clone()?;
+        Ok((
+            TlsReader {
+                conn: conn.clone(),
+                sock: self.sock,
+                buf: vec![0; 16 * 1024],
+            },
+            TlsWriter {
+                conn,
+                sock: writer_sock,
+            },
+        ))
+    }
+    fn tcp(&self) -> Option<&TcpStream> {
+        Some(&self.sock)
+    }
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.conn.lock().unwrap().reader().read(buf) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                res => return res,
+            }
+            // Wait for more data without the lock, so the writer isn't blocked
+            let n = self.sock.read(&mut self.buf)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            let mut conn = self.conn.lock().unwrap();
+            // rustls only takes what it has room for, which processing frees up
+            let mut data = &self.buf[..n];
+            while !data.is_empty() {
+                if conn.read_tls(&mut data)? == 0 {
+                    return Err(io::Error::other("TLS buffer is full"));
+                }
+                conn.process_new_packets()
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            }
+            while conn.wants_write() {
+                conn.write_tls(&mut self.sock)?;
+            }
+        }
+    }
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let n = conn.writer().write(buf)?;
+        while conn.wants_write() {
+            conn.write_tls(&mut self.sock)?;
+        }
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        conn.writer().flush()?;
+        while conn.wants_write() {
+            conn.write_tls(&mut self.sock)?;
+        }
+        Ok(())
+    }
+}
+
+impl ShutdownWrite for TlsWriter {
+    fn shutdown(&mut self) {
+        let mut conn = self.conn.lock().unwrap();
+        conn.send_close_notify();
+        while conn.wants_write() {
+            if conn.write_tls(&mut self.sock).is_err() {
+                break;
+            }
+        }
+        let _ = self.sock.shutdown(Shutdown::Both);
+    }
+}
@@ -1,18 +1,371 @@
-use std::sync::mpsc;
+use std::{
+    ffi::OsString,
+    fs,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+static DROPPED_TOTAL: AtomicU64 = AtomicU64::new(0);
+
+/// Number of items `send_or_drop` threw away because their channel was full.
+pub fn dropped_total() -> u64 {
+    DROPPED_TOTAL.load(Ordering::Relaxed)
+}
+
+// How long `send_timeout` waits before checking a full channel again. The
+// wait doubles each time, so a long timeout doesn't keep the thread spinning.
+const SEND_RETRY_MIN: Duration = Duration::from_millis(1);
+const SEND_RETRY_MAX: Duration = Duration::from_millis(20);
+
+fn record_backpressure(name: &'static str) {
+    log::warn!("Backpressure: {}", name);
+    metrics::counter!("workshop_output_backpressure_total", "channel" => name).increment(1);
+}
+
+fn record_drop(name: &'static str) {
+    log::warn!("Dropped: {}", name);
+    DROPPED_TOTAL.fetch_add(1, Ordering::Relaxed);
+}
+
+#[derive(Debug)]
+pub enum SendTimeoutError<T> {
+    Timeout(T),
+    Disconnected(T),
+}
 
 pub trait SyncSenderExt<T> {
-    fn send_realtime(&self, item: T, name: &str) -> Result<(), mpsc::SendError<T>>;
+    fn send_realtime(&self, item: T, name: &'static str) -> Result<(), mpsc::SendError<T>>;
+    fn send_timeout(
+        &self,
+        item: T,
+        timeout: Duration,
+        name: &'static str,
+    ) -> Result<(), SendTimeoutError<T>>;
+    fn send_or_drop(&self, item: T, name: &'static str) -> bool;
+    // `send_timeout` with the error `try_send` gives, for callers that treat
+    // running out of time like finding the channel full
+    fn send_with_timeout(
+        &self,
+        item: T,
+        name: &'static str,
+        timeout: Duration,
+    ) -> Result<(), mpsc::TrySendError<T>> {
+        self.send_timeout(item, timeout, name).map_err(|e| match e {
+            SendTimeoutError::Timeout(item) => mpsc::TrySendError::Full(item),
+            SendTimeoutError::Disconnected(item) => mpsc::TrySendError::Disconnected(item),
+        })
+    }
 }
 
 impl<T> SyncSenderExt<T> for mpsc::SyncSender<T> {
-    fn send_realtime(&self, item: T, name: &str) -> Result<(), mpsc::SendError<T>> {
+    fn send_realtime(&self, item: T, name: &'static str) -> Result<(), mpsc::SendError<T>> {
         match self.try_send(item) {
             Ok(()) => Ok(()),
             Err(mpsc::TrySendError::Full(item)) => {
-                log::warn!("Backpressure: {}", name);
+                record_backpressure(name);
                 self.send(item)
             }
             Err(mpsc::TrySendError::Disconnected(item)) => Err(mpsc::SendError(item)),
         }
     }
+    // SyncSender has no send with a deadline, so poll until there's room
+    fn send_timeout(
+        &self,
+        item: T,
+        timeout: Duration,
+        name: &'static str,
+    ) -> Result<(), SendTimeoutError<T>> {
+        let deadline = Instant::now() + timeout;
+        let mut item = match self.try_send(item) {
+            Ok(()) => return Ok(()),
+            Err(mpsc::TrySendError::Full(item)) => {
+                record_backpressure(name);
+                item
+            }
+            Err(mpsc::TrySendError::Disconnected(item)) => {
+                return Err(SendTimeoutError::Disconnected(item))
+            }
+        };
+        let mut retry = SEND_RETRY_MIN;
+        loop {
+            thread::sleep(retry.min(deadline.saturating_duration_since(Instant::now())));
+            retry = (retry * 2).min(SEND_RETRY_MAX);
+            item = match self.try_send(item) {
+                Ok(()) => return Ok(()),
+                Err(mpsc::TrySendError::Full(item)) if Instant::now() < deadline => item,
+                Err(mpsc::TrySendError::Full(item)) => return Err(SendTimeoutError::Timeout(item)),
+                Err(mpsc::TrySendError::Disconnected(item)) => {
+                    return Err(SendTimeoutError::Disconnected(item))
+                }
+            };
+        }
+    }
+    // For items that are fine to lose, returns whether it was sent
+    fn send_or_drop(&self, item: T, name: &'static str) -> bool {
+        match self.try_send(item) {
+            Ok(()) => true,
+            Err(mpsc::TrySendError::Full(_)) => {
+                record_drop(name);
+                false
+            }
+            Err(mpsc::TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+// Client events and commands go over crossbeam channels, which behave the
+// same but can wait with a deadline themselves
+impl<T> SyncSenderExt<T> for crossbeam_channel::Sender<T> {
+    fn send_realtime(&self, item: T, name: &'static str) -> Result<(), mpsc::SendError<T>> {
+        match self.try_send(item) {
+            Ok(()) => Ok(()),
+            Err(crossbeam_channel::TrySendError::Full(item)) => {
+                record_backpressure(name);
+                self.send(item).map_err(|e| mpsc::SendError(e.0))
+            }
+            Err(crossbeam_channel::TrySendError::Disconnected(item)) => Err(mpsc::SendError(item)),
+        }
+    }
+    fn send_timeout(
+        &self,
+        item: T,
+        timeout: Duration,
+        name: &'static str,
+    ) -> Result<(), SendTimeoutError<T>> {
+        match self.try_send(item) {
+            Ok(()) => Ok(()),
+            Err(crossbeam_channel::TrySendError::Full(item)) => {
+                record_backpressure(name);
+                // Resolves to the inherent method, which takes no name
+                self.send_timeout(item, timeout).map_err(|e| match e {
+                    crossbeam_channel::SendTimeoutError::Timeout(item) => {
+                        SendTimeoutError::Timeout(item)
+                    }
+                    crossbeam_channel::SendTimeoutError::Disconnected(item) => {
+                        SendTimeoutError::Disconnected(item)
+                    }
+                })
+            }
+            Err(crossbeam_channel::TrySendError::Disconnected(item)) => {
+                Err(SendTimeoutError::Disconnected(item))
+            }
+        }
+    }
+    fn send_or_drop(&self, item: T, name: &'static str) -> bool {
+        match self.try_send(item) {
+            Ok(()) => true,
+            Err(crossbeam_channel::TrySendError::Full(_)) => {
+                record_drop(name);
+                false
+            }
+            Err(crossbeam_channel::TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+// Writes files on a thread of its own, so the server loop never waits on the
+// disk. Each file is written alongside its target and then renamed over it,
+// so a crash mid-write leaves the previous version intact. Dropping the
+// writer waits for the writes already queued.
+pub struct FileWriter {
+    tx: Option<mpsc::SyncSender<(PathBuf, Vec<u8>)>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl FileWriter {
+    pub fn spawn() -> Self {
+        let (tx, rx) = mpsc::sync_channel::<(PathBuf, Vec<u8>)>(4);
+        let thread = thread::Builder::new()
+            .name("file-writer".into())
+            .spawn(move || {
+                for (path, data) in rx {
+                    let mut tmp_path = OsString::from(&path);
+                    tmp_path.push(".tmp");
+                    let result =
+                        fs::write(&tmp_path, data).and_then(|()| fs::rename(&tmp_path, &path));
+                    if let Err(e) = result {
+                        log::warn!("Failed to write {}: {}", path.display(), e);
+                    }
+                }
+            })
+            .unwrap();
+        Self {
+            tx: Some(tx),
+            thread: Some(thread),
+        }
+    }
+    // Files are rewritten in full each time, so if the disk falls behind the
+    // next write of the same file makes up for one that is dropped
+    pub fn write(&self, path: PathBuf, data: Vec<u8>) {
+        if let Some(tx) = &self.tx {
+            tx.send_or_drop((path, data), "FileWriter.tx");
+        }
+    }
+}
+
+impl Drop for FileWriter {
+    fn drop(&mut self) {
+        self.tx = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::{Mutex, Once};
+
+    use super::*;
+    use crate::stats;
+
+    // Warnings from every test end up here, so tests look for messages
+    // naming their own channels or files
+    static LOGGED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.level() <= log::Level::Warn
+        }
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                LOGGED.lock().unwrap().push(record.args().to_string());
+            }
+        }
+        fn flush(&self) {}
+    }
+
+    pub(crate) fn capture_logs() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&CaptureLogger).unwrap();
+            log::set_max_level(log::LevelFilter::Warn);
+        });
+    }
+
+    pub(crate) fn logged(message: &str) -> bool {
+        LOGGED.lock().unwrap().iter().any(|line| line == message)
+    }
+
+    #[test]
+    fn send_realtime_reports_a_full_channel() {
+        capture_logs();
+        let (tx, rx) = mpsc::sync_channel(1);
+        tx.send_realtime(1, "realtime-test").unwrap();
+        assert!(!logged("Backpressure: realtime-test"));
+
+        let reader = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            rx.iter().collect::<Vec<_>>()
+        });
+        let recorder = stats::prometheus_recorder();
+        let metrics = recorder.handle();
+        metrics::with_local_recorder(&recorder, || {
+            // Blocks until the reader makes room, rather than failing
+            tx.send_realtime(2, "realtime-test").unwrap();
+        });
+        drop(tx);
+        assert_eq!(reader.join().unwrap(), [1, 2]);
+        assert!(logged("Backpressure: realtime-test"));
+        assert!(metrics
+            .render()
+            .contains("workshop_output_backpressure_total{channel=\"realtime-test\"} 1"));
+    }
+
+    #[test]
+    fn send_timeout_gives_up_on_a_full_channel() {
+        let (tx, rx) = mpsc::sync_channel(1);
+        tx.send(1).unwrap();
+        let start = Instant::now();
+        let result =
+            SyncSenderExt::send_timeout(&tx, 2, Duration::from_millis(100), "timeout-test");
+        assert!(matches!(result, Err(SendTimeoutError::Timeout(2))));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(100));
+        // Backing off never overshoots the deadline by more than one retry
+        assert!(elapsed < Duration::from_millis(100) + SEND_RETRY_MAX * 5);
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), [1]);
+    }
+
+    #[test]
+    fn send_timeout_waits_for_room() {
+        let (tx, rx) = mpsc::sync_channel(1);
+        // Goes straight in
+        SyncSenderExt::send_timeout(&tx, 1, Duration::from_secs(5), "timeout-test").unwrap();
+        let reader = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            rx.iter().collect::<Vec<_>>()
+        });
+        SyncSenderExt::send_timeout(&tx, 2, Duration::from_secs(5), "timeout-test").unwrap();
+        drop(tx);
+        assert_eq!(reader.join().unwrap(), [1, 2]);
+    }
+
+    // A zero-capacity channel only takes an item while a receiver is waiting
+    fn waiting_receiver() -> (mpsc::SyncSender<u32>, thread::JoinHandle<Option<u32>>) {
+        let (tx, rx) = mpsc::sync_channel(0);
+        let reader = thread::spawn(move || rx.recv().ok());
+        (tx, reader)
+    }
+
+    #[test]
+    fn send_or_drop_drops_without_a_waiting_receiver() {
+        capture_logs();
+        let (tx, rx) = mpsc::sync_channel(0);
+        let dropped = dropped_total();
+        assert!(!tx.send_or_drop(1, "drop-test"));
+        assert!(dropped_total() > dropped);
+        assert!(logged("Dropped: drop-test"));
+        assert!(rx.try_recv().is_err());
+
+        let (tx, reader) = waiting_receiver();
+        // The reader may not be waiting yet, so retry until it is
+        while !tx.send_or_drop(2, "drop-test") {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(reader.join().unwrap(), Some(2));
+    }
+
+    #[test]
+    fn send_with_timeout_returns_the_item_without_a_waiting_receiver() {
+        let (tx, _rx) = mpsc::sync_channel(0);
+        let result = tx.send_with_timeout(1, "with-timeout-test", Duration::from_millis(50));
+        assert!(matches!(result, Err(mpsc::TrySendError::Full(1))));
+
+        let (tx, reader) = waiting_receiver();
+        tx.send_with_timeout(2, "with-timeout-test", Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(reader.join().unwrap(), Some(2));
+
+        let (tx, rx) = mpsc::sync_channel(0);
+        drop(rx);
+        let result = tx.send_with_timeout(3, "with-timeout-test", Duration::from_secs(5));
+        assert!(matches!(result, Err(mpsc::TrySendError::Disconnected(3))));
+    }
+
+    #[test]
+    fn send_timeout_reports_a_disconnect() {
+        let (tx, rx) = mpsc::sync_channel(1);
+        drop(rx);
+        let result = SyncSenderExt::send_timeout(&tx, 1, Duration::from_secs(5), "timeout-test");
+        assert!(matches!(result, Err(SendTimeoutError::Disconnected(1))));
+    }
+
+    #[test]
+    fn file_writes_are_finished_once_the_writer_is_dropped() {
+        let path = std::env::temp_dir().join(format!("file-writer-{}", std::process::id()));
+        let writer = FileWriter::spawn();
+        writer.write(path.clone(), b"first".to_vec());
+        writer.write(path.clone(), b"second".to_vec());
+        drop(writer);
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,70 @@
+use std::{
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+// The server loop bumps the heartbeat at least every `ADMIN_POLL_INTERVAL`
+// even when idle, so this only fires when a single pass of the loop is
+// stuck, such as a handler blocked on a lock or a full channel. Aborting
+// gives a core dump to look at instead of a workshop that silently freezes.
+pub fn spawn(heartbeat: Arc<AtomicU64>, timeout: Duration) {
+    watch(heartbeat, timeout, || {
+        // SIGABRT's default action dumps core. raise only returns if
+        // something blocks or handles it, so fall back to abort.
+        unsafe {
+            libc::raise(libc::SIGABRT);
+        }
+        process::abort();
+    });
+}
+
+fn watch(heartbeat: Arc<AtomicU64>, timeout: Duration, on_stall: impl FnOnce() + Send + 'static) {
+    thread::spawn(move || {
+        let mut last_seen = heartbeat.load(Ordering::Relaxed);
+        loop {
+            thread::sleep(timeout);
+            let current = heartbeat.load(Ordering::Relaxed);
+            if current == last_seen {
+                log::error!("Server loop made no progress for {:?}, aborting", timeout);
+                on_stall();
+                return;
+            }
+            last_seen = current;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn a_stuck_heartbeat_is_caught() {
+        let (tx, rx) = mpsc::channel();
+        watch(Arc::default(), Duration::from_millis(20), move || {
+            let _ = tx.send(());
+        });
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn a_moving_heartbeat_is_left_alone() {
+        let heartbeat = Arc::new(AtomicU64::new(0));
+        let (tx, rx) = mpsc::channel();
+        watch(heartbeat.clone(), Duration::from_millis(100), move || {
+            let _ = tx.send(());
+        });
+        for _ in 0..60 {
+            heartbeat.fetch_add(1, Ordering::Relaxed);
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(rx.try_recv().is_err());
+    }
+}
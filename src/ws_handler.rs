@@ -0,0 +1,222 @@
+use std::{
+    io::{ErrorKind, Write},
+    net::{SocketAddr, TcpStream},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use tungstenite::{Message, WebSocket};
+
+use crate::{
+    ban_list::BanList,
+    client_handler::{recv_response, ClientChannel, ConnectionSlot},
+    protocol::{JsonResponse, Request, Response},
+    ClientCommand, ClientEvent,
+};
+
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+// Applies to each read and write until the upgrade completes
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct WsHandler {
+    channel: ClientChannel,
+    commands: crossbeam_channel::Receiver<ClientCommand>,
+    socket: WebSocket<TcpStream>,
+}
+
+impl WsHandler {
+    fn new(
+        socket: WebSocket<TcpStream>,
+        addr: SocketAddr,
+        tx: crossbeam_channel::Sender<ClientEvent>,
+    ) -> Self {
+        let (mut channel, commands) = ClientChannel::new(tx, addr.to_string());
+        if let Ok(tcp) = socket.get_ref().try_clone() {
+            channel.measure_round_trips(tcp);
+        }
+        Self {
+            channel,
+            commands,
+            socket,
+        }
+    }
+    // Requests and responses alternate here, so pushed keepalives can't be
+    // delivered. WebSocket pings already detect dead connections instead.
+    // Announcements queued up meanwhile are passed on before the response.
+    fn recv_response(&mut self, binary: bool) -> anyhow::Result<Response> {
+        loop {
+            match recv_response(&self.commands)? {
+                (_, Response::Keepalive) => continue,
+                (_, response @ Response::Announcement(_)) => {
+                    self.write_response(&response, binary)?
+                }
+                (_, response) => return Ok(response),
+            }
+        }
+    }
+    fn write_response(&mut self, response: &Response, binary: bool) -> anyhow::Result<()> {
+        let message = if binary {
+            Message::Binary(postcard::to_allocvec(response)?)
+        } else {
+            Message::Text(serde_json::to_string(&JsonResponse(response))?)
+        };
+        self.socket.write_message(message)?;
+        Ok(())
+    }
+    // Commands pushed while no request is outstanding, such as a kick or an
+    // idle disconnect, would otherwise wait for the client's next request
+    fn poll_commands(&mut self, binary: bool) -> anyhow::Result<()> {
+        while let Ok(command) = self.commands.try_recv() {
+            match command {
+                ClientCommand::Disconnect(reason) => {
+                    // Best effort, the client may already have gone
+                    let _ = self.socket.close(None);
+                    let _ = self.socket.write_pending();
+                    bail!(reason);
+                }
+                ClientCommand::Response(_, response @ Response::Announcement(_)) => {
+                    self.write_response(&response, binary)?
+                }
+                ClientCommand::Response(..) => {}
+            }
+        }
+        Ok(())
+    }
+    pub fn run(&mut self) -> anyhow::Result<()> {
+        let stream = self.socket.get_mut();
+        stream.set_read_timeout(Some(PING_INTERVAL))?;
+        stream.set_write_timeout(Some(PING_INTERVAL))?;
+        stream.set_nodelay(true)?;
+
+        log::info!(
+            "Client ({:?} - {}) - Connected (protocol: websocket)",
+            self.channel.id,
+            self.socket.get_ref().peer_addr()?
+        );
+
+        let mut last_seen = Instant::now();
+        // Pushed messages use the encoding of the client's last request
+        let mut binary = false;
+        loop {
+            self.poll_commands(binary)?;
+            let message = match self.socket.read_message() {
+                Ok(message) => message,
+                Err(tungstenite::Error::Io(e))
+                    if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                {
+                    if last_seen.elapsed() > PING_INTERVAL * 2 {
+                        bail!("No response to keepalive ping");
+                    }
+                    self.socket.write_message(Message::Ping(Vec::new()))?;
+                    continue;
+                }
+                Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            last_seen = Instant::now();
+
+            match message {
+                Message::Text(text) => {
+                    binary = false;
+                    let request: Request = serde_json::from_str(&text)?;
+                    self.channel.send_request(0, request);
+                    let response = self.recv_response(false)?;
+                    self.write_response(&response, false)?;
+                }
+                Message::Binary(data) => {
+                    binary = true;
+                    let request: Request = postcard::from_bytes(&data)?;
+                    self.channel.send_request(0, request);
+                    let response = self.recv_response(true)?;
+                    self.write_response(&response, true)?;
+                }
+                // Pongs and close replies are queued by tungstenite, so just flush them
+                Message::Ping(_) | Message::Pong(_) | Message::Close(_) | Message::Frame(_) => {
+                    match self.socket.write_pending() {
+                        Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+                        res => res?,
+                    }
+                }
+            }
+            if self.channel.said_goodbye() {
+                // Best effort, the client may already have gone
+                let _ = self.socket.close(None);
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Like `client_handler::refuse_connection`, but answers the handshake with a
+// plain HTTP error instead of upgrading
+pub fn refuse_ws_connection(mut stream: impl Write, addr: &str) {
+    log::warn!("Refusing WebSocket connection from {addr}, the server is at --max-clients");
+    let _ = stream.write_all(
+        b"HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\nContent-Length: 14\r\n\r\nServer is full",
+    );
+}
+
+// The slot counts against --max-clients until the client disconnects
+pub fn ws_connected(
+    stream: TcpStream,
+    _slot: ConnectionSlot,
+    tx: crossbeam_channel::Sender<ClientEvent>,
+    ban_list: &BanList,
+) -> anyhow::Result<()> {
+    let addr = stream.peer_addr()?;
+    if ban_list.is_banned(addr.ip()) {
+        log::info!("Refusing connection from banned address {addr}");
+        return Ok(());
+    }
+    // Otherwise a peer that never finishes the handshake holds a worker forever
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let socket = tungstenite::accept(stream)
+        .map_err(|e| anyhow::anyhow!("{e}"))
+        .with_context(|| format!("WebSocket handshake ({addr})"))?;
+    let mut ws_handler = WsHandler::new(socket, addr, tx);
+    let id = ws_handler.channel.id.0;
+    ws_handler
+        .run()
+        .with_context(|| format!("Client ({id} - {addr})"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use crate::{client_handler::ConnectionLimit, ClientEventPayload};
+
+    use super::*;
+
+    #[test]
+    fn quiet_client_is_told_about_a_disconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let slot = ConnectionLimit::new(None).try_acquire().unwrap();
+            ws_connected(stream, slot, tx, &BanList::default())
+        });
+        let (mut client, _) = tungstenite::connect(format!("ws://{addr}")).unwrap();
+        let commands = match rx.recv().unwrap().payload {
+            ClientEventPayload::Connected(commands, _) => commands,
+            other => panic!("Unexpected event {other:?}"),
+        };
+        commands
+            .send(ClientCommand::Disconnect("Kicked".into()))
+            .unwrap();
+        // The client never sends anything, so only the poll after a read
+        // timeout can pick up the command
+        loop {
+            match client.read_message() {
+                Ok(Message::Close(_)) | Err(tungstenite::Error::ConnectionClosed) => break,
+                Ok(Message::Ping(_)) => continue,
+                other => panic!("Unexpected message {other:?}"),
+            }
+        }
+        let err = server.join().unwrap().unwrap_err();
+        assert!(format!("{err:#}").contains("Kicked"));
+    }
+}